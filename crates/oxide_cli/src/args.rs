@@ -1,4 +1,7 @@
-use crate::{build::BuildArgs, instantiate::InstantiateArgs};
+use crate::{
+    build::BuildArgs, build_log::LogArgs, copy::CopyArgs, instantiate::InstantiateArgs,
+    key::KeyArgs, path_info::PathInfoArgs, store::StoreArgs, why_depends::WhyDependsArgs,
+};
 use clap::{Parser, Subcommand};
 
 #[derive(Parser, Clone, Debug)]
@@ -13,5 +16,14 @@ pub struct Args {
 #[derive(Subcommand, Clone, Debug)]
 pub enum Command {
     Build(BuildArgs),
+    Copy(CopyArgs),
     Instantiate(InstantiateArgs),
+    Key(KeyArgs),
+    /// Print the output of the last build of a derivation
+    Log(LogArgs),
+    PathInfo(PathInfoArgs),
+    /// Serve the local store on stdin and stdout, used by `ssh://` stores
+    ServeStdio,
+    Store(StoreArgs),
+    WhyDepends(WhyDependsArgs),
 }
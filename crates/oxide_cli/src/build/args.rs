@@ -1,6 +1,37 @@
 use clap::Parser;
 
 #[derive(Parser, Clone, Debug)]
+#[allow(clippy::struct_excessive_bools)]
 pub struct BuildArgs {
-    pub path: String,
+    /// Derivations or `oxide#pkg_name`
+    #[arg(required = true)]
+    pub paths: Vec<String>,
+    /// Number of builders to run at the same time
+    #[arg(short = 'j', long)]
+    pub max_jobs: Option<usize>,
+    /// Number of cores each builder may use, 0 for all of them
+    #[arg(long)]
+    pub cores: Option<usize>,
+    /// Keep building the derivations that do not depend on a failed one
+    #[arg(short, long)]
+    pub keep_going: bool,
+    /// Keep the build directory and the outputs of failed builds
+    #[arg(short = 'K', long)]
+    pub keep_failed: bool,
+    /// Start a shell in the environment of a failed build before it is cleaned up
+    #[arg(long)]
+    pub debug_shell: bool,
+    /// Build derivations that were already built again and compare their outputs,
+    /// the outputs that differ are kept in the `checks` directory of the state dir
+    #[arg(long, conflicts_with = "dry_run")]
+    pub check: bool,
+    /// Number of times `--check` builds every derivation
+    #[arg(long, default_value_t = 1, requires = "check")]
+    pub rounds: usize,
+    /// Only show what would be built and substituted
+    #[arg(long)]
+    pub dry_run: bool,
+    /// Print the result of a dry run as json
+    #[arg(long, requires = "dry_run")]
+    pub json: bool,
 }
@@ -1,32 +1,182 @@
 mod args;
 pub use args::*;
 
-use anyhow::{Result, bail};
-use oxide_pkgs::top_level::all_packages::all_pkgs;
-use oxide_store::{api::Store, build::build, instantiate::instantiate, stores::local::LocalStore};
+use crate::installable::resolve_drv;
+use anyhow::Result;
+use oxide_core::{store::StorePath, types::Out};
+use oxide_store::{
+    api::Store,
+    build::{BuildOpts, BuildPlan, Built},
+    stores::any::AnyStore,
+};
+use serde_json::{Map, Value, json};
+use std::collections::HashMap;
 
-type S = LocalStore;
+type S = AnyStore;
 
 pub async fn build_cli(args: BuildArgs) -> Result<()> {
-    if let Some(pkg_name) = args.path.strip_prefix("oxide#") {
-        let (pkgs, _) = all_pkgs();
-
-        if let Some(pkg) = pkgs.get(pkg_name) {
-            let store = S::new().await?;
-            let (_, path) = instantiate(&store, pkg).await?;
-            let outputs = build(&store, &path).await?;
-            for (out, p) in outputs {
-                println!("{}!{}", S::store_path(&p), out);
-            }
+    let store = S::auto().await?;
+    let mut drvs = Vec::with_capacity(args.paths.len());
+    for path in &args.paths {
+        drvs.push(resolve_drv(&store, path).await?);
+    }
+    if args.dry_run {
+        let plan = store.plan_build(&drvs).await?;
+        if args.json {
+            println!("{}", serde_json::to_string_pretty(&plan_json(&plan))?);
         } else {
-            bail!("pkg {} not found", pkg_name);
+            print_plan(&plan);
+        }
+        return Ok(());
+    }
+    let opts = BuildOpts {
+        max_jobs: args.max_jobs,
+        cores: args.cores,
+        keep_going: args.keep_going,
+        keep_failed: args.keep_failed,
+        debug_shell: args.debug_shell,
+        check: args.check,
+        rounds: args.rounds,
+        ..BuildOpts::from_env()
+    };
+    let built = store.build_drvs(&drvs, &opts).await?;
+    if args.keep_going {
+        // the summary of a failed build is its error
+        print_built(&built);
+    }
+    for outputs in built.outputs {
+        for (out, p) in outputs {
+            println!("{}!{}", S::store_path(&p), out);
         }
-    } else {
-        // TODO: build derivations outside the pkgs collection
-        bail!(
-            "Derivations outside the pkgs collection are not yet supported. Preappend the path with oxide#pkg_name"
-        )
     }
-
     Ok(())
 }
+
+fn print_built(built: &Built) {
+    eprintln!("{} derivations built", built.built.len());
+    for (drv, log) in &built.built {
+        match log {
+            Some(log) => eprintln!("  {drv} (log: {})", log.display()),
+            None => eprintln!("  {drv} (no log)"),
+        }
+    }
+}
+
+fn print_plan(plan: &BuildPlan) {
+    if !plan.valid.is_empty() {
+        println!("these {} derivations are already built:", plan.valid.len());
+        for drv in plan.valid.keys() {
+            println!("  {}", S::store_path(drv));
+        }
+    }
+    if !plan.substitute.is_empty() {
+        println!(
+            "these {} derivations will be substituted:",
+            plan.substitute.len()
+        );
+        for (drv, sub) in &plan.substitute {
+            println!(
+                "  {} from {} ({} bytes to download, {} bytes unpacked)",
+                S::store_path(drv),
+                sub.url,
+                size_text(sub.download_size),
+                sub.unpacked_size
+            );
+            for p in &sub.paths {
+                println!("    {}", S::store_path(p));
+            }
+        }
+    }
+    if !plan.build.is_empty() {
+        println!("these {} derivations will be built:", plan.build.len());
+        for drv in &plan.build {
+            println!("  {}", S::store_path(drv));
+        }
+    }
+}
+
+fn size_text(size: Option<u64>) -> String {
+    size.map_or_else(|| "unknown".to_string(), |size| size.to_string())
+}
+
+fn plan_json(plan: &BuildPlan) -> Value {
+    let valid = plan
+        .valid
+        .iter()
+        .map(|(drv, outs)| (S::store_path(drv), outs_json(outs)))
+        .collect::<Map<_, _>>();
+    let substitute = plan
+        .substitute
+        .iter()
+        .map(|(drv, sub)| {
+            let v = json!({
+                "url": sub.url,
+                "download_size": sub.download_size,
+                "unpacked_size": sub.unpacked_size,
+                "paths": sub.paths.iter().map(S::store_path).collect::<Vec<_>>(),
+                "outputs": outs_json(&sub.outs),
+            });
+            (S::store_path(drv), v)
+        })
+        .collect::<Map<_, _>>();
+    json!({
+        "build": plan.build.iter().map(S::store_path).collect::<Vec<_>>(),
+        "valid": valid,
+        "substitute": substitute,
+    })
+}
+
+fn outs_json(outs: &HashMap<Out, StorePath>) -> Value {
+    outs.iter()
+        .map(|(out, p)| (out.clone(), S::store_path(p).into()))
+        .collect::<Map<_, _>>()
+        .into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use oxide_core::hash::Hash;
+    use oxide_store::build::Substitution;
+
+    /// a path whose hash only depends on the first letter of `name`
+    fn path(name: &str) -> StorePath {
+        StorePath::new(&Hash::Sha512(Box::new([name.as_bytes()[0]; 64])), name)
+    }
+
+    #[test]
+    fn plan_as_json() {
+        let (built, valid, sub, out) = (path("b.drv"), path("v.drv"), path("s.drv"), path("out"));
+        let outs = HashMap::from([("out".to_string(), out.clone())]);
+        let plan = BuildPlan {
+            build: vec![built.clone()],
+            valid: [(valid.clone(), outs.clone())].into(),
+            substitute: [(
+                sub.clone(),
+                Substitution {
+                    url: "file:///cache".to_string(),
+                    download_size: None,
+                    unpacked_size: 10,
+                    paths: vec![out.clone()],
+                    outs,
+                },
+            )]
+            .into(),
+        };
+        let json = plan_json(&plan);
+        assert_eq!(json["build"], json!([S::store_path(&built)]));
+        assert_eq!(
+            json["valid"][S::store_path(&valid)]["out"],
+            json!(S::store_path(&out))
+        );
+        let sub = &json["substitute"][S::store_path(&sub)];
+        assert_eq!(sub["url"], "file:///cache");
+        // an unknown size is not reported as zero
+        assert!(sub["download_size"].is_null());
+        assert_eq!(sub["unpacked_size"], 10);
+        assert_eq!(sub["paths"], json!([S::store_path(&out)]));
+
+        assert_eq!(size_text(None), "unknown");
+        assert_eq!(size_text(Some(0)), "0");
+    }
+}
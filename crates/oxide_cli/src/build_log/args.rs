@@ -0,0 +1,7 @@
+use clap::Parser;
+
+#[derive(Parser, Clone, Debug)]
+pub struct LogArgs {
+    /// Derivation, output or `oxide#pkg_name` whose last build log is printed
+    pub path: String,
+}
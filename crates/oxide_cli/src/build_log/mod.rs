@@ -0,0 +1,18 @@
+mod args;
+pub use args::*;
+
+use crate::installable::resolve_drv;
+use anyhow::{Result, bail};
+use oxide_store::{api::Store, stores::any::AnyStore};
+use tokio::io;
+
+type S = AnyStore;
+
+pub async fn log_cli(args: LogArgs) -> Result<()> {
+    let store = S::auto().await?;
+    let drv = resolve_drv(&store, &args.path).await?;
+    if !store.read_build_log(&drv, &mut io::stdout()).await? {
+        bail!("no build log for {}", S::store_path(&drv));
+    }
+    Ok(())
+}
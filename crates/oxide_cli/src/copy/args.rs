@@ -0,0 +1,16 @@
+use clap::Parser;
+use oxide_store::stores::any::AUTO_STORE_URL;
+
+#[derive(Parser, Clone, Debug)]
+pub struct CopyArgs {
+    /// Store to copy from: `auto`, `local`, `daemon`, a binary cache url or
+    /// `ssh://<host>`, `pipe://<command>`, `unix://<socket>`
+    #[arg(long, default_value = AUTO_STORE_URL)]
+    pub from: String,
+    /// Store to copy to, e.g. `file:///mnt/cache`, same urls as `--from`
+    #[arg(long)]
+    pub to: String,
+    /// Store paths or `oxide#pkg_name`
+    #[arg(required = true)]
+    pub paths: Vec<String>,
+}
@@ -0,0 +1,21 @@
+mod args;
+pub use args::*;
+
+use crate::installable::resolve_installable;
+use anyhow::Result;
+use oxide_store::{api::Store, export::copy_paths, stores::any::AnyStore};
+
+type S = AnyStore;
+
+pub async fn copy_cli(args: CopyArgs) -> Result<()> {
+    let src = S::open(&args.from).await?;
+    let mut paths = Vec::new();
+    for installable in &args.paths {
+        paths.extend(resolve_installable(&src, installable).await?);
+    }
+    let dst = S::create(&args.to).await?;
+    for path in copy_paths(&src, &dst, &paths).await? {
+        println!("{}", S::store_path(&path));
+    }
+    Ok(())
+}
@@ -0,0 +1,58 @@
+use anyhow::{Result, bail};
+use oxide_core::{drv::DRV_EXT, store::StorePath};
+use oxide_pkgs::top_level::all_packages::all_pkgs;
+use oxide_store::{api::Store, instantiate::instantiate};
+
+pub const PKGS_PREFIX: &str = "oxide#";
+
+/// resolve either `oxide#pkg_name` or a store path to valid store paths
+/// pkgs must already be built
+pub async fn resolve_installable<S>(store: &S, installable: &str) -> Result<Vec<StorePath>>
+where
+    S: Store,
+{
+    let Some(pkg_name) = installable.strip_prefix(PKGS_PREFIX) else {
+        return Ok(vec![S::parse_store_path(installable)?]);
+    };
+    let (pkgs, _) = all_pkgs();
+    let Some(pkg) = pkgs.get(pkg_name) else {
+        bail!("pkg {pkg_name} not found");
+    };
+    let (drv, _) = instantiate(store, pkg).await?;
+    let mut paths = Vec::new();
+    for (out, eq_class) in &drv.eq_classes {
+        let Some(path) = store.trusted_paths(eq_class, out).await?.into_iter().next() else {
+            bail!(
+                "output {out} of {installable} is not built, run `oxide build {installable}` first"
+            );
+        };
+        paths.push(path);
+    }
+    Ok(paths)
+}
+
+/// resolve either `oxide#pkg_name`, a derivation or an output to the derivation that builds it
+pub async fn resolve_drv<S>(store: &S, installable: &str) -> Result<StorePath>
+where
+    S: Store,
+{
+    if let Some(pkg_name) = installable.strip_prefix(PKGS_PREFIX) {
+        let (pkgs, _) = all_pkgs();
+        let Some(pkg) = pkgs.get(pkg_name) else {
+            bail!("pkg {pkg_name} not found");
+        };
+        let (_, path) = instantiate(store, pkg).await?;
+        return Ok(path);
+    }
+    let path = S::parse_store_path(installable)?;
+    if path.ends_with(DRV_EXT) {
+        return Ok(path);
+    }
+    let Some(info) = store.query_path_info(&path).await? else {
+        bail!("path {} is not valid", S::store_path(&path));
+    };
+    let Some(deriver) = info.deriver else {
+        bail!("{} was not built by a derivation", S::store_path(&path));
+    };
+    Ok(deriver)
+}
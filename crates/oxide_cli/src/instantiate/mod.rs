@@ -3,15 +3,15 @@ use anyhow::{Result, bail};
 mod args;
 pub use args::*;
 use oxide_pkgs::top_level::all_packages::all_pkgs;
-use oxide_store::{api::Store, instantiate::instantiate, stores::local::LocalStore};
+use oxide_store::{api::Store, instantiate::instantiate, stores::any::AnyStore};
 
-type S = LocalStore;
+type S = AnyStore;
 
 pub async fn instantiate_cli(args: InstantiateArgs) -> Result<()> {
     let (pkgs, _) = all_pkgs();
 
     if let Some(pkg) = pkgs.get(&args.pkg_name) {
-        let store = S::new().await?;
+        let store = S::auto().await?;
         let (_, p) = instantiate(&store, pkg).await?;
         println!("{}", S::store_path(&p));
     } else {
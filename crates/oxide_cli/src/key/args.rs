@@ -0,0 +1,33 @@
+use clap::{Parser, Subcommand};
+
+#[derive(Parser, Clone, Debug)]
+pub struct KeyArgs {
+    #[command(subcommand)]
+    pub command: KeyCommand,
+}
+
+#[derive(Subcommand, Clone, Debug)]
+pub enum KeyCommand {
+    /// Generate a signing key and print its public key
+    Generate {
+        /// Name of the key, usually the host that signs with it
+        name: String,
+        /// Where to write the secret key
+        secret_key_file: String,
+    },
+    /// Sign the realisations of the given paths
+    Sign {
+        /// File containing the secret key
+        #[arg(short, long)]
+        key_file: String,
+        /// Store paths or `oxide#pkg_name`
+        #[arg(required = true)]
+        paths: Vec<String>,
+    },
+    /// Check that the realisations of the given paths are trusted
+    Verify {
+        /// Store paths or `oxide#pkg_name`
+        #[arg(required = true)]
+        paths: Vec<String>,
+    },
+}
@@ -0,0 +1,111 @@
+mod args;
+pub use args::*;
+
+use crate::installable::resolve_installable;
+use anyhow::{Result, bail};
+use oxide_store::{
+    api::Store,
+    signing::{SecretKey, TrustPolicy, fingerprint},
+    stores::any::AnyStore,
+    types::Realisation,
+};
+use std::{fs::Permissions, os::unix::fs::PermissionsExt};
+use tokio::fs::{self, OpenOptions};
+use tokio::io::AsyncWriteExt;
+
+type S = AnyStore;
+
+pub async fn key_cli(args: KeyArgs) -> Result<()> {
+    match args.command {
+        KeyCommand::Generate {
+            name,
+            secret_key_file,
+        } => generate(&name, &secret_key_file).await,
+        KeyCommand::Sign { key_file, paths } => sign(&key_file, paths).await,
+        KeyCommand::Verify { paths } => verify(paths).await,
+    }
+}
+
+async fn generate(name: &str, secret_key_file: &str) -> Result<()> {
+    let key = SecretKey::generate(name)?;
+    let mut file = OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(secret_key_file)
+        .await?;
+    file.write_all(key.to_string().as_bytes()).await?;
+    file.flush().await?;
+    fs::set_permissions(secret_key_file, Permissions::from_mode(0o600)).await?;
+    println!("{}", key.public_key());
+    Ok(())
+}
+
+async fn realisations(store: &S, installables: Vec<String>) -> Result<Vec<Realisation>> {
+    let mut realisations = Vec::new();
+    for installable in &installables {
+        for path in resolve_installable(store, installable).await? {
+            for r in store.query_realisations(&path).await? {
+                realisations.push(Realisation {
+                    eq_class: r.eq_class,
+                    out: r.out,
+                    path: path.clone(),
+                });
+            }
+        }
+    }
+    Ok(realisations)
+}
+
+async fn sign(key_file: &str, installables: Vec<String>) -> Result<()> {
+    let key = SecretKey::parse(&fs::read_to_string(key_file).await?)?;
+    let store = S::auto().await?;
+    for r in realisations(&store, installables).await? {
+        let sig = key.sign(&fingerprint(&store, &r).await?);
+        store.add_signatures(&r, vec![sig]).await?;
+        println!("signed {}!{} {}", r.eq_class, r.out, S::store_path(&r.path));
+    }
+    Ok(())
+}
+
+async fn verify(installables: Vec<String>) -> Result<()> {
+    let store = S::auto().await?;
+    let policy = TrustPolicy::from_config()?;
+    let mut untrusted = 0;
+    for r in realisations(&store, installables).await? {
+        let sigs = store
+            .query_realisations(&r.path)
+            .await?
+            .into_iter()
+            .find(|info| info.eq_class == r.eq_class && info.out == r.out)
+            .map(|info| info.signatures)
+            .unwrap_or_default();
+        let fingerprint = fingerprint(&store, &r).await?;
+        let valid = policy.valid_signatures(&fingerprint, &sigs);
+        let trusted = store
+            .trusted_paths(&r.eq_class, &r.out)
+            .await?
+            .contains(&r.path);
+        if !trusted {
+            untrusted += 1;
+        }
+        println!(
+            "{} {}!{} {}: {} of {} trusted signatures{}",
+            if trusted { "trusted" } else { "untrusted" },
+            r.eq_class,
+            r.out,
+            S::store_path(&r.path),
+            valid.len(),
+            policy.required_signatures,
+            if valid.is_empty() {
+                String::new()
+            } else {
+                format!(" ({})", valid.into_iter().collect::<Vec<_>>().join(", "))
+            }
+        );
+    }
+    if untrusted > 0 {
+        bail!("{untrusted} realisations are not trusted");
+    }
+    Ok(())
+}
@@ -1,15 +1,30 @@
 mod args;
 mod build;
+mod build_log;
+mod copy;
+mod installable;
 mod instantiate;
+mod key;
 mod logger;
+mod path_info;
+mod serve_stdio;
+mod store;
+mod why_depends;
 
 use anyhow::Result;
 use args::{Args, Command};
 use build::build_cli;
+use build_log::log_cli;
 use clap::Parser;
+use copy::copy_cli;
 use instantiate::instantiate_cli;
+use key::key_cli;
 use log::LevelFilter;
 use logger::Logger;
+use path_info::path_info_cli;
+use serve_stdio::serve_stdio_cli;
+use store::store_cli;
+use why_depends::why_depends_cli;
 
 fn main() -> Result<()> {
     tokio::runtime::Builder::new_multi_thread()
@@ -29,11 +44,19 @@ fn setup_logging() {
 
 async fn cli() -> Result<()> {
     let args = Args::parse();
-    if args.verbose {
+    // stdout is used by the protocol
+    if args.verbose && !matches!(args.command, Command::ServeStdio) {
         setup_logging();
     }
     match args.command {
         Command::Build(args) => build_cli(args).await,
+        Command::Copy(args) => copy_cli(args).await,
         Command::Instantiate(args) => instantiate_cli(args).await,
+        Command::Key(args) => key_cli(args).await,
+        Command::Log(args) => log_cli(args).await,
+        Command::PathInfo(args) => path_info_cli(args).await,
+        Command::ServeStdio => serve_stdio_cli().await,
+        Command::Store(args) => store_cli(args).await,
+        Command::WhyDepends(args) => why_depends_cli(args).await,
     }
 }
@@ -0,0 +1,17 @@
+use clap::Parser;
+
+#[derive(Parser, Clone, Debug)]
+pub struct PathInfoArgs {
+    /// Store paths or `oxide#pkg_name`
+    #[arg(required = true)]
+    pub paths: Vec<String>,
+    /// Show every path in the closure of the given paths
+    #[arg(short = 'r', long)]
+    pub closure: bool,
+    /// Show the size of each path and the size of its closure
+    #[arg(short, long)]
+    pub size: bool,
+    /// Print the result as json
+    #[arg(long)]
+    pub json: bool,
+}
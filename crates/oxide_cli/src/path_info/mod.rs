@@ -0,0 +1,97 @@
+mod args;
+pub use args::*;
+
+use crate::installable::resolve_installable;
+use anyhow::{Result, bail};
+use oxide_core::store::StorePath;
+use oxide_store::{api::Store, stores::any::AnyStore, types::PathInfo};
+use serde_json::{Value, json};
+use std::collections::{BTreeMap, BTreeSet};
+
+type S = AnyStore;
+
+pub async fn path_info_cli(args: PathInfoArgs) -> Result<()> {
+    let store = S::auto().await?;
+    let mut roots = Vec::new();
+    for installable in &args.paths {
+        roots.extend(resolve_installable(&store, installable).await?);
+    }
+    // every path whose size can be needed, queried only once
+    let closure = if args.closure || args.size {
+        store.compute_closure(&roots).await?
+    } else {
+        BTreeSet::new()
+    };
+    let paths = if args.closure {
+        closure.clone()
+    } else {
+        roots.into_iter().collect::<BTreeSet<_>>()
+    };
+    let mut known = BTreeMap::new();
+    for path in closure.into_iter().chain(paths.iter().cloned()) {
+        if known.contains_key(&path) {
+            continue;
+        }
+        let Some(info) = store.query_path_info(&path).await? else {
+            bail!("path {} is not valid", S::store_path(&path));
+        };
+        known.insert(path, info);
+    }
+
+    let mut infos = Vec::new();
+    for path in &paths {
+        let closure_size = args.size.then(|| closure_size(&known, path));
+        infos.push((known[path].clone(), closure_size));
+    }
+
+    if args.json {
+        let infos = infos
+            .into_iter()
+            .map(|(info, closure_size)| {
+                let mut v = json!({
+                    "path": S::store_path(&info.path),
+                    "hash": info.hash.base64_with_algo(),
+                    "size": info.size,
+                    "references": info.refs.iter().map(S::store_path).collect::<Vec<_>>(),
+                    "deriver": info.deriver.as_ref().map(S::store_path),
+                    "registration_time": info.registration_time,
+                });
+                if let Some(closure_size) = closure_size {
+                    v["closure_size"] = closure_size.into();
+                }
+                v
+            })
+            .collect::<Value>();
+        println!("{}", serde_json::to_string_pretty(&infos)?);
+    } else {
+        for (info, closure_size) in infos {
+            if let Some(closure_size) = closure_size {
+                println!(
+                    "{}\t{}\t{}",
+                    S::store_path(&info.path),
+                    info.size,
+                    closure_size
+                );
+            } else {
+                println!("{}", S::store_path(&info.path));
+            }
+        }
+    }
+    Ok(())
+}
+
+/// the size of `path` and of everything it references, `infos` contains its closure
+fn closure_size(infos: &BTreeMap<StorePath, PathInfo>, path: &StorePath) -> u64 {
+    let mut seen = BTreeSet::new();
+    let mut queue = vec![path];
+    let mut size = 0;
+    while let Some(p) = queue.pop() {
+        if !seen.insert(p) {
+            continue;
+        }
+        let info = &infos[p];
+        size += info.size;
+        queue.extend(info.refs.iter().filter(|r| !seen.contains(r)));
+    }
+    size
+}
@@ -0,0 +1,15 @@
+use anyhow::Result;
+use oxide_store::{
+    protocol::{Access, serve},
+    stores::any::AnyStore,
+};
+use tokio::io;
+
+type S = AnyStore;
+
+/// serve the local store or the daemon on stdin and stdout, see `oxide_store::protocol`
+pub async fn serve_stdio_cli() -> Result<()> {
+    let store = S::auto().await?;
+    // the client can only do what the user running this command can do
+    serve(&store, io::stdin(), io::stdout(), Access::Trusted).await
+}
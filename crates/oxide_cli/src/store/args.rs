@@ -0,0 +1,33 @@
+use clap::{Parser, Subcommand};
+
+#[derive(Parser, Clone, Debug)]
+pub struct StoreArgs {
+    #[command(subcommand)]
+    pub command: StoreCommand,
+}
+
+#[derive(Subcommand, Clone, Debug)]
+pub enum StoreCommand {
+    /// Replace identical files in the store with hard links
+    Optimise,
+    /// Remove build directories, unregistered outputs and lock files left by crashed runs
+    CleanTemp {
+        /// Only remove entries that were not modified for this many seconds
+        #[arg(long, default_value_t = 3600)]
+        older_than: u64,
+    },
+    /// Show the last reproducibility check of every output checked with `oxide build --check`
+    Checks {
+        /// Only show the outputs that were not reproducible
+        #[arg(long)]
+        failed: bool,
+    },
+    /// Write the closure of the given paths to stdout
+    Export {
+        /// Store paths or `oxide#pkg_name`
+        #[arg(required = true)]
+        paths: Vec<String>,
+    },
+    /// Read paths exported with `oxide store export` from stdin and add them to the store
+    Import,
+}
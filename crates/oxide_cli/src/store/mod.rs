@@ -0,0 +1,83 @@
+mod args;
+pub use args::*;
+
+use crate::installable::resolve_installable;
+use anyhow::Result;
+use oxide_store::{
+    api::Store,
+    export::{export_closure, import_closure},
+    stores::{any::AnyStore, local::LocalStore},
+};
+use std::time::Duration;
+use tokio::io;
+
+type S = AnyStore;
+
+pub async fn store_cli(args: StoreArgs) -> Result<()> {
+    match args.command {
+        StoreCommand::Optimise => optimise().await,
+        StoreCommand::CleanTemp { older_than } => clean_temp(older_than).await,
+        StoreCommand::Checks { failed } => checks(failed).await,
+        StoreCommand::Export { paths } => export(paths).await,
+        StoreCommand::Import => import().await,
+    }
+}
+
+async fn optimise() -> Result<()> {
+    let store = LocalStore::new().await?;
+    let stats = store.optimise_store().await?;
+    println!(
+        "{} files hard-linked, {} bytes freed",
+        stats.files_linked, stats.bytes_freed
+    );
+    Ok(())
+}
+
+async fn clean_temp(older_than: u64) -> Result<()> {
+    let store = LocalStore::new().await?;
+    let stats = store.clean_temp(Duration::from_secs(older_than)).await?;
+    println!(
+        "{} entries removed, {} bytes freed",
+        stats.entries_removed, stats.bytes_freed
+    );
+    Ok(())
+}
+
+async fn checks(failed: bool) -> Result<()> {
+    let store = S::auto().await?;
+    let checks = store.query_checks().await?;
+    let differing = checks.iter().filter(|c| c.mismatches != 0).count();
+    for check in checks {
+        let drv = S::store_path(&check.drv);
+        if check.mismatches != 0 {
+            println!(
+                "{drv}!{}: not reproducible, {} of {} rebuilds differed",
+                check.out, check.mismatches, check.rounds
+            );
+        } else if !failed {
+            println!(
+                "{drv}!{}: reproducible, {} rebuilds",
+                check.out, check.rounds
+            );
+        }
+    }
+    println!("{differing} outputs are not reproducible");
+    Ok(())
+}
+
+async fn export(installables: Vec<String>) -> Result<()> {
+    let store = S::auto().await?;
+    let mut paths = Vec::new();
+    for installable in &installables {
+        paths.extend(resolve_installable(&store, installable).await?);
+    }
+    export_closure(&store, &paths, io::stdout()).await
+}
+
+async fn import() -> Result<()> {
+    let store = S::auto().await?;
+    for path in import_closure(&store, io::stdin()).await? {
+        println!("{}", S::store_path(&path));
+    }
+    Ok(())
+}
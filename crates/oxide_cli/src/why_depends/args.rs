@@ -0,0 +1,12 @@
+use clap::Parser;
+
+#[derive(Parser, Clone, Debug)]
+pub struct WhyDependsArgs {
+    /// Store path or `oxide#pkg_name` whose closure contains `dep`
+    pub path: String,
+    /// Store path or `oxide#pkg_name` of the dependency
+    pub dep: String,
+    /// Show every chain of references instead of the shortest one
+    #[arg(short, long)]
+    pub all: bool,
+}
@@ -0,0 +1,53 @@
+mod args;
+pub use args::*;
+
+use crate::installable::resolve_installable;
+use anyhow::Result;
+use oxide_store::{
+    api::Store,
+    stores::any::AnyStore,
+    why_depends::{MAX_CHAINS, why_depends},
+};
+
+type S = AnyStore;
+
+pub async fn why_depends_cli(args: WhyDependsArgs) -> Result<()> {
+    let store = S::auto().await?;
+    let paths = resolve_installable(&store, &args.path).await?;
+    let deps = resolve_installable(&store, &args.dep).await?;
+    for path in &paths {
+        for dep in &deps {
+            let chains = why_depends(&store, path, dep, args.all).await?;
+            if chains.is_empty() {
+                println!(
+                    "{} does not depend on {}",
+                    S::store_path(path),
+                    S::store_path(dep)
+                );
+                continue;
+            }
+            let chains_len = chains.len();
+            for chain in chains {
+                println!("{}", S::store_path(path));
+                for (depth, hop) in chain.iter().enumerate() {
+                    let indent = "    ".repeat(depth);
+                    println!("{indent}└── {}", S::store_path(&hop.to));
+                    let from = S::store_path(&hop.from);
+                    for occ in &hop.occurrences {
+                        let file = occ.file.strip_prefix(&from).unwrap_or(&occ.file);
+                        println!(
+                            "{indent}    {}:{}: …{}…",
+                            file.display(),
+                            occ.offset,
+                            occ.context
+                        );
+                    }
+                }
+            }
+            if chains_len == MAX_CHAINS {
+                println!("only the first {MAX_CHAINS} chains are shown");
+            }
+        }
+    }
+    Ok(())
+}
@@ -7,7 +7,8 @@ use std::fmt::Display;
 
 pub const BASE64: GeneralPurpose = BASE64_URL_SAFE_NO_PAD;
 
-#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
 #[non_exhaustive]
 pub enum HashAlgo {
     Sha256,
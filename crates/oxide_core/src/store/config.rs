@@ -3,11 +3,87 @@ use std::env;
 pub const STORE_DIR: &str = "/var/lib/oxide/store";
 pub const LOG_DIR: &str = "/var/log/oxide";
 pub const STATE_DIR: &str = "/var/lib/oxide/var";
+/// environment variables fixed-output derivations can get from the caller by default
+pub const IMPURE_ENV_VARS: &[&str] = &[
+    "http_proxy",
+    "https_proxy",
+    "ftp_proxy",
+    "all_proxy",
+    "no_proxy",
+    "HTTP_PROXY",
+    "HTTPS_PROXY",
+    "FTP_PROXY",
+    "ALL_PROXY",
+    "NO_PROXY",
+    "SSL_CERT_FILE",
+    "CURL_CA_BUNDLE",
+];
+/// first uid given to builds with `auto_allocate_uids`
+pub const START_ID: u32 = 872_415_232;
 
+/// How builders are isolated from the rest of the system
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SandboxMode {
+    Enabled,
+    Disabled,
+    /// sandbox only if the system supports it
+    Relaxed,
+}
+
+#[allow(clippy::struct_excessive_bools)]
 pub struct Config {
     pub store_dir: String,
     pub log_dir: String,
     pub state_dir: String,
+    /// hard-link identical files every time a path is added to the store
+    pub auto_optimise: bool,
+    /// binary caches queried before building, in order of preference
+    pub substituters: Vec<String>,
+    /// keys whose signatures are trusted, `<name>:<base64>`
+    pub trusted_public_keys: Vec<String>,
+    /// number of trusted signatures a realisation that was not built locally needs
+    pub required_signatures: usize,
+    /// unix socket the daemon listens on
+    pub daemon_socket: String,
+    /// users that can connect to the daemon, `*` for everyone and `@group` for a group
+    pub allowed_users: Vec<String>,
+    /// users that can do everything the daemon can, same syntax as `allowed_users`
+    pub trusted_users: Vec<String>,
+    /// when running as root builds run as one of the members of this group,
+    /// builds without a sandbox need the store dir to be writable by it and sticky
+    pub build_users_group: Option<String>,
+    /// give every build a uid from `start_id` instead of using the members of `build_users_group`
+    pub auto_allocate_uids: bool,
+    pub start_id: u32,
+    /// number of uids that can be allocated, so also the number of builds at the same time
+    pub id_count: u32,
+    /// `true`, `false` or `relaxed`
+    pub sandbox: SandboxMode,
+    /// host paths visible inside of the sandbox, `<path>` or `<path in the sandbox>=<path>`
+    pub sandbox_paths: Vec<String>,
+    /// environment variables of the caller given to fixed-output derivations,
+    /// they are not part of the derivation so they cannot change its hash
+    pub impure_env_vars: Vec<String>,
+    /// deny syscalls that make outputs unsafe or not reproducible
+    /// like setting setuid bits or extended attributes
+    pub filter_syscalls: bool,
+    /// seconds a builder can run before it is killed, 0 for no limit,
+    /// a derivation can change it with its `timeout` env
+    pub timeout: u64,
+    /// seconds a builder can go without writing anything before it is killed, 0 for no limit,
+    /// a derivation can change it with its `max_silent_time` env
+    pub max_silent_time: u64,
+    /// lines of the output of a failed builder shown in its error
+    pub log_lines: usize,
+    /// compress build logs with zstd once the build is done
+    pub compress_build_logs: bool,
+    /// number of builders running at the same time
+    pub max_jobs: usize,
+    /// number of cores a builder should use, given to it as `OXIDE_BUILD_CORES`, 0 for all of them
+    pub cores: usize,
+    /// shell started in the environment of a failed build by `--debug-shell`,
+    /// with the sandbox it must be one of the inputs or in `sandbox_paths`
+    pub debug_shell: String,
 }
 
 impl Config {
@@ -15,10 +91,59 @@ impl Config {
         let store_dir = env::var("OXIDE_STORE_DIR").unwrap_or(STORE_DIR.to_string());
         let log_dir = env::var("OXIDE_LOG_DIR").unwrap_or(LOG_DIR.to_string());
         let state_dir = env::var("OXIDE_STATE_DIR").unwrap_or(STATE_DIR.to_string());
+        let auto_optimise = env_bool("OXIDE_AUTO_OPTIMISE", false);
+        let substituters = env_list("OXIDE_SUBSTITUTERS");
+        let trusted_public_keys = env_list("OXIDE_TRUSTED_PUBLIC_KEYS");
+        let required_signatures = env_usize("OXIDE_REQUIRED_SIGNATURES", 1);
+        let daemon_socket =
+            env::var("OXIDE_DAEMON_SOCKET").unwrap_or(format!("{state_dir}/daemon/socket"));
+        let allowed_users = env_list_or("OXIDE_ALLOWED_USERS", &["*"]);
+        let trusted_users = env_list_or("OXIDE_TRUSTED_USERS", &["root"]);
+        let build_users_group = env::var("OXIDE_BUILD_USERS_GROUP").ok();
+        let auto_allocate_uids = env_bool("OXIDE_AUTO_ALLOCATE_UIDS", false);
+        let start_id = env_u32("OXIDE_START_ID", START_ID);
+        let id_count = env_u32("OXIDE_ID_COUNT", 128);
+        let sandbox = match env::var("OXIDE_SANDBOX").as_deref() {
+            Ok("0" | "false") => SandboxMode::Disabled,
+            Ok("relaxed") => SandboxMode::Relaxed,
+            _ => SandboxMode::Enabled,
+        };
+        let sandbox_paths = env_list("OXIDE_SANDBOX_PATHS");
+        let impure_env_vars = env_list_or("OXIDE_IMPURE_ENV_VARS", IMPURE_ENV_VARS);
+        let filter_syscalls = env_bool("OXIDE_FILTER_SYSCALLS", true);
+        let timeout = env_u64("OXIDE_TIMEOUT", 0);
+        let max_silent_time = env_u64("OXIDE_MAX_SILENT_TIME", 0);
+        let log_lines = env_usize("OXIDE_LOG_LINES", 25);
+        let compress_build_logs = env_bool("OXIDE_COMPRESS_BUILD_LOGS", true);
+        let max_jobs = env_usize("OXIDE_MAX_JOBS", 1);
+        let cores = env_usize("OXIDE_CORES", 0);
+        let debug_shell = env::var("OXIDE_DEBUG_SHELL").unwrap_or("/bin/sh".to_string());
         Self {
             store_dir,
             log_dir,
             state_dir,
+            auto_optimise,
+            substituters,
+            trusted_public_keys,
+            required_signatures,
+            daemon_socket,
+            allowed_users,
+            trusted_users,
+            build_users_group,
+            auto_allocate_uids,
+            start_id,
+            id_count,
+            sandbox,
+            sandbox_paths,
+            impure_env_vars,
+            filter_syscalls,
+            timeout,
+            max_silent_time,
+            log_lines,
+            compress_build_logs,
+            max_jobs,
+            cores,
+            debug_shell,
         }
     }
 }
@@ -28,3 +153,42 @@ impl Default for Config {
         Self::new()
     }
 }
+
+fn env_bool(key: &str, default: bool) -> bool {
+    env::var(key).map_or(default, |v| v == "1" || v == "true")
+}
+
+fn env_list(key: &str) -> Vec<String> {
+    env::var(key).map_or(Vec::new(), |v| {
+        v.split_whitespace().map(ToString::to_string).collect()
+    })
+}
+
+fn env_list_or(key: &str, default: &[&str]) -> Vec<String> {
+    if env::var(key).is_ok() {
+        env_list(key)
+    } else {
+        default.iter().map(ToString::to_string).collect()
+    }
+}
+
+fn env_usize(key: &str, default: usize) -> usize {
+    env::var(key)
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(default)
+}
+
+fn env_u32(key: &str, default: u32) -> u32 {
+    env::var(key)
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(default)
+}
+
+fn env_u64(key: &str, default: u64) -> u64 {
+    env::var(key)
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(default)
+}
@@ -0,0 +1,68 @@
+//! Who can use the daemon, see `allowed_users` and `trusted_users` in the config
+
+use libc::{gid_t, uid_t};
+use oxide_store::{
+    api::CONFIG,
+    protocol::Access,
+    users::{group_list, group_name, user_by_uid},
+};
+use std::fmt::Display;
+
+/// The user on the other side of a connection
+pub struct Peer {
+    pub uid: uid_t,
+    /// the uid if the user has no name
+    pub name: String,
+    pub groups: Vec<String>,
+}
+
+impl Peer {
+    /// `gid` is the primary group of the process that connected
+    pub fn new(uid: uid_t, gid: gid_t) -> Self {
+        let (name, gids) = match user_by_uid(uid) {
+            Some((name, user_gid)) => {
+                let mut gids = group_list(&name, user_gid);
+                gids.push(gid);
+                (name, gids)
+            }
+            None => (uid.to_string(), vec![gid]),
+        };
+        let mut groups = gids
+            .into_iter()
+            .map(|gid| group_name(gid).unwrap_or(gid.to_string()))
+            .collect::<Vec<_>>();
+        groups.sort();
+        groups.dedup();
+        Self { uid, name, groups }
+    }
+
+    pub fn access(&self) -> Access {
+        // the user running the daemon can write the store anyway
+        if self.uid == unsafe { libc::geteuid() } || self.matches(&CONFIG.trusted_users) {
+            Access::Trusted
+        } else if self.matches(&CONFIG.allowed_users) {
+            Access::Untrusted
+        } else {
+            Access::Denied
+        }
+    }
+
+    /// entries are user names, `@group` or `*`
+    fn matches(&self, list: &[String]) -> bool {
+        list.iter().any(|entry| {
+            if entry == "*" {
+                true
+            } else if let Some(group) = entry.strip_prefix('@') {
+                self.groups.iter().any(|g| g == group)
+            } else {
+                *entry == self.name
+            }
+        })
+    }
+}
+
+impl Display for Peer {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} (uid {})", self.name, self.uid)
+    }
+}
@@ -1 +1,72 @@
-// TODO
+//! Store daemon
+//!
+//! The daemon owns the local store and its database and serves them on a unix socket,
+//! see `oxide_store::protocol`. Users that cannot write the store themselves
+//! use it through `oxide_store::stores::remote::DaemonStore`.
+
+mod auth;
+
+use anyhow::Result;
+use auth::Peer;
+use log::{info, warn};
+use oxide_store::{
+    api::CONFIG,
+    protocol::{Access, serve},
+    stores::local::LocalStore,
+};
+use std::{fs::Permissions, os::unix::fs::PermissionsExt, path::Path, thread};
+use tokio::{
+    fs,
+    net::{UnixListener, UnixStream},
+    runtime,
+};
+
+/// listen on the daemon socket and serve every connection until the process is killed
+pub async fn run_daemon() -> Result<()> {
+    let socket = CONFIG.daemon_socket.clone();
+    let store = LocalStore::new().await?;
+    if let Some(parent) = Path::new(&socket).parent() {
+        fs::create_dir_all(parent).await?;
+    }
+    // left behind by a daemon that did not exit cleanly
+    if fs::symlink_metadata(&socket).await.is_ok() {
+        fs::remove_file(&socket).await?;
+    }
+    let listener = UnixListener::bind(&socket)?;
+    fs::set_permissions(&socket, Permissions::from_mode(0o666)).await?;
+    info!("listening on {socket}");
+    loop {
+        let (stream, _) = listener.accept().await?;
+        let cred = stream.peer_cred()?;
+        let peer = Peer::new(cred.uid(), cred.gid());
+        let access = peer.access();
+        if access == Access::Denied {
+            warn!("refusing connection from {peer}");
+        } else {
+            info!("connection from {peer}: {access:?}");
+        }
+        let stream = stream.into_std()?;
+        let store = store.clone();
+        // builds block the thread they run on so every connection gets its own
+        thread::spawn(move || {
+            if let Err(e) = serve_connection(&store, stream, access) {
+                warn!("connection closed: {e:#}");
+            }
+        });
+    }
+}
+
+fn serve_connection(
+    store: &LocalStore,
+    stream: std::os::unix::net::UnixStream,
+    access: Access,
+) -> Result<()> {
+    let rt = runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()?;
+    rt.block_on(async {
+        let stream = UnixStream::from_std(stream)?;
+        let (reader, writer) = stream.into_split();
+        serve(store, reader, writer, access).await
+    })
+}
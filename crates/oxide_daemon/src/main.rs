@@ -0,0 +1,17 @@
+mod logger;
+
+use anyhow::Result;
+use log::LevelFilter;
+use logger::Logger;
+use oxide_daemon::run_daemon;
+
+fn main() -> Result<()> {
+    if log::set_logger(&Logger).is_ok() {
+        log::set_max_level(LevelFilter::Info);
+    }
+    tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()
+        .expect("could not spawn tokio runtime")
+        .block_on(run_daemon())
+}
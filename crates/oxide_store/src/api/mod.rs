@@ -1,18 +1,26 @@
 mod opt;
 pub use opt::*;
 
-use crate::{types::Realisation, utils::tempfile::tempfile_in};
-use anyhow::Result;
+use crate::{
+    archive::write_archive,
+    build::{BuildOpts, BuildPlan, Built, build, plan_build},
+    export::{read_export, write_export},
+    hash::utils::is_valid_hash_char,
+    types::{CheckResult, ObjInfo, PathInfo, Realisation, RealisationInfo},
+    utils::{is_valid_name, tempfile::tempfile_in},
+};
+use anyhow::{Result, bail};
 use oxide_core::{
     drv::StoreDrv,
-    store::{StorePath, config::Config},
+    store::{HASH_PART_LEN, StorePath, config::Config},
     types::{EqClass, Out},
 };
 use std::cell::LazyCell;
-use std::path::Path;
+use std::collections::BTreeSet;
+use std::path::{Path, PathBuf};
 use tokio::{
     fs,
-    io::{self, AsyncBufRead, BufReader},
+    io::{self, AsyncBufRead, AsyncRead, AsyncWrite, BufReader},
 };
 
 // TODO: maybe do not use a global variable
@@ -39,6 +47,7 @@ pub trait Store {
                 name: opt.name,
                 rewrites: opt.rewrites,
                 self_hash: opt.self_hash,
+                deriver: opt.deriver,
             },
         )
         .await
@@ -52,6 +61,28 @@ pub trait Store {
         format!("{}/{}", CONFIG.store_dir, path)
     }
 
+    /// parse either a full path inside of the store or the base name of a store path
+    fn parse_store_path(s: &str) -> Result<StorePath> {
+        let store_dir = Self::store_dir();
+        let base_name = match s.strip_prefix(&store_dir) {
+            Some(rest) => rest
+                .trim_start_matches('/')
+                .split('/')
+                .next()
+                .unwrap_or_default(),
+            None if s.starts_with('/') => bail!("{s} is not in the store {store_dir}"),
+            None => s,
+        };
+        let valid = base_name.len() > HASH_PART_LEN + 1
+            && base_name[..HASH_PART_LEN].chars().all(is_valid_hash_char)
+            && base_name.as_bytes()[HASH_PART_LEN] == b'-'
+            && is_valid_name(&base_name[HASH_PART_LEN + 1..]);
+        if !valid {
+            bail!("{s} is not a valid store path");
+        }
+        Ok(unsafe { StorePath::from_string(base_name.to_string()) })
+    }
+
     async fn read_drv(&self, p: &StorePath) -> Result<StoreDrv> {
         let path = Self::store_path(p);
         let buff = fs::read(&path).await?;
@@ -59,7 +90,114 @@ pub trait Store {
         Ok(toml::from_str(&drv_str)?)
     }
 
+    /// build the derivations `drvs` and return their outputs in the same order
+    async fn build_drvs(&self, drvs: &[StorePath], opts: &BuildOpts) -> Result<Built>
+    where
+        Self: Sized,
+    {
+        build(self, drvs, opts).await
+    }
+
+    /// what building `drvs` would do, without substituting or building anything
+    async fn plan_build(&self, drvs: &[StorePath]) -> Result<BuildPlan>
+    where
+        Self: Sized,
+    {
+        plan_build(self, drvs).await
+    }
+
     async fn trusted_paths(&self, eq_class: &EqClass, out: &Out) -> Result<Vec<StorePath>>;
 
     async fn realisation_refs(&self, realisation: &Realisation) -> Result<Vec<Realisation>>;
+
+    /// the realisations that have `path` as output
+    async fn query_realisations(&self, path: &StorePath) -> Result<Vec<RealisationInfo>>;
+
+    /// where paths are unpacked before [`Store::import_paths`] registers them
+    fn import_dir(&self) -> PathBuf {
+        PathBuf::from(Self::store_dir())
+    }
+
+    /// register paths that were already unpacked and verified in a temporary location
+    /// paths that are already valid are skipped but their realisations are registered,
+    /// the temporary location is `None` if the caller already knows the path is valid
+    async fn import_paths(&self, objs: Vec<(ObjInfo, Option<PathBuf>)>) -> Result<()>;
+
+    /// write the archive of a valid path, see [`crate::archive`]
+    async fn dump_path<W>(&self, path: &StorePath, writer: &mut W) -> Result<()>
+    where
+        W: AsyncWrite + Unpin,
+    {
+        write_archive(Self::store_path(path), writer).await
+    }
+
+    /// write `paths` as an export stream, `paths` must be sorted with [`crate::export::sort_paths`]
+    /// only the metadata of the paths in `valid` is written
+    async fn export_paths<W>(
+        &self,
+        paths: &[StorePath],
+        valid: &BTreeSet<StorePath>,
+        writer: W,
+    ) -> Result<()>
+    where
+        W: AsyncWrite + Unpin,
+    {
+        write_export(self, paths, valid, writer).await
+    }
+
+    /// import an export stream, returns the paths in the stream
+    async fn import_stream<R>(&self, reader: R) -> Result<Vec<StorePath>>
+    where
+        R: AsyncRead + Unpin,
+    {
+        read_export(self, reader).await
+    }
+
+    /// attach signatures to a realisation that is already registered
+    async fn add_signatures(&self, realisation: &Realisation, sigs: Vec<String>) -> Result<()>;
+
+    /// remember the outcome of building an output again with `check`
+    async fn record_check(&self, result: &CheckResult) -> Result<()>;
+
+    /// the last check of every output that was checked
+    async fn query_checks(&self) -> Result<Vec<CheckResult>>;
+
+    /// write the log of the last build of `drv` to `writer`, `false` if it has none
+    async fn read_build_log<W>(&self, drv: &StorePath, writer: &mut W) -> Result<bool>
+    where
+        W: AsyncWrite + Unpin;
+
+    /// `None` if the path is not valid
+    async fn query_path_info(&self, path: &StorePath) -> Result<Option<PathInfo>>;
+
+    /// the valid paths that reference `path`
+    async fn query_referrers(&self, path: &StorePath) -> Result<BTreeSet<StorePath>>;
+
+    /// the subset of `paths` that are valid
+    async fn query_valid_paths(&self, paths: &[StorePath]) -> Result<BTreeSet<StorePath>> {
+        let mut valid = BTreeSet::new();
+        for path in paths {
+            if self.query_path_info(path).await?.is_some() {
+                valid.insert(path.clone());
+            }
+        }
+        Ok(valid)
+    }
+
+    /// every path reachable from `paths` through references, `paths` included
+    async fn compute_closure(&self, paths: &[StorePath]) -> Result<BTreeSet<StorePath>> {
+        let mut closure = BTreeSet::new();
+        let mut queue = paths.to_vec();
+        while let Some(path) = queue.pop() {
+            if closure.contains(&path) {
+                continue;
+            }
+            let Some(info) = self.query_path_info(&path).await? else {
+                bail!("path {} is not valid", Self::store_path(&path));
+            };
+            queue.extend(info.refs.into_iter().filter(|r| !closure.contains(r)));
+            closure.insert(path);
+        }
+        Ok(closure)
+    }
 }
@@ -4,14 +4,17 @@ use oxide_core::{
     store::StorePath,
     types::{EqClass, Out},
 };
+use serde::{Deserialize, Serialize};
 use std::collections::{HashMap, HashSet};
 
+#[derive(Serialize, Deserialize)]
 pub struct EqRefs {
     pub eq_class: EqClass,
     pub out: Out,
     pub refs: Vec<Realisation>,
 }
 
+#[derive(Serialize, Deserialize)]
 pub struct Opt {
     pub algo: HashAlgo,
     pub refs: HashSet<StorePath>,
@@ -19,4 +22,5 @@ pub struct Opt {
     pub name: String,
     pub rewrites: HashMap<StorePath, StorePath>,
     pub self_hash: Option<StorePath>,
+    pub deriver: Option<StorePath>,
 }
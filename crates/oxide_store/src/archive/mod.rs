@@ -0,0 +1,438 @@
+//! Oxide archive format
+//!
+//! A deterministic serialization of a store path whose hash can be computed
+//! while it streams. The digest returned by [`read_archive`] is the same as
+//! the one computed by `hash_mod_rewrites` on the unpacked tree.
+//!
+//! ```text
+//! archive := entry
+//! entry   := u64(DIR_TYPE) u64(n) (bytes(name) entry){n}
+//!          | u64(FILE_TYPE) bytes(content)
+//!          | u64(EXEC_TYPE) bytes(content)
+//!          | u64(SYMLINK_TYPE) bytes(target)
+//! bytes   := u64(len) byte{len}
+//! ```
+//!
+//! Every integer is a big endian u64, the file types are the ones used to hash directories.
+//! Directory entries are strictly sorted by name and names cannot be empty,
+//! `.`, `..` or contain `/`.
+
+use crate::hash::{
+    DIR_TYPE, EXEC_TYPE, FILE_TYPE, SYMLINK_TYPE, hash_symlink_target, search_self_hash,
+};
+use anyhow::{Result, bail};
+use oxide_core::{
+    hash::{Hash, HashAlgo},
+    store::{HASH_PART_LEN, StorePath},
+};
+use sha2::{Digest, Sha256, Sha512};
+use std::{
+    collections::BTreeMap,
+    ffi::OsStr,
+    fs::Permissions,
+    os::unix::{ffi::OsStrExt, fs::PermissionsExt},
+    path::Path,
+};
+use tokio::{
+    fs::{self, File},
+    io::{self, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+};
+
+/// names of files inside of a store path cannot be longer than this
+const MAX_NAME_LEN: u64 = 255;
+/// symlink targets cannot be longer than this
+const MAX_TARGET_LEN: u64 = 4096;
+
+const BUFF_SIZE: usize = 64 * 1024;
+
+/// serialize `path` into `writer`
+pub async fn write_archive<P, W>(path: P, writer: &mut W) -> Result<()>
+where
+    P: AsRef<Path>,
+    W: AsyncWrite + Unpin,
+{
+    let path = path.as_ref();
+    let metadata = fs::symlink_metadata(path).await?;
+    if metadata.is_dir() {
+        let mut entries = fs::read_dir(path).await?;
+        let mut sorted_entries = BTreeMap::new();
+        while let Some(entry) = entries.next_entry().await? {
+            sorted_entries.insert(entry.file_name(), entry.path());
+        }
+        write_u64(writer, DIR_TYPE).await?;
+        write_u64(writer, sorted_entries.len() as u64).await?;
+        for (name, path) in sorted_entries {
+            write_bytes(writer, name.as_encoded_bytes()).await?;
+            Box::pin(write_archive(path, writer)).await?;
+        }
+    } else if metadata.is_file() {
+        let ft = if metadata.permissions().mode() & 0o111 == 0 {
+            FILE_TYPE
+        } else {
+            EXEC_TYPE
+        };
+        write_u64(writer, ft).await?;
+        write_u64(writer, metadata.len()).await?;
+        let mut file = File::open(path).await?.take(metadata.len());
+        let n = io::copy(&mut file, writer).await?;
+        if n != metadata.len() {
+            bail!("file {} changed while archiving it", path.display());
+        }
+    } else if metadata.is_symlink() {
+        let target = fs::read_link(path).await?;
+        write_u64(writer, SYMLINK_TYPE).await?;
+        write_bytes(writer, target.as_os_str().as_encoded_bytes()).await?;
+    } else {
+        bail!("unknown file type {}", path.display());
+    }
+    Ok(())
+}
+
+/// unpack an archive into `dest` and return its hash
+/// if `dest` is `None` the archive is only hashed
+/// `self_hash` is the store path the archive belongs to, it is zeroed while hashing
+/// in the content of files and in the target of symlinks
+pub async fn read_archive<R>(
+    reader: &mut R,
+    dest: Option<&Path>,
+    algo: HashAlgo,
+    self_hash: Option<&StorePath>,
+) -> Result<Hash>
+where
+    R: AsyncRead + Unpin,
+{
+    macro_rules! hash_algos {
+        ($($algo:pat, $hash:expr, $hasher:ty);*;) => {
+            Ok(match algo {
+            $(
+                $algo => $hash(
+                    read_entry::<$hasher, _>(reader, dest, self_hash)
+                        .await?
+                        .1
+                        .try_into()
+                        .unwrap(),
+                ),
+            )*
+                _ => bail!("unimplemented hash algo"),
+            })
+        };
+    }
+    hash_algos!(
+        HashAlgo::Sha256, Hash::Sha256, Sha256;
+        HashAlgo::Sha512, Hash::Sha512, Sha512;
+    )
+}
+
+/// returns the file type and the hash of the entry
+async fn read_entry<H, R>(
+    reader: &mut R,
+    dest: Option<&Path>,
+    self_hash: Option<&StorePath>,
+) -> Result<(u64, Vec<u8>)>
+where
+    H: Digest,
+    R: AsyncRead + Unpin,
+{
+    let ft = read_u64(reader).await?;
+    let hash = match ft {
+        DIR_TYPE => {
+            if let Some(dest) = dest {
+                fs::create_dir(dest).await?;
+            }
+            let len = read_u64(reader).await?;
+            let mut hasher = H::new();
+            let mut prev: Option<Vec<u8>> = None;
+            for _ in 0..len {
+                let name = read_bytes(reader, MAX_NAME_LEN).await?;
+                if name.is_empty() || name == b"." || name == b".." || name.contains(&b'/') {
+                    bail!("invalid file name in archive");
+                }
+                if prev.as_ref().is_some_and(|prev| *prev >= name) {
+                    bail!("archive entries are not sorted");
+                }
+                let child = dest.map(|p| p.join(OsStr::from_bytes(&name)));
+                let (ft, hash) =
+                    Box::pin(read_entry::<H, _>(reader, child.as_deref(), self_hash)).await?;
+                hasher.update(ft.to_be_bytes());
+                hasher.update((name.len() as u64).to_be_bytes());
+                hasher.update(&name);
+                hasher.update((hash.len() as u64).to_be_bytes());
+                hasher.update(hash);
+                prev = Some(name);
+            }
+            hasher.finalize().to_vec()
+        }
+        FILE_TYPE | EXEC_TYPE => {
+            let mut file = match dest {
+                Some(dest) => Some(File::create(dest).await?),
+                None => None,
+            };
+            let hash = read_file::<H, _>(reader, file.as_mut(), self_hash).await?;
+            if let (Some(mut file), Some(dest)) = (file, dest) {
+                file.flush().await?;
+                let mode = if ft == EXEC_TYPE { 0o755 } else { 0o644 };
+                fs::set_permissions(dest, Permissions::from_mode(mode)).await?;
+            }
+            hash
+        }
+        SYMLINK_TYPE => {
+            let target = read_bytes(reader, MAX_TARGET_LEN).await?;
+            if let Some(dest) = dest {
+                fs::symlink(OsStr::from_bytes(&target), dest).await?;
+            }
+            hash_symlink_target::<H>(&target, self_hash)
+        }
+        _ => bail!("unknown file type in archive"),
+    };
+    Ok((ft, hash))
+}
+
+/// hash the content of a file the same way `hash_mod_rewrites` does
+/// occurrences of `self_hash` are zeroed and their offsets are appended to the hash
+async fn read_file<H, R>(
+    reader: &mut R,
+    mut file: Option<&mut File>,
+    self_hash: Option<&StorePath>,
+) -> Result<Vec<u8>>
+where
+    H: Digest,
+    R: AsyncRead + Unpin,
+{
+    let len = read_u64(reader).await?;
+    let mut content = reader.take(len);
+    let mut hasher = H::new();
+    let mut modulos = Vec::new();
+    // the tail of the previous chunk is kept so that hashes split between two reads are found
+    let mut buff = vec![0; BUFF_SIZE + HASH_PART_LEN - 1];
+    let mut tail_len = 0;
+    let mut offset = 0;
+    loop {
+        let n = content.read(&mut buff[tail_len..]).await?;
+        if n == 0 {
+            break;
+        }
+        if let Some(file) = file.as_mut() {
+            file.write_all(&buff[tail_len..tail_len + n]).await?;
+        }
+        let chunk_len = tail_len + n;
+        let chunk_offset = offset - tail_len as u64;
+        offset += n as u64;
+        if let Some(self_hash) = self_hash {
+            for i in search_self_hash(&buff[..chunk_len], self_hash) {
+                buff[i..i + HASH_PART_LEN].fill(0);
+                modulos.push(chunk_offset + i as u64);
+            }
+        }
+        let tail_start = chunk_len.saturating_sub(HASH_PART_LEN - 1);
+        hasher.update(&buff[..tail_start]);
+        buff.copy_within(tail_start..chunk_len, 0);
+        tail_len = chunk_len - tail_start;
+    }
+    if offset != len {
+        bail!("unexpected end of archive");
+    }
+    hasher.update(&buff[..tail_len]);
+
+    hasher.update(u64::MAX.to_be_bytes());
+    for modulo in modulos {
+        hasher.update(u64::MAX.to_be_bytes());
+        hasher.update(modulo.to_be_bytes());
+    }
+    Ok(hasher.finalize().to_vec())
+}
+
+pub(crate) async fn write_u64<W>(writer: &mut W, v: u64) -> Result<()>
+where
+    W: AsyncWrite + Unpin,
+{
+    writer.write_all(&v.to_be_bytes()).await?;
+    Ok(())
+}
+
+pub(crate) async fn write_bytes<W>(writer: &mut W, buff: &[u8]) -> Result<()>
+where
+    W: AsyncWrite + Unpin,
+{
+    write_u64(writer, buff.len() as u64).await?;
+    writer.write_all(buff).await?;
+    Ok(())
+}
+
+pub(crate) async fn read_u64<R>(reader: &mut R) -> Result<u64>
+where
+    R: AsyncRead + Unpin,
+{
+    let mut buff = [0; 8];
+    reader.read_exact(&mut buff).await?;
+    Ok(u64::from_be_bytes(buff))
+}
+
+pub(crate) async fn read_bytes<R>(reader: &mut R, max_len: u64) -> Result<Vec<u8>>
+where
+    R: AsyncRead + Unpin,
+{
+    let len = read_u64(reader).await?;
+    if len > max_len {
+        bail!("string too long in archive");
+    }
+    let mut buff = vec![0; usize::try_from(len)?];
+    reader.read_exact(&mut buff).await?;
+    Ok(buff)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hash::{hash_mod_rewrites, rewrite_self_hash, utils::random_path};
+    use crate::utils::{remove_path, tempfile::tempdir_in};
+    use std::collections::HashMap;
+
+    /// a tree with every file type, each one referring to `self_hash`
+    async fn make_tree(root: &Path, self_hash: &StorePath) -> Result<()> {
+        let own = format!("/store/{self_hash}");
+        fs::create_dir(root).await?;
+        fs::create_dir(root.join("bin")).await?;
+        fs::write(
+            root.join("bin/run"),
+            format!("#!/bin/sh\nexec {own}/lib/a\n"),
+        )
+        .await?;
+        fs::set_permissions(root.join("bin/run"), Permissions::from_mode(0o755)).await?;
+        fs::write(root.join("empty"), "").await?;
+        fs::create_dir(root.join("lib")).await?;
+        fs::write(root.join("lib/a"), format!("{own} {own}")).await?;
+        fs::symlink(format!("{own}/lib/a"), root.join("lib/own")).await?;
+        fs::symlink("a", root.join("lib/rel")).await?;
+        Ok(())
+    }
+
+    async fn archive(path: &Path) -> Result<Vec<u8>> {
+        let mut buff = Vec::new();
+        write_archive(path, &mut buff).await?;
+        Ok(buff)
+    }
+
+    #[tokio::test]
+    async fn archive_hash_matches_tree_hash() -> Result<()> {
+        let dir = tempdir_in(std::env::temp_dir()).await?;
+        let self_hash = random_path("out");
+        let tree = dir.join("tree");
+        make_tree(&tree, &self_hash).await?;
+        let hash =
+            hash_mod_rewrites(&tree, HashAlgo::Sha512, &HashMap::new(), Some(&self_hash)).await?;
+
+        let buff = archive(&tree).await?;
+        let read = read_archive(&mut &buff[..], None, HashAlgo::Sha512, Some(&self_hash)).await?;
+        assert_eq!(read, hash);
+
+        // unpacking gives back the same tree
+        let unpacked = dir.join("unpacked");
+        let read = read_archive(
+            &mut &buff[..],
+            Some(&unpacked),
+            HashAlgo::Sha512,
+            Some(&self_hash),
+        )
+        .await?;
+        assert_eq!(read, hash);
+        assert_eq!(archive(&unpacked).await?, buff);
+
+        remove_path(&dir).await
+    }
+
+    /// pinned so that a change to how trees are hashed is never silent,
+    /// the paths already in the store would no longer match their archives
+    #[tokio::test]
+    async fn symlinks_are_hashed_as_symlinks() -> Result<()> {
+        let dir = tempdir_in(std::env::temp_dir()).await?;
+        let tree = dir.join("tree");
+        fs::create_dir_all(tree.join("dir")).await?;
+        fs::write(tree.join("dir/file"), "content").await?;
+        fs::symlink("dir/file", tree.join("to-file")).await?;
+        fs::symlink("dir", tree.join("to-dir")).await?;
+        let hash = hash_mod_rewrites(&tree, HashAlgo::Sha256, &HashMap::new(), None).await?;
+        assert_eq!(
+            hash.to_string(),
+            "sha256:eRXaIMcXkDA5uBXi1ASSHKalxhxcHGRIuJ9RFaKl7Cc"
+        );
+
+        // a symlink is not hashed as what it points to
+        fs::remove_file(tree.join("to-file")).await?;
+        fs::write(tree.join("to-file"), "content").await?;
+        let copied = hash_mod_rewrites(&tree, HashAlgo::Sha256, &HashMap::new(), None).await?;
+        assert_ne!(copied, hash);
+
+        remove_path(&dir).await
+    }
+
+    #[tokio::test]
+    async fn self_hash_does_not_change_the_hash() -> Result<()> {
+        let dir = tempdir_in(std::env::temp_dir()).await?;
+        let (a, b) = (random_path("out"), random_path("out"));
+        make_tree(&dir.join("a"), &a).await?;
+        make_tree(&dir.join("b"), &b).await?;
+        let hash_a =
+            hash_mod_rewrites(dir.join("a"), HashAlgo::Sha512, &HashMap::new(), Some(&a)).await?;
+        let hash_b =
+            hash_mod_rewrites(dir.join("b"), HashAlgo::Sha512, &HashMap::new(), Some(&b)).await?;
+        assert_eq!(hash_a, hash_b);
+        // without it the references are hashed as they are
+        let hash_a =
+            hash_mod_rewrites(dir.join("a"), HashAlgo::Sha512, &HashMap::new(), None).await?;
+        assert_ne!(hash_a, hash_b);
+
+        // once rewritten the trees are the same, symlinks included
+        rewrite_self_hash(dir.join("b"), &b, &a).await?;
+        assert_eq!(
+            fs::read_link(dir.join("b/lib/own")).await?,
+            fs::read_link(dir.join("a/lib/own")).await?
+        );
+        assert_eq!(
+            archive(&dir.join("a")).await?,
+            archive(&dir.join("b")).await?
+        );
+
+        remove_path(&dir).await
+    }
+
+    /// a directory with an empty file for every name
+    async fn dir_archive(names: &[&str]) -> Result<Vec<u8>> {
+        let mut buff = Vec::new();
+        write_u64(&mut buff, DIR_TYPE).await?;
+        write_u64(&mut buff, names.len() as u64).await?;
+        for name in names {
+            write_bytes(&mut buff, name.as_bytes()).await?;
+            write_u64(&mut buff, FILE_TYPE).await?;
+            write_bytes(&mut buff, b"").await?;
+        }
+        Ok(buff)
+    }
+
+    #[tokio::test]
+    async fn invalid_archives_are_rejected() -> Result<()> {
+        let read =
+            async |buff: Vec<u8>| read_archive(&mut &buff[..], None, HashAlgo::Sha256, None).await;
+        assert!(read(dir_archive(&["a", "b"]).await?).await.is_ok());
+        for names in [
+            &["b", "a"][..],
+            &["a", "a"],
+            &[""],
+            &["."],
+            &[".."],
+            &["a/b"],
+        ] {
+            assert!(read(dir_archive(names).await?).await.is_err(), "{names:?}");
+        }
+
+        let mut buff = Vec::new();
+        write_u64(&mut buff, 0o060_000).await?;
+        assert!(read(buff).await.is_err());
+        // the content is shorter than announced
+        let mut buff = Vec::new();
+        write_u64(&mut buff, FILE_TYPE).await?;
+        write_u64(&mut buff, 10).await?;
+        buff.extend(b"short");
+        assert!(read(buff).await.is_err());
+        Ok(())
+    }
+}
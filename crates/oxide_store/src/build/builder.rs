@@ -1,76 +1,274 @@
+use super::{BuildError, BuildFailure, BuildOpts, BuildTimeout, logs::BuildLog};
 use crate::{
-    api::Store,
+    api::{CONFIG, Store},
     builtins::{Ctx, fetch_url},
-    os::{sandbox::prepare_sandbox, utils::errno},
-    utils::tempfile::tempdir_in,
+    os::{
+        build_users::BuildUser,
+        lock::{LockMode, PathLock},
+        sandbox::{SANDBOX_BUILD_DIR, Sandbox, use_sandbox},
+        seccomp::SyscallFilter,
+        utils::{ChildError, die_with_parent, errno},
+    },
+    utils::{add_lock_ext, chown_path, remove_path, tempfile::tempdir_in},
 };
 use anyhow::{Result, bail};
 use log::warn;
-use oxide_core::drv::StoreDrv;
+use oxide_core::{drv::StoreDrv, store::StorePath, types::Out};
 use std::{
-    collections::HashMap,
+    collections::{BTreeSet, HashMap},
     ffi::{CStr, CString},
-    ptr,
+    io, mem,
+    num::NonZeroUsize,
+    os::unix::ffi::OsStrExt,
+    path::PathBuf,
+    ptr, thread,
+    time::{Duration, Instant},
 };
-
-pub const SANDBOX_BUILD_DIR: &str = "/build";
+use tokio::{fs, sync::Mutex, task};
 
 // TODO: maybe rewrites must be passed here and be used somewhere outside of build
-pub async fn run_builder<S>(drv: &StoreDrv) -> Result<()>
+/// `inputs` are the store paths the builder can read,
+/// returns the log of the builder, builtins have none
+pub async fn run_builder<S>(
+    drv_path: &StorePath,
+    drv: &StoreDrv,
+    outputs: &HashMap<Out, StorePath>,
+    inputs: &BTreeSet<StorePath>,
+    opts: &BuildOpts,
+) -> Result<Option<PathBuf>>
 where
     S: Store,
 {
+    let impure_envs = impure_envs(drv, opts);
     if let Some(builtin) = drv.builtin() {
-        let outputs = drv
-            .eq_classes
+        let outputs = outputs
             .iter()
             .map(|(k, v)| (k.clone(), S::store_path(v)))
             .collect();
-        let ctx = Ctx { drv, outputs };
+        let ctx = Ctx {
+            drv,
+            outputs,
+            impure_envs,
+        };
         if builtin == "fetchurl" {
-            fetch_url(ctx).await
+            fetch_url(ctx).await?;
+            Ok(None)
         } else {
-            bail!("unkown builtin {}", builtin)
+            bail!("unkown builtin {builtin}")
         }
     } else {
-        prepare_build::<S>(drv).await?;
-        Ok(())
+        let log = prepare_build::<S>(drv_path, drv, outputs, inputs, &impure_envs, opts).await?;
+        Ok(Some(log))
+    }
+}
+
+/// give the outputs written by a build user to this process
+async fn reclaim_outputs<S>(outputs: &HashMap<Out, StorePath>) -> Result<()>
+where
+    S: Store,
+{
+    let (uid, gid) = unsafe { (libc::geteuid(), libc::getegid()) };
+    for out in outputs.values() {
+        let out = S::store_path(out);
+        if fs::symlink_metadata(&out).await.is_ok() {
+            chown_path(&out, uid, gid).await?;
+        }
+    }
+    Ok(())
+}
+
+/// the number of cores a builder should use, `cores` of the config or of `opts`,
+/// 0 means all of them
+fn build_cores(opts: &BuildOpts) -> usize {
+    match opts.cores.unwrap_or(CONFIG.cores) {
+        0 => thread::available_parallelism().map_or(1, NonZeroUsize::get),
+        cores => cores,
+    }
+}
+
+/// the environment of the caller that is allowed to reach the builder,
+/// only fixed-output derivations can use it since their outputs are checked
+fn impure_envs(drv: &StoreDrv, opts: &BuildOpts) -> HashMap<String, String> {
+    if drv.fixed_hash.is_none() {
+        return HashMap::new();
+    }
+    opts.impure_envs
+        .iter()
+        .filter(|(k, _)| CONFIG.impure_env_vars.contains(k))
+        .map(|(k, v)| (k.clone(), v.clone()))
+        .collect()
+}
+
+/// env of a derivation overriding `timeout` of the config
+const TIMEOUT_ENV: &str = "timeout";
+/// env of a derivation overriding `max_silent_time` of the config
+const MAX_SILENT_TIME_ENV: &str = "max_silent_time";
+
+/// how long a builder can run, `None` for no limit
+#[derive(Clone, Copy)]
+struct Limits {
+    timeout: Option<Duration>,
+    max_silent_time: Option<Duration>,
+}
+
+impl Limits {
+    fn new(drv: &StoreDrv) -> Result<Self> {
+        let limit = |key: &str, default: u64| -> Result<Option<Duration>> {
+            let secs = match drv.envs.get(key) {
+                Some(v) => match v.parse() {
+                    Ok(secs) => secs,
+                    Err(_) => bail!("{key} must be a number of seconds, got {v}"),
+                },
+                None => default,
+            };
+            Ok((secs != 0).then(|| Duration::from_secs(secs)))
+        };
+        Ok(Self {
+            timeout: limit(TIMEOUT_ENV, CONFIG.timeout)?,
+            max_silent_time: limit(MAX_SILENT_TIME_ENV, CONFIG.max_silent_time)?,
+        })
     }
 }
 
 // TODO: maybe replace envs with Cows since many of them are &str
 // this reduces the number of allocations by a lot
-fn builder_envs<S>(drv: &StoreDrv) -> HashMap<String, String>
+fn builder_envs<S>(
+    drv: &StoreDrv,
+    build_dir: &str,
+    impure_envs: &HashMap<String, String>,
+    cores: usize,
+) -> HashMap<String, String>
 where
     S: Store,
 {
-    let mut envs = HashMap::new();
+    let mut envs = impure_envs.clone();
     envs.insert("PATH".to_string(), "/path-not-set".to_string());
     envs.insert("HOME".to_string(), "/homeless-shelter".to_string());
     envs.insert("OXIDE_STORE".to_string(), S::store_dir());
+    envs.insert("OXIDE_BUILD_CORES".to_string(), cores.to_string());
     // store derivation envs
     envs.extend(drv.envs.iter().map(|(k, v)| (k.clone(), v.clone())));
 
-    envs.insert("TMPDIR".to_string(), SANDBOX_BUILD_DIR.to_string());
-    envs.insert("TEMPDIR".to_string(), SANDBOX_BUILD_DIR.to_string());
-    envs.insert("TMP".to_string(), SANDBOX_BUILD_DIR.to_string());
-    envs.insert("TEMP".to_string(), SANDBOX_BUILD_DIR.to_string());
+    envs.insert("TMPDIR".to_string(), build_dir.to_string());
+    envs.insert("TEMPDIR".to_string(), build_dir.to_string());
+    envs.insert("TMP".to_string(), build_dir.to_string());
+    envs.insert("TEMP".to_string(), build_dir.to_string());
 
     envs.insert("TERM".to_string(), "xterm-256color".to_string());
     envs
 }
 
-async fn prepare_build<S>(drv: &StoreDrv) -> Result<()>
+async fn prepare_build<S>(
+    drv_path: &StorePath,
+    drv: &StoreDrv,
+    outputs: &HashMap<Out, StorePath>,
+    inputs: &BTreeSet<StorePath>,
+    impure_envs: &HashMap<String, String>,
+    opts: &BuildOpts,
+) -> Result<PathBuf>
 where
     S: Store,
 {
+    let build_user = BuildUser::acquire().await?;
+    // the root of the sandbox
     let top_tmp_dir = tempdir_in(S::store_dir()).await?;
     // skip the slash :)
     let tmp_dir = top_tmp_dir.join(&SANDBOX_BUILD_DIR[1..]);
-    let envs = builder_envs::<S>(drv);
-    prepare_sandbox(tmp_dir)?;
+    // held until the directory is deleted so that `clean-temp` leaves it alone
+    let tmp_lock = PathLock::lock_async(add_lock_ext(&top_tmp_dir), LockMode::Write).await?;
+    let res = async {
+        fs::create_dir(&tmp_dir).await?;
+        let sandbox = if use_sandbox(build_user.as_ref())? {
+            let (uid, gid) = match build_user {
+                Some(ref user) => (user.uid, user.gid),
+                None => unsafe { (libc::geteuid(), libc::getegid()) },
+            };
+            let inputs = inputs.iter().map(S::store_path).collect::<Vec<_>>();
+            // fixed-output derivations are checked against their hash so they can use the network
+            let network = drv.fixed_hash.is_some();
+            Some(Sandbox::new(&top_tmp_dir, &inputs, uid, gid, network).await?)
+        } else {
+            None
+        };
+        if let Some(ref user) = build_user {
+            chown_path(&top_tmp_dir, user.uid, user.gid).await?;
+        }
+        let build_dir = if sandbox.is_some() {
+            SANDBOX_BUILD_DIR.to_string()
+        } else {
+            tmp_dir.to_string_lossy().into_owned()
+        };
+        let envs = builder_envs::<S>(drv, &build_dir, impure_envs, build_cores(opts));
+        let limits = Limits::new(drv)?;
+        let filter = if CONFIG.filter_syscalls {
+            Some(SyscallFilter::new()?)
+        } else {
+            None
+        };
+
+        let exec = Exec::new(drv, &envs)?;
+        let c_tmp_dir = CString::new(tmp_dir.as_os_str().as_bytes())?;
+        let child = Child {
+            exec: &exec,
+            tmp_dir: &c_tmp_dir,
+            build_user: build_user.as_ref(),
+            sandbox: sandbox.as_ref(),
+            filter: filter.as_ref(),
+            interactive: false,
+        };
+        let log = BuildLog::create(drv_path).await?;
+        let res = unsafe { run_process(&child, limits, log).await };
+        if opts.debug_shell
+            && let Ok((Some(ref failure), _)) = res
+        {
+            debug_shell::<S>(drv_path, failure, &envs, &child).await;
+        }
+        if let Some(ref user) = build_user {
+            // nothing running as the build user can change the outputs after this
+            user.kill_processes()?;
+        }
+        if let Some(sandbox) = sandbox {
+            sandbox
+                .take_outputs(outputs.values().map(S::store_path))
+                .await?;
+        }
+        if let Some(user) = build_user {
+            reclaim_outputs::<S>(outputs).await?;
+            user.release();
+        }
+        let (failure, log) = res?;
+        let (log, tail) = log.finish().await?;
+        if let Some(failure) = failure {
+            return Err(BuildError {
+                drv: S::store_path(drv_path),
+                failure,
+                log: Some(log),
+                tail,
+                kept: Vec::new(),
+            }
+            .into());
+        }
+        Ok::<_, anyhow::Error>(log)
+    }
+    .await;
 
-    unsafe { run_process(drv, &envs) }
+    match res {
+        Err(mut e) if opts.keep_failed => {
+            if let Some(err) = e.downcast_mut::<BuildError>() {
+                err.kept.push(tmp_dir);
+            } else {
+                warn!("keeping build directory {}", tmp_dir.display());
+            }
+            Err(e)
+        }
+        res => {
+            if let Err(e) = remove_path(&top_tmp_dir).await {
+                warn!("could not remove {}: {e}", top_tmp_dir.display());
+            }
+            tmp_lock.unlock();
+            res
+        }
+    }
 }
 
 fn strings_to_charptr(strs: Vec<String>) -> Result<(Vec<CString>, Vec<*const libc::c_char>)> {
@@ -86,53 +284,447 @@ fn strings_to_charptr(strs: Vec<String>) -> Result<(Vec<CString>, Vec<*const lib
     Ok((cstrings, charptr))
 }
 
-fn run_child(drv: &StoreDrv, envs: &HashMap<String, String>) -> Result<()> {
-    let mut args = Vec::new();
-    args.push(drv.builder.to_string());
-    for arg in &drv.args {
-        args.push(arg.clone());
+/// The builder with its arguments and environment
+///
+/// made before forking so the child does not have to allocate
+struct Exec {
+    prog: CString,
+    // do not remove _args and _envs otherwise they might get dropped
+    // and the pointers will point to dirty memory
+    _args: Vec<CString>,
+    args: Vec<*const libc::c_char>,
+    _envs: Vec<CString>,
+    envs: Vec<*const libc::c_char>,
+    /// reported if `prog` cannot be executed
+    error: String,
+}
+
+impl Exec {
+    fn new(drv: &StoreDrv, envs: &HashMap<String, String>) -> Result<Self> {
+        let mut args = Vec::new();
+        args.push(drv.builder.clone());
+        for arg in &drv.args {
+            args.push(arg.clone());
+        }
+        Self::with_args(&drv.builder, args, envs)
     }
-    let mut env_strs = Vec::new();
-    for (k, v) in envs {
-        env_strs.push(format!("{k}={v}"));
+
+    /// `debug_shell` of the config with the environment of the builder
+    fn shell(envs: &HashMap<String, String>) -> Result<Self> {
+        let args = vec![CONFIG.debug_shell.clone()];
+        Self::with_args(&CONFIG.debug_shell, args, envs)
+    }
+
+    fn with_args(prog: &str, args: Vec<String>, envs: &HashMap<String, String>) -> Result<Self> {
+        let mut env_strs = Vec::new();
+        for (k, v) in envs {
+            env_strs.push(format!("{k}={v}"));
+        }
+        let (arg_strs, args) = strings_to_charptr(args)?;
+        let (env_strs, envs) = strings_to_charptr(env_strs)?;
+        Ok(Self {
+            prog: CString::new(prog)?,
+            error: format!("could not execute {prog}"),
+            _args: arg_strs,
+            args,
+            _envs: env_strs,
+            envs,
+        })
     }
-    exec_builder(&drv.builder, args, env_strs)?;
-    Ok(())
 }
 
-fn exec_builder(builder: &str, args: Vec<String>, envs: Vec<String>) -> Result<()> {
-    // do not remove _args and _envs otherwise they might get dropped
-    // and the pointers will point to dirty memory
-    let (_args, args) = strings_to_charptr(args)?;
-    let (_envs, envs) = strings_to_charptr(envs)?;
-    let prog = CString::new(builder)?;
-    let prog = prog.as_ptr().cast::<libc::c_char>();
+/// Everything the forked process needs to start the builder
+struct Child<'a> {
+    exec: &'a Exec,
+    tmp_dir: &'a CStr,
+    build_user: Option<&'a BuildUser>,
+    sandbox: Option<&'a Sandbox>,
+    filter: Option<&'a SyscallFilter>,
+    /// a debug shell attached to the terminal
+    interactive: bool,
+}
+
+/// only returns if the builder could not be started,
+/// it runs after fork so it must not allocate
+fn run_child(child: &Child) {
+    let Child {
+        exec,
+        tmp_dir,
+        build_user,
+        sandbox,
+        filter,
+        interactive,
+    } = *child;
     unsafe {
-        let code = libc::execve(prog, args.as_ptr(), envs.as_ptr());
-        if code == -1 {
-            libc::exit(errno());
+        let parent = libc::getppid();
+        if let Some(user) = build_user {
+            if !user.switch_to() {
+                ChildError::last("could not switch to the build user").report();
+                return;
+            }
+            die_with_parent(parent);
+        }
+        if let Some(sandbox) = sandbox {
+            if let Err(e) = sandbox.enter(interactive) {
+                e.report();
+                return;
+            }
+        } else if libc::chdir(tmp_dir.as_ptr()) != 0 {
+            ChildError::last("could not enter the build dir").report();
+            return;
+        }
+        if let Some(filter) = filter
+            && let Err(e) = filter.install()
+        {
+            e.report();
+            return;
         }
+        libc::execve(exec.prog.as_ptr(), exec.args.as_ptr(), exec.envs.as_ptr());
+        ChildError::last(&exec.error).report();
     }
-    Ok(())
 }
 
-unsafe fn run_process(drv: &StoreDrv, envs: &HashMap<String, String>) -> Result<()> {
+/// run the builder writing its output to `log`, `None` if it succeeded
+async unsafe fn run_process(
+    child: &Child<'_>,
+    limits: Limits,
+    mut log: BuildLog,
+) -> Result<(Option<BuildFailure>, BuildLog)> {
+    let mut fds = [0 as libc::c_int; 2];
+    if unsafe { libc::pipe2(fds.as_mut_ptr(), libc::O_CLOEXEC) } != 0 {
+        bail!("could not create a pipe: {}", io::Error::last_os_error());
+    }
+    let [read_fd, write_fd] = fds;
+    let parent = unsafe { libc::getpid() };
     let pid = unsafe { libc::fork() };
     if pid == 0 {
-        run_child(drv, envs)
+        unsafe {
+            // the builder and everything it starts can be killed together
+            libc::setpgid(0, 0);
+            die_with_parent(parent);
+            if libc::dup2(write_fd, libc::STDOUT_FILENO) == -1
+                || libc::dup2(write_fd, libc::STDERR_FILENO) == -1
+            {
+                libc::_exit(1);
+            }
+        }
+        run_child(child);
+        unsafe { libc::_exit(1) }
     } else if pid == -1 {
+        unsafe {
+            libc::close(read_fd);
+            libc::close(write_fd);
+        }
         bail!("unable to fork process");
     } else {
-        let mut status = 0 as libc::c_int;
         unsafe {
-            libc::waitpid(pid, &mut status, 0);
-            let exit_status = libc::WEXITSTATUS(status);
-            if exit_status != 0 {
-                let exit_str = libc::strerror(exit_status);
-                let exit_string = CStr::from_ptr(exit_str);
-                warn!("builder {}: {}", drv.builder, exit_string.to_string_lossy());
+            // also in the parent so the group exists before it is killed
+            libc::setpgid(pid, pid);
+            libc::close(write_fd);
+        }
+        // other builds can run while this one is watched
+        let guard = KillOnDrop(pid);
+        let (res, log) = task::spawn_blocking(move || {
+            let res = wait_builder(pid, read_fd, limits, &mut log);
+            (res, log)
+        })
+        .await?;
+        mem::forget(guard);
+        Ok((res?, log))
+    }
+}
+
+/// start `debug_shell` of the config where the builder of `child` failed,
+/// its errors are only logged since the build still has to be cleaned up
+async fn debug_shell<S>(
+    drv_path: &StorePath,
+    failure: &BuildFailure,
+    envs: &HashMap<String, String>,
+    child: &Child<'_>,
+) where
+    S: Store,
+{
+    eprintln!(
+        "builder for {} {failure}, starting {} in its environment, \
+         the build directory is cleaned up when it exits",
+        S::store_path(drv_path),
+        CONFIG.debug_shell
+    );
+    let res = async {
+        let shell = Exec::shell(envs)?;
+        let child = Child {
+            exec: &shell,
+            interactive: true,
+            ..*child
+        };
+        unsafe { run_shell(&child).await }
+    }
+    .await;
+    if let Err(e) = res {
+        warn!("could not run the debug shell: {e:#}");
+    }
+}
+
+/// run the shell of `child` attached to the terminal of this process and wait for it to exit
+async unsafe fn run_shell(child: &Child<'_>) -> Result<()> {
+    // failed builds running at the same time take turns with the terminal
+    static TERMINAL: Mutex<()> = Mutex::const_new(());
+    let _terminal = TERMINAL.lock().await;
+    let parent = unsafe { libc::getpid() };
+    // like system(3), ctrl-c is for the shell and not for this process,
+    // ignored before the fork so that no signal can reach this process in between
+    let (int, quit, foreground) = unsafe {
+        (
+            libc::signal(libc::SIGINT, libc::SIG_IGN),
+            libc::signal(libc::SIGQUIT, libc::SIG_IGN),
+            libc::tcgetpgrp(libc::STDIN_FILENO),
+        )
+    };
+    let restore = move || unsafe {
+        libc::signal(libc::SIGINT, int);
+        libc::signal(libc::SIGQUIT, quit);
+    };
+    let pid = unsafe { libc::fork() };
+    if pid == 0 {
+        // ignored signals stay ignored across exec
+        restore();
+        die_with_parent(parent);
+        run_child(child);
+        unsafe { libc::_exit(1) }
+    } else if pid == -1 {
+        restore();
+        bail!("unable to fork process");
+    }
+    task::spawn_blocking(move || unsafe {
+        let mut status = 0 as libc::c_int;
+        while libc::waitpid(pid, &raw mut status, 0) == -1 && errno() == libc::EINTR {}
+        if foreground != -1 {
+            // a shell in the sandbox cannot see this process group to give the terminal back
+            let ttou = libc::signal(libc::SIGTTOU, libc::SIG_IGN);
+            libc::tcsetpgrp(libc::STDIN_FILENO, foreground);
+            libc::signal(libc::SIGTTOU, ttou);
+        }
+        restore();
+    })
+    .await?;
+    Ok(())
+}
+
+/// Kills the builder and everything it started if the build is cancelled
+struct KillOnDrop(libc::pid_t);
+
+impl Drop for KillOnDrop {
+    fn drop(&mut self) {
+        unsafe { libc::kill(-self.0, libc::SIGKILL) };
+    }
+}
+
+/// wait for the builder to exit, `None` if it succeeded
+fn wait_builder(
+    pid: libc::pid_t,
+    output: libc::c_int,
+    limits: Limits,
+    log: &mut BuildLog,
+) -> Result<Option<BuildFailure>> {
+    let res = watch_builder(pid, output, limits, log);
+    let mut status = 0 as libc::c_int;
+    unsafe {
+        // kill what the builder left behind
+        libc::kill(-pid, libc::SIGKILL);
+        libc::waitpid(pid, &raw mut status, 0);
+    }
+    let drained = drain_output(output, log);
+    unsafe { libc::close(output) };
+    let timeout = res?;
+    drained?;
+    let failure = if let Some(timeout) = timeout {
+        Some(BuildFailure::Timeout(timeout))
+    } else if libc::WIFSIGNALED(status) {
+        Some(BuildFailure::Signal(libc::WTERMSIG(status)))
+    } else if libc::WEXITSTATUS(status) != 0 {
+        Some(BuildFailure::Exit(libc::WEXITSTATUS(status)))
+    } else {
+        None
+    };
+    Ok(failure)
+}
+
+/// forward the output of the builder until it exits or hits one of the limits
+fn watch_builder(
+    pid: libc::pid_t,
+    output: libc::c_int,
+    limits: Limits,
+    log: &mut BuildLog,
+) -> Result<Option<BuildTimeout>> {
+    let pidfd = unsafe { libc::syscall(libc::SYS_pidfd_open, pid, 0) };
+    if pidfd == -1 {
+        bail!(
+            "could not watch the builder: {}",
+            io::Error::last_os_error()
+        );
+    }
+    #[allow(clippy::cast_possible_truncation)]
+    let pidfd = pidfd as libc::c_int;
+    let res = watch_pidfd(pidfd, output, limits, log);
+    unsafe { libc::close(pidfd) };
+    res
+}
+
+fn watch_pidfd(
+    pidfd: libc::c_int,
+    output: libc::c_int,
+    limits: Limits,
+    log: &mut BuildLog,
+) -> Result<Option<BuildTimeout>> {
+    let start = Instant::now();
+    let mut last_output = start;
+    let mut fds = [
+        libc::pollfd {
+            fd: output,
+            events: libc::POLLIN,
+            revents: 0,
+        },
+        libc::pollfd {
+            fd: pidfd,
+            events: libc::POLLIN,
+            revents: 0,
+        },
+    ];
+    loop {
+        let mut wait = None::<Duration>;
+        if let Some(timeout) = limits.timeout {
+            let left = timeout.saturating_sub(start.elapsed());
+            if left.is_zero() {
+                return Ok(Some(BuildTimeout::Timeout(timeout.as_secs())));
+            }
+            wait = Some(wait.map_or(left, |w| w.min(left)));
+        }
+        if let Some(max_silent_time) = limits.max_silent_time {
+            let left = max_silent_time.saturating_sub(last_output.elapsed());
+            if left.is_zero() {
+                return Ok(Some(BuildTimeout::MaxSilentTime(max_silent_time.as_secs())));
+            }
+            wait = Some(wait.map_or(left, |w| w.min(left)));
+        }
+        // round up so the limit has passed when poll returns
+        let wait = wait.map_or(-1, |w| {
+            libc::c_int::try_from(w.as_millis() + 1).unwrap_or(libc::c_int::MAX)
+        });
+        if unsafe { libc::poll(fds.as_mut_ptr(), 2, wait) } == -1 {
+            if errno() == libc::EINTR {
+                continue;
+            }
+            bail!(
+                "could not wait for the builder: {}",
+                io::Error::last_os_error()
+            );
+        }
+        if fds[0].revents != 0 {
+            if forward_output(output, log)? {
+                last_output = Instant::now();
+            } else {
+                // poll ignores negative fds
+                fds[0].fd = -1;
             }
         }
+        if fds[1].revents != 0 {
+            return Ok(None);
+        }
+    }
+}
+
+/// copy a chunk of the output of the builder to its log, `false` once it is closed
+fn forward_output(output: libc::c_int, log: &mut BuildLog) -> Result<bool> {
+    let mut buf = [0u8; 4096];
+    loop {
+        let n = unsafe { libc::read(output, buf.as_mut_ptr().cast(), buf.len()) };
+        if n == -1 {
+            if errno() == libc::EINTR {
+                continue;
+            }
+            bail!(
+                "could not read the builder output: {}",
+                io::Error::last_os_error()
+            );
+        }
+        if n == 0 {
+            return Ok(false);
+        }
+        #[allow(clippy::cast_sign_loss)]
+        log.write(&buf[..n as usize])?;
+        return Ok(true);
+    }
+}
+
+/// forward what was written before the builder was killed
+fn drain_output(output: libc::c_int, log: &mut BuildLog) -> Result<()> {
+    loop {
+        let mut fd = libc::pollfd {
+            fd: output,
+            events: libc::POLLIN,
+            revents: 0,
+        };
+        if unsafe { libc::poll(&raw mut fd, 1, 0) } < 1 || !forward_output(output, log)? {
+            return Ok(());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hash::utils::random_path;
+
+    /// run `script` with `sh` as a builder with `limits`
+    async fn run_script(script: &str, limits: Limits) -> Result<Option<BuildFailure>> {
+        let dir = tempdir_in(std::env::temp_dir()).await?;
+        let args = vec!["sh".to_string(), "-c".to_string(), script.to_string()];
+        let exec = Exec::with_args("/bin/sh", args, &HashMap::new())?;
+        let c_dir = CString::new(dir.as_os_str().as_bytes())?;
+        let child = Child {
+            exec: &exec,
+            tmp_dir: &c_dir,
+            build_user: None,
+            sandbox: None,
+            filter: None,
+            interactive: false,
+        };
+        let log = BuildLog::to_file(&random_path("drv"), std::fs::File::create(dir.join("log"))?);
+        let (failure, _) = unsafe { run_process(&child, limits, log).await? };
+        remove_path(&dir).await?;
+        Ok(failure)
+    }
+
+    fn limits(timeout: u64, max_silent_time: u64) -> Limits {
+        let secs = |secs| (secs != 0).then(|| Duration::from_secs(secs));
+        Limits {
+            timeout: secs(timeout),
+            max_silent_time: secs(max_silent_time),
+        }
+    }
+
+    #[tokio::test]
+    async fn builders_are_killed_after_the_timeout() -> Result<()> {
+        // writing output does not help against the timeout
+        let failure = run_script("while true; do echo .; sleep 0.1; done", limits(1, 2)).await?;
+        assert!(matches!(
+            failure,
+            Some(BuildFailure::Timeout(BuildTimeout::Timeout(1)))
+        ));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn silent_builders_are_killed_before_the_timeout() -> Result<()> {
+        let failure = run_script("sleep 10", limits(5, 1)).await?;
+        assert!(matches!(
+            failure,
+            Some(BuildFailure::Timeout(BuildTimeout::MaxSilentTime(1)))
+        ));
+
+        // output resets the time of silence
+        let script = "for i in 1 2 3 4 5 6; do echo $i; sleep 0.3; done";
+        assert!(run_script(script, limits(5, 1)).await?.is_none());
         Ok(())
     }
 }
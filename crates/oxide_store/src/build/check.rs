@@ -0,0 +1,435 @@
+use super::{
+    BuildOpts, Built, CheckError, CheckMismatch, FileDiff, build, clean_outputs, lock_drv,
+    lock_outputs, prepare_drv, run_drv, trusted_outs,
+};
+use crate::{
+    api::{CONFIG, Store},
+    archive::{read_archive, write_archive},
+    hash::{hash_mod_rewrites, rewrite_self_hash, utils::make_path},
+    types::CheckResult,
+    utils::remove_path,
+};
+use anyhow::{Result, bail};
+use futures_util::future::join;
+use log::{info, warn};
+use oxide_core::{drv::StoreDrv, hash::HashAlgo, store::StorePath, types::Out};
+use std::{
+    collections::{BTreeSet, HashMap},
+    ffi::OsString,
+    os::unix::{ffi::OsStrExt, fs::PermissionsExt},
+    path::{Path, PathBuf},
+    time::{SystemTime, UNIX_EPOCH},
+};
+use tokio::{
+    fs::{self, File},
+    io::{self, AsyncBufReadExt, AsyncWriteExt, BufReader},
+};
+
+/// size of the pipe between the archive writer and reader of [`copy_path`]
+const COPY_PIPE_SIZE: usize = 64 * 1024;
+
+/// build `drvs` again and compare the outputs with their trusted outputs,
+/// the results are recorded in the store and the outputs that differ are kept
+pub(super) async fn check<S>(store: &S, drvs: &[StorePath], opts: &BuildOpts) -> Result<Built>
+where
+    S: Store,
+{
+    let mut read = Vec::with_capacity(drvs.len());
+    let mut inputs = BTreeSet::new();
+    for p in drvs {
+        let drv = store.read_drv(p).await?;
+        inputs.extend(drv.input_drvs.keys().cloned());
+        read.push(drv);
+    }
+    // the inputs are built or substituted like for any other build
+    let inputs = inputs.into_iter().collect::<Vec<_>>();
+    let input_opts = BuildOpts {
+        check: false,
+        ..opts.clone()
+    };
+    Box::pin(build(store, &inputs, &input_opts)).await?;
+
+    let rounds = opts.rounds.max(1);
+    let mut error = CheckError::default();
+    let mut checked = Vec::with_capacity(drvs.len());
+    for (p, drv) in drvs.iter().zip(read) {
+        let Some(trusted) = trusted_outs(store, &drv.eq_classes).await? else {
+            bail!(
+                "{} was never built, there is nothing to compare with",
+                S::store_path(p)
+            );
+        };
+        // the rebuilt outputs are compared with the content of the trusted ones
+        let paths = trusted.values().cloned().collect::<Vec<_>>();
+        let valid = store.query_valid_paths(&paths).await?;
+        if let Some(missing) = paths.iter().find(|path| !valid.contains(*path)) {
+            bail!(
+                "the trusted output {} of {} is not in the store, build or substitute it before checking",
+                S::store_path(missing),
+                S::store_path(p)
+            );
+        }
+        let lock = lock_drv::<S>(p).await?;
+        let (drv, inputs, outputs) = prepare_drv(store, drv, true).await?;
+        let mut mismatches = HashMap::<Out, u64>::new();
+        for round in 1..=rounds {
+            info!("checking: {p} ({round}/{rounds})");
+            let out_locks = lock_outputs::<S>(&outputs).await?;
+            let res = async {
+                run_drv(store, p, &drv, &inputs, &outputs, opts).await?;
+                compare::<S>(p, &drv, &outputs, &trusted, round).await
+            }
+            .await;
+            let res = clean_outputs(store, &outputs, res, opts).await;
+            drop(out_locks);
+            for m in res? {
+                *mismatches.entry(m.out.clone()).or_default() += 1;
+                error.mismatches.push(m);
+            }
+        }
+        lock.unlock();
+
+        let time = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+        for (out, path) in &trusted {
+            store
+                .record_check(&CheckResult {
+                    drv: p.clone(),
+                    out: out.clone(),
+                    path: path.clone(),
+                    rounds: rounds as u64,
+                    mismatches: mismatches.get(out).copied().unwrap_or_default(),
+                    time,
+                })
+                .await?;
+        }
+        checked.push(trusted);
+    }
+    if !error.mismatches.is_empty() {
+        return Err(error.into());
+    }
+    Ok(Built {
+        outputs: checked,
+        built: Vec::new(),
+    })
+}
+
+/// where the rebuilt outputs that differ are kept, it is outside of the store
+/// so that `clean-temp` leaves them alone
+fn checks_dir() -> PathBuf {
+    Path::new(&CONFIG.state_dir).join("checks")
+}
+
+/// move `src` to `dst`, it is copied if they are on different file systems
+async fn move_path(src: &Path, dst: &Path) -> Result<()> {
+    match fs::rename(src, dst).await {
+        Err(e) if e.raw_os_error() == Some(libc::EXDEV) => {}
+        res => return Ok(res?),
+    }
+    if let Err(e) = copy_path(src, dst).await {
+        _ = remove_path(dst).await;
+        return Err(e);
+    }
+    remove_path(src).await
+}
+
+/// copy `src` to `dst` through an archive, which keeps everything an output can contain
+async fn copy_path(src: &Path, dst: &Path) -> Result<()> {
+    let (mut reader, mut writer) = io::duplex(COPY_PIPE_SIZE);
+    // the writer is dropped once written so that the reader does not wait for more
+    let written = async move {
+        write_archive(src, &mut writer).await?;
+        writer.shutdown().await?;
+        Ok::<_, anyhow::Error>(())
+    };
+    let (written, read) = join(
+        written,
+        read_archive(&mut reader, Some(dst), HashAlgo::Sha256, None),
+    )
+    .await;
+    written?;
+    read?;
+    Ok(())
+}
+
+/// compare the outputs built in `round` with the trusted ones,
+/// the ones that differ are moved to [`checks_dir`] as `<trusted>.check-<round>`
+async fn compare<S>(
+    p: &StorePath,
+    drv: &StoreDrv,
+    outputs: &HashMap<Out, StorePath>,
+    trusted: &HashMap<Out, StorePath>,
+    round: usize,
+) -> Result<Vec<CheckMismatch>>
+where
+    S: Store,
+{
+    // the output of a fixed-output derivation was already checked against its hash
+    if drv.fixed_hash.is_some() {
+        return Ok(Vec::new());
+    }
+    let mut mismatches = Vec::new();
+    for (out, eq_class) in &drv.eq_classes {
+        let self_hash = &outputs[out];
+        let tmp_path = S::store_path(self_hash);
+        // the path `add_to_store` would give to the rebuilt output
+        let hash = hash_mod_rewrites(
+            &tmp_path,
+            HashAlgo::Sha512,
+            &HashMap::new(),
+            Some(self_hash),
+        )
+        .await?;
+        let trusted = &trusted[out];
+        if make_path(&hash, eq_class.name_part()) == *trusted {
+            continue;
+        }
+
+        // references to itself look like the ones of the trusted output
+        // so that only the real differences show up
+        let rewritten = match rewrite_self_hash(&tmp_path, self_hash, trusted).await {
+            Ok(()) => true,
+            Err(e) => {
+                warn!("could not rewrite the self references of {tmp_path}: {e}");
+                false
+            }
+        };
+        let path = S::store_path(trusted);
+        let checks_dir = checks_dir();
+        fs::create_dir_all(&checks_dir).await?;
+        let rebuilt = checks_dir.join(format!("{trusted}.check-{round}"));
+        if fs::symlink_metadata(&rebuilt).await.is_ok() {
+            remove_path(&rebuilt).await?;
+        }
+        if let Err(e) = move_path(Path::new(&tmp_path), &rebuilt).await {
+            bail!("could not move {tmp_path} to {}: {e:#}", rebuilt.display());
+        }
+        let mut files = Vec::new();
+        diff_paths(Path::new(&path), &rebuilt, PathBuf::new(), &mut files).await?;
+        mismatches.push(CheckMismatch {
+            drv: S::store_path(p),
+            out: out.clone(),
+            path,
+            rebuilt,
+            rewritten,
+            files,
+        });
+    }
+    Ok(mismatches)
+}
+
+/// add how `rebuilt` differs from `trusted` to `diffs`, `file` is where they are in the output
+async fn diff_paths(
+    trusted: &Path,
+    rebuilt: &Path,
+    file: PathBuf,
+    diffs: &mut Vec<FileDiff>,
+) -> Result<()> {
+    let a = fs::symlink_metadata(trusted).await?;
+    let b = fs::symlink_metadata(rebuilt).await?;
+    if a.file_type() != b.file_type() {
+        diffs.push(FileDiff::Type(file));
+    } else if a.is_dir() {
+        let a_names = dir_names(trusted).await?;
+        let b_names = dir_names(rebuilt).await?;
+        for name in a_names.union(&b_names) {
+            let entry = file.join(name);
+            match (a_names.contains(name), b_names.contains(name)) {
+                (true, false) => diffs.push(FileDiff::Missing(entry)),
+                (false, true) => diffs.push(FileDiff::Added(entry)),
+                _ => {
+                    Box::pin(diff_paths(
+                        &trusted.join(name),
+                        &rebuilt.join(name),
+                        entry,
+                        diffs,
+                    ))
+                    .await?;
+                }
+            }
+        }
+    } else if a.is_symlink() {
+        let a_target = fs::read_link(trusted).await?;
+        let b_target = fs::read_link(rebuilt).await?;
+        let (a_target, b_target) = (
+            a_target.as_os_str().as_bytes(),
+            b_target.as_os_str().as_bytes(),
+        );
+        if let Some(offset) = first_difference(a_target, b_target) {
+            diffs.push(FileDiff::Content {
+                file,
+                trusted_size: a_target.len() as u64,
+                rebuilt_size: b_target.len() as u64,
+                offset: offset as u64,
+            });
+        }
+    } else {
+        let executable = |mode: u32| mode & 0o111 != 0;
+        if executable(a.permissions().mode()) != executable(b.permissions().mode()) {
+            diffs.push(FileDiff::Mode(file.clone()));
+        }
+        if let Some(offset) = first_file_difference(trusted, rebuilt).await? {
+            diffs.push(FileDiff::Content {
+                file,
+                trusted_size: a.len(),
+                rebuilt_size: b.len(),
+                offset,
+            });
+        }
+    }
+    Ok(())
+}
+
+async fn dir_names(dir: &Path) -> Result<BTreeSet<OsString>> {
+    let mut names = BTreeSet::new();
+    let mut entries = fs::read_dir(dir).await?;
+    while let Some(entry) = entries.next_entry().await? {
+        names.insert(entry.file_name());
+    }
+    Ok(names)
+}
+
+/// the first offset where `a` and `b` differ, `None` if they are the same
+fn first_difference(a: &[u8], b: &[u8]) -> Option<usize> {
+    a.iter()
+        .zip(b)
+        .position(|(x, y)| x != y)
+        .or_else(|| (a.len() != b.len()).then(|| a.len().min(b.len())))
+}
+
+/// like [`first_difference`] with the content of two files, read a chunk at a time
+async fn first_file_difference(a: &Path, b: &Path) -> Result<Option<u64>> {
+    let mut a = BufReader::new(File::open(a).await?);
+    let mut b = BufReader::new(File::open(b).await?);
+    let mut offset = 0;
+    loop {
+        let a_buf = a.fill_buf().await?;
+        let b_buf = b.fill_buf().await?;
+        let n = a_buf.len().min(b_buf.len());
+        if n == 0 {
+            return Ok((a_buf.len() != b_buf.len()).then_some(offset));
+        }
+        if let Some(i) = first_difference(&a_buf[..n], &b_buf[..n]) {
+            return Ok(Some(offset + i as u64));
+        }
+        a.consume(n);
+        b.consume(n);
+        offset += n as u64;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::tempfile::tempdir_in;
+    use std::fs::Permissions;
+
+    #[test]
+    fn first_difference_of_bytes() {
+        assert_eq!(first_difference(b"abc", b"abc"), None);
+        assert_eq!(first_difference(b"abc", b"abd"), Some(2));
+        assert_eq!(first_difference(b"xbc", b"abc"), Some(0));
+        // one is a prefix of the other
+        assert_eq!(first_difference(b"ab", b"abc"), Some(2));
+        assert_eq!(first_difference(b"abc", b""), Some(0));
+        assert_eq!(first_difference(b"", b""), None);
+    }
+
+    #[tokio::test]
+    async fn first_file_difference_across_chunks() -> Result<()> {
+        let dir = tempdir_in(std::env::temp_dir()).await?;
+        let (a, b) = (dir.join("a"), dir.join("b"));
+        // bigger than the buffer of the readers
+        let content = (0..20_000u32).map(|i| (i % 251) as u8).collect::<Vec<_>>();
+        fs::write(&a, &content).await?;
+        fs::write(&b, &content).await?;
+        assert_eq!(first_file_difference(&a, &b).await?, None);
+
+        let mut changed = content.clone();
+        changed[12_345] ^= 1;
+        fs::write(&b, &changed).await?;
+        assert_eq!(first_file_difference(&a, &b).await?, Some(12_345));
+
+        fs::write(&b, &content[..10_000]).await?;
+        assert_eq!(first_file_difference(&a, &b).await?, Some(10_000));
+        assert_eq!(first_file_difference(&b, &a).await?, Some(10_000));
+        remove_path(&dir).await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn diff_paths_finds_every_kind_of_difference() -> Result<()> {
+        let dir = tempdir_in(std::env::temp_dir()).await?;
+        let (trusted, rebuilt) = (dir.join("trusted"), dir.join("rebuilt"));
+        for root in [&trusted, &rebuilt] {
+            fs::create_dir_all(root.join("lib")).await?;
+            fs::write(root.join("same"), "same").await?;
+            fs::write(root.join("bin"), "#!/bin/sh").await?;
+            fs::symlink("same", root.join("link")).await?;
+        }
+        fs::write(trusted.join("lib/a"), "0123456789").await?;
+        fs::write(rebuilt.join("lib/a"), "0123x567").await?;
+        fs::write(trusted.join("old"), "").await?;
+        fs::write(rebuilt.join("new"), "").await?;
+        fs::set_permissions(rebuilt.join("bin"), Permissions::from_mode(0o755)).await?;
+        fs::write(trusted.join("kind"), "").await?;
+        fs::create_dir(rebuilt.join("kind")).await?;
+        fs::remove_file(rebuilt.join("link")).await?;
+        fs::symlink("samf", rebuilt.join("link")).await?;
+
+        let mut diffs = Vec::new();
+        diff_paths(&trusted, &rebuilt, PathBuf::new(), &mut diffs).await?;
+        assert_eq!(
+            diffs,
+            vec![
+                FileDiff::Mode("bin".into()),
+                FileDiff::Type("kind".into()),
+                FileDiff::Content {
+                    file: "lib/a".into(),
+                    trusted_size: 10,
+                    rebuilt_size: 8,
+                    offset: 4,
+                },
+                FileDiff::Content {
+                    file: "link".into(),
+                    trusted_size: 4,
+                    rebuilt_size: 4,
+                    offset: 3,
+                },
+                FileDiff::Added("new".into()),
+                FileDiff::Missing("old".into()),
+            ]
+        );
+
+        // the same tree has no differences
+        diffs.clear();
+        diff_paths(&trusted, &trusted, PathBuf::new(), &mut diffs).await?;
+        assert!(diffs.is_empty(), "{diffs:?}");
+        remove_path(&dir).await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn outputs_are_copied_across_file_systems() -> Result<()> {
+        let dir = tempdir_in(std::env::temp_dir()).await?;
+        let (src, dst) = (dir.join("src"), dir.join("dst"));
+        fs::create_dir_all(src.join("bin")).await?;
+        fs::write(src.join("bin/run"), "#!/bin/sh").await?;
+        fs::set_permissions(src.join("bin/run"), Permissions::from_mode(0o755)).await?;
+        fs::symlink("bin/run", src.join("link")).await?;
+        copy_path(&src, &dst).await?;
+        let mut diffs = Vec::new();
+        diff_paths(&src, &dst, PathBuf::new(), &mut diffs).await?;
+        assert!(diffs.is_empty(), "{diffs:?}");
+
+        // the copy is only used when a rename is not possible
+        let moved = dir.join("moved");
+        move_path(&src, &moved).await?;
+        assert!(fs::symlink_metadata(&src).await.is_err());
+        assert_eq!(
+            fs::read_link(moved.join("link")).await?,
+            Path::new("bin/run")
+        );
+        remove_path(&dir).await?;
+        Ok(())
+    }
+}
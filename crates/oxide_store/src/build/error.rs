@@ -0,0 +1,230 @@
+use std::{
+    ffi::CStr,
+    fmt::{self, Display},
+    path::PathBuf,
+};
+
+/// The builder was killed because it took too long
+#[derive(Debug)]
+pub enum BuildTimeout {
+    /// ran for more than this many seconds
+    Timeout(u64),
+    /// did not write anything for this many seconds
+    MaxSilentTime(u64),
+}
+
+impl Display for BuildTimeout {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BuildTimeout::Timeout(secs) => write!(f, "timed out after {secs} seconds"),
+            BuildTimeout::MaxSilentTime(secs) => {
+                write!(f, "timed out after {secs} seconds of silence")
+            }
+        }
+    }
+}
+
+impl std::error::Error for BuildTimeout {}
+
+/// How a builder failed
+#[derive(Debug)]
+pub enum BuildFailure {
+    /// exited with this code
+    Exit(i32),
+    /// was killed by this signal
+    Signal(i32),
+    Timeout(BuildTimeout),
+}
+
+impl Display for BuildFailure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BuildFailure::Exit(code) => write!(f, "failed with exit code {code}"),
+            BuildFailure::Signal(sig) => {
+                let name = unsafe { CStr::from_ptr(libc::strsignal(*sig)) };
+                write!(f, "was killed by signal {sig} ({})", name.to_string_lossy())
+            }
+            BuildFailure::Timeout(timeout) => timeout.fmt(f),
+        }
+    }
+}
+
+/// A builder that did not succeed
+#[derive(Debug)]
+pub struct BuildError {
+    /// full path of the derivation
+    pub drv: String,
+    pub failure: BuildFailure,
+    /// file with everything the builder wrote
+    pub log: Option<PathBuf>,
+    /// last lines written by the builder
+    pub tail: Vec<String>,
+    /// build directory and outputs left for inspection with `keep_failed`
+    pub kept: Vec<PathBuf>,
+}
+
+impl Display for BuildError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "builder for {} {}", self.drv, self.failure)?;
+        if !self.tail.is_empty() {
+            write!(f, "\nlast {} log lines:", self.tail.len())?;
+            for line in &self.tail {
+                write!(f, "\n> {line}")?;
+            }
+        }
+        if let Some(ref log) = self.log {
+            write!(f, "\nfull log: {}", log.display())?;
+        }
+        for path in &self.kept {
+            write!(f, "\nkept for inspection: {}", path.display())?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for BuildError {}
+
+/// A build with `keep_going` where some derivations failed
+#[derive(Debug, Default)]
+pub struct KeepGoingError {
+    /// full paths of the derivations that were built and their log
+    pub built: Vec<(String, Option<PathBuf>)>,
+    /// derivations that failed, their log and why
+    pub failed: Vec<(String, Option<PathBuf>, String)>,
+    /// derivations that were not built and the failed dependency they need
+    pub skipped: Vec<(String, String)>,
+}
+
+impl Display for KeepGoingError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let total = self.built.len() + self.failed.len() + self.skipped.len();
+        write!(
+            f,
+            "{} of {total} derivations failed to build, {} skipped",
+            self.failed.len(),
+            self.skipped.len()
+        )?;
+        if !self.built.is_empty() {
+            write!(f, "\nbuilt:")?;
+            for (drv, log) in &self.built {
+                write!(f, "\n  {drv}")?;
+                write_log(f, log.as_ref())?;
+            }
+        }
+        write!(f, "\nfailed:")?;
+        for (drv, log, err) in &self.failed {
+            write!(f, "\n  {drv}")?;
+            write_log(f, log.as_ref())?;
+            for line in err.lines() {
+                write!(f, "\n    {line}")?;
+            }
+        }
+        if !self.skipped.is_empty() {
+            write!(f, "\nskipped because a dependency failed:")?;
+            for (drv, dep) in &self.skipped {
+                write!(f, "\n  {drv} (needs {dep})")?;
+            }
+        }
+        Ok(())
+    }
+}
+
+fn write_log(f: &mut fmt::Formatter<'_>, log: Option<&PathBuf>) -> fmt::Result {
+    match log {
+        Some(log) => write!(f, " (log: {})", log.display()),
+        None => write!(f, " (no log)"),
+    }
+}
+
+impl std::error::Error for KeepGoingError {}
+
+/// How a file of a rebuilt output differs from the trusted one,
+/// paths are relative to the root of the output
+#[derive(Debug, PartialEq, Eq)]
+pub enum FileDiff {
+    /// only in the trusted output
+    Missing(PathBuf),
+    /// only in the rebuilt output
+    Added(PathBuf),
+    /// a file, a directory or a symlink in one and something else in the other
+    Type(PathBuf),
+    /// executable in only one of them
+    Mode(PathBuf),
+    /// the content of a file or the target of a symlink is different from `offset` on
+    Content {
+        file: PathBuf,
+        trusted_size: u64,
+        rebuilt_size: u64,
+        offset: u64,
+    },
+}
+
+impl Display for FileDiff {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let show = |file: &PathBuf| {
+            if file.as_os_str().is_empty() {
+                ".".to_string()
+            } else {
+                file.display().to_string()
+            }
+        };
+        match self {
+            FileDiff::Missing(file) => write!(f, "{}: only in the trusted output", show(file)),
+            FileDiff::Added(file) => write!(f, "{}: only in the rebuilt output", show(file)),
+            FileDiff::Type(file) => write!(f, "{}: different file types", show(file)),
+            FileDiff::Mode(file) => write!(f, "{}: executable in only one of them", show(file)),
+            FileDiff::Content {
+                file,
+                trusted_size,
+                rebuilt_size,
+                offset,
+            } => write!(
+                f,
+                "{}: differs from byte {offset}, {trusted_size} bytes in the trusted output \
+                 and {rebuilt_size} in the rebuilt one",
+                show(file)
+            ),
+        }
+    }
+}
+
+/// An output that was different when it was built again
+#[derive(Debug)]
+pub struct CheckMismatch {
+    /// full path of the derivation
+    pub drv: String,
+    pub out: String,
+    /// full path of the trusted output
+    pub path: String,
+    /// the rebuilt output, kept in the `checks` directory of the state dir
+    pub rebuilt: PathBuf,
+    /// the references of `rebuilt` to itself were changed to point to `path`
+    pub rewritten: bool,
+    pub files: Vec<FileDiff>,
+}
+
+/// Derivations built again with `check` whose outputs are not reproducible
+#[derive(Debug, Default)]
+pub struct CheckError {
+    pub mismatches: Vec<CheckMismatch>,
+}
+
+impl Display for CheckError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} rebuilt outputs differ", self.mismatches.len())?;
+        for m in &self.mismatches {
+            write!(f, "\n{}!{} is not reproducible", m.drv, m.out)?;
+            write!(f, "\n  trusted: {}", m.path)?;
+            write!(f, "\n  rebuilt: {}", m.rebuilt.display())?;
+            if m.rewritten {
+                write!(f, " (its references to itself point to the trusted output)")?;
+            }
+            for file in &m.files {
+                write!(f, "\n    {file}")?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for CheckError {}
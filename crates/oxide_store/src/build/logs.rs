@@ -0,0 +1,216 @@
+use crate::api::CONFIG;
+use anyhow::Result;
+use async_compression::tokio::{bufread::ZstdDecoder, write::ZstdEncoder};
+use oxide_core::store::StorePath;
+use std::{
+    collections::VecDeque,
+    io::{self, Write},
+    path::{Path, PathBuf},
+};
+use tokio::{
+    fs::{self, File},
+    io::{self as aio, AsyncWrite, AsyncWriteExt, BufReader},
+};
+
+const LOG_EXT: &str = "log";
+const COMPRESSED_LOG_EXT: &str = "log.zst";
+/// a line of the tail longer than this is cut, builders can write a lot without a newline
+const MAX_LINE_LEN: usize = 4 * 1024;
+
+/// where the output of the last build of `drv` is written,
+/// `<log_dir>/drvs/<first two chars>/<rest of the name>.log`
+pub fn log_path(drv: &StorePath) -> PathBuf {
+    let (prefix, rest) = drv.split_at(2);
+    Path::new(&CONFIG.log_dir)
+        .join("drvs")
+        .join(prefix)
+        .join(format!("{rest}.{LOG_EXT}"))
+}
+
+fn compressed_log_path(drv: &StorePath) -> PathBuf {
+    log_path(drv).with_extension(COMPRESSED_LOG_EXT)
+}
+
+/// the file with the output of the last build of `drv`, compressed or not
+async fn find_log(drv: &StorePath) -> Result<Option<PathBuf>> {
+    let compressed = compressed_log_path(drv);
+    if fs::try_exists(&compressed).await? {
+        return Ok(Some(compressed));
+    }
+    let path = log_path(drv);
+    if fs::try_exists(&path).await? {
+        return Ok(Some(path));
+    }
+    Ok(None)
+}
+
+/// write the output of the last build of `drv` to `writer`, even if it failed,
+/// `false` if it was never built
+pub async fn write_log<W>(drv: &StorePath, writer: &mut W) -> Result<bool>
+where
+    W: AsyncWrite + Unpin,
+{
+    let Some(path) = find_log(drv).await? else {
+        return Ok(false);
+    };
+    let mut reader = BufReader::new(File::open(&path).await?);
+    if path == compressed_log_path(drv) {
+        aio::copy(&mut ZstdDecoder::new(reader), writer).await?;
+    } else {
+        aio::copy_buf(&mut reader, writer).await?;
+    }
+    writer.flush().await?;
+    Ok(true)
+}
+
+/// Where the output of a builder goes
+pub(super) struct BuildLog {
+    drv: StorePath,
+    file: std::fs::File,
+    tail: LogTail,
+    /// also print it, done when info logs are shown
+    print: bool,
+}
+
+impl BuildLog {
+    /// replace the log of the previous build of `drv`
+    pub async fn create(drv: &StorePath) -> Result<Self> {
+        let path = log_path(drv);
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent).await?;
+        }
+        let compressed = compressed_log_path(drv);
+        if fs::try_exists(&compressed).await? {
+            fs::remove_file(&compressed).await?;
+        }
+        let file = File::create(&path).await?.into_std().await;
+        Ok(Self {
+            drv: drv.clone(),
+            file,
+            tail: LogTail::new(CONFIG.log_lines),
+            print: log::log_enabled!(log::Level::Info),
+        })
+    }
+
+    /// a log of `drv` written to `file` instead of the log dir
+    #[cfg(test)]
+    pub fn to_file(drv: &StorePath, file: std::fs::File) -> Self {
+        Self {
+            drv: drv.clone(),
+            file,
+            tail: LogTail::new(CONFIG.log_lines),
+            print: false,
+        }
+    }
+
+    pub fn write(&mut self, buf: &[u8]) -> Result<()> {
+        self.file.write_all(buf)?;
+        if self.print {
+            io::stderr().write_all(buf)?;
+        }
+        self.tail.push(buf);
+        Ok(())
+    }
+
+    /// close the log and compress it if enabled,
+    /// returns where it was written and its last lines
+    pub async fn finish(self) -> Result<(PathBuf, Vec<String>)> {
+        drop(self.file);
+        let path = log_path(&self.drv);
+        let tail = self.tail.into_lines();
+        if !CONFIG.compress_build_logs {
+            return Ok((path, tail));
+        }
+        let compressed = compressed_log_path(&self.drv);
+        let mut reader = File::open(&path).await?;
+        let mut writer = ZstdEncoder::new(File::create(&compressed).await?);
+        tokio::io::copy(&mut reader, &mut writer).await?;
+        writer.shutdown().await?;
+        fs::remove_file(&path).await?;
+        Ok((compressed, tail))
+    }
+}
+
+/// the last lines written by the builder
+struct LogTail {
+    lines: VecDeque<String>,
+    /// a line that has not ended yet
+    partial: Vec<u8>,
+    max: usize,
+}
+
+impl LogTail {
+    fn new(max: usize) -> Self {
+        Self {
+            lines: VecDeque::new(),
+            partial: Vec::new(),
+            max,
+        }
+    }
+
+    fn push(&mut self, buf: &[u8]) {
+        for &b in buf {
+            if b == b'\n' {
+                self.end_line();
+            } else {
+                self.partial.push(b);
+                if self.partial.len() == MAX_LINE_LEN {
+                    self.end_line();
+                }
+            }
+        }
+    }
+
+    fn end_line(&mut self) {
+        let line = String::from_utf8_lossy(&self.partial).into_owned();
+        self.push_line(line);
+        self.partial.clear();
+    }
+
+    fn push_line(&mut self, line: String) {
+        if self.max == 0 {
+            return;
+        }
+        if self.lines.len() == self.max {
+            self.lines.pop_front();
+        }
+        self.lines.push_back(line);
+    }
+
+    fn into_lines(mut self) -> Vec<String> {
+        if !self.partial.is_empty() {
+            self.end_line();
+        }
+        self.lines.into()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tail_keeps_the_last_lines() {
+        let mut tail = LogTail::new(2);
+        tail.push(b"one\ntw");
+        tail.push(b"o\nthree\nfour");
+        assert_eq!(tail.into_lines(), ["three", "four"]);
+
+        let mut tail = LogTail::new(0);
+        tail.push(b"one\n");
+        assert!(tail.into_lines().is_empty());
+    }
+
+    #[test]
+    fn long_lines_are_cut() {
+        let mut tail = LogTail::new(3);
+        tail.push(&vec![b'a'; MAX_LINE_LEN * 2 + 1]);
+        assert!(tail.partial.len() < MAX_LINE_LEN);
+        tail.push(b"\nend\n");
+        let lines = tail.into_lines();
+        assert_eq!(lines.len(), 3);
+        assert_eq!(lines[0], "a".repeat(MAX_LINE_LEN));
+        assert_eq!(lines[1], "a");
+        assert_eq!(lines[2], "end");
+    }
+}
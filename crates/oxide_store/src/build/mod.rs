@@ -1,51 +1,209 @@
 mod builder;
+mod check;
+mod error;
+mod logs;
+mod plan;
+mod schedule;
+mod substitute;
+
+pub use error::*;
+pub use logs::{log_path, write_log};
+pub use plan::{BuildPlan, plan_build};
+pub use substitute::Substitution;
 
 use crate::{
-    api::{EqRefs, Opt, Store},
+    api::{CONFIG, EqRefs, Opt, Store},
     hash::{hash_mod_rewrites, rewrite_str, scan_for_refs, utils::random_path},
+    os::lock::{LockMode, PathLock},
     types::Realisation,
+    utils::{add_lock_ext, remove_path},
 };
-use anyhow::{Result, bail};
+use anyhow::{Result, anyhow, bail};
 use builder::run_builder;
-use log::info;
+use check::check;
+use log::{info, warn};
 use oxide_core::{
     drv::{DEFAULT_OUT, StoreDrv},
     hash::HashAlgo,
     store::StorePath,
     types::{EqClass, Out},
 };
+use plan::{Plan, plan};
+use schedule::schedule;
+use serde::{Deserialize, Serialize};
 use std::{
-    collections::{HashMap, HashSet},
-    path::Path,
+    collections::{BTreeMap, HashMap, HashSet},
+    env,
+    path::{Path, PathBuf},
 };
+use tokio::fs;
+
+/// Settings of a build chosen by whoever asks for it
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[allow(clippy::struct_excessive_bools)]
+pub struct BuildOpts {
+    /// given to fixed-output derivations if they are in `impure_env_vars` of the config
+    pub impure_envs: BTreeMap<String, String>,
+    /// overrides `max_jobs` of the config
+    pub max_jobs: Option<usize>,
+    /// overrides `cores` of the config
+    pub cores: Option<usize>,
+    /// keep building the derivations that do not depend on a failed one
+    pub keep_going: bool,
+    /// do not delete the build directory and the outputs of a failed build
+    pub keep_failed: bool,
+    /// start a shell in the environment of a failed build before it is cleaned up,
+    /// it uses the terminal of this process so it cannot go through the daemon
+    pub debug_shell: bool,
+    /// build the derivations again even if they have a trusted output and compare the outputs
+    pub check: bool,
+    /// number of times `check` builds every derivation, at least once
+    pub rounds: usize,
+}
 
-pub async fn build<S>(store: &S, p: &StorePath) -> Result<HashMap<Out, StorePath>>
+impl BuildOpts {
+    /// take the impure environment variables from the environment of this process
+    pub fn from_env() -> Self {
+        let impure_envs = CONFIG
+            .impure_env_vars
+            .iter()
+            .filter_map(|k| env::var(k).ok().map(|v| (k.clone(), v)))
+            .collect();
+        Self {
+            impure_envs,
+            ..Default::default()
+        }
+    }
+}
+
+/// What a build produced
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Built {
+    /// the outputs of every derivation in the order they were asked for
+    pub outputs: Vec<HashMap<Out, StorePath>>,
+    /// full paths of the derivations that were built by this build and their log
+    pub built: Vec<(String, Option<PathBuf>)>,
+}
+
+/// build the outputs of `drvs`, running up to `max_jobs` builders at the same time
+///
+/// returns the outputs of every derivation in the same order
+pub async fn build<S>(store: &S, drvs: &[StorePath], opts: &BuildOpts) -> Result<Built>
 where
     S: Store,
 {
-    let mut drv = store.read_drv(p).await?;
-
-    'b: {
-        // if all the eq_classes have a trusted path do not build again
-        let mut outs = HashMap::new();
-        for (out, eq_class) in &drv.eq_classes {
-            let trusted = store.trusted_paths(eq_class, out).await?;
-            if let Some(path) = trusted.into_iter().next() {
-                outs.insert(out.clone(), path.clone());
-            } else {
-                break 'b;
-            }
+    if opts.check {
+        return check(store, drvs, opts).await;
+    }
+    let Plan {
+        mut done, builds, ..
+    } = plan(store, drvs, false).await?;
+    // the dependencies that are not built are valid or were substituted
+    let max_jobs = opts.max_jobs.unwrap_or(CONFIG.max_jobs);
+    let scheduled = schedule(builds, max_jobs, opts.keep_going, |p, drv| async move {
+        build_one(store, &p, drv, opts).await
+    })
+    .await?;
+
+    let mut summary = KeepGoingError::default();
+    for (p, (outs, log)) in scheduled.built {
+        summary.built.push((S::store_path(&p), log));
+        done.insert(p, outs);
+    }
+    for (p, e) in scheduled.failed {
+        let log = e.downcast_ref::<BuildError>().and_then(|e| e.log.clone());
+        summary
+            .failed
+            .push((S::store_path(&p), log, format!("{e:#}")));
+    }
+    for (p, dep) in scheduled.skipped {
+        summary
+            .skipped
+            .push((S::store_path(&p), S::store_path(&dep)));
+    }
+    if !summary.failed.is_empty() {
+        return Err(summary.into());
+    }
+
+    let outputs = drvs
+        .iter()
+        .map(|p| {
+            done.get(p)
+                .cloned()
+                .ok_or_else(|| anyhow!("{p} was not built"))
+        })
+        .collect::<Result<_>>()?;
+    Ok(Built {
+        outputs,
+        built: summary.built,
+    })
+}
+
+/// build a single derivation whose inputs are all built,
+/// returns its outputs and the log of its builder
+async fn build_one<S>(
+    store: &S,
+    p: &StorePath,
+    drv: StoreDrv,
+    opts: &BuildOpts,
+) -> Result<(HashMap<Out, StorePath>, Option<PathBuf>)>
+where
+    S: Store,
+{
+    // another process may be building the same derivation,
+    // wait for it and use its outputs
+    let lock = lock_drv::<S>(p).await?;
+    if let Some(outs) = trusted_outs(store, &drv.eq_classes).await? {
+        info!("building: {p}: built by another process");
+        return Ok((outs, None));
+    }
+
+    let (drv, inputs, outputs) = prepare_drv(store, drv, false).await?;
+    info!("building: {p}");
+
+    let mut out_locks = lock_outputs::<S>(&outputs).await?;
+    let res = async {
+        let log = run_drv(store, p, &drv, &inputs, &outputs, opts).await?;
+        if drv.fixed_hash.is_some() {
+            // the output keeps its path and `add_to_store` locks it
+            out_locks.clear();
         }
-        info!("building: {p}: trusted path found");
-        return Ok(outs);
+        let added = add_outputs(store, p, drv, &outputs, &inputs).await?;
+        Ok((added, log))
     }
+    .await;
 
-    for path in drv.input_drvs.keys() {
-        Box::pin(build(store, path)).await?;
+    let res = clean_outputs(store, &outputs, res, opts).await;
+    drop(out_locks);
+    lock.unlock();
+    res
+}
+
+/// lock a derivation so that only one process builds it
+async fn lock_drv<S>(p: &StorePath) -> Result<PathLock>
+where
+    S: Store,
+{
+    let lock_path = format!("{}.build.lock", S::store_path(p));
+    if let Some(lock) = PathLock::try_lock(&lock_path, LockMode::Write)? {
+        Ok(lock)
+    } else {
+        info!("waiting for lock on {p}");
+        PathLock::lock_async(&lock_path, LockMode::Write).await
     }
+}
 
+/// resolve the inputs of `drv` and choose where its outputs are built,
+/// with `fresh` fixed-output derivations do not build to their final path either
+async fn prepare_drv<S>(
+    store: &S,
+    mut drv: StoreDrv,
+    fresh: bool,
+) -> Result<(StoreDrv, Vec<Realisation>, HashMap<Out, StorePath>)>
+where
+    S: Store,
+{
     let inputs = inputs(store, &drv).await?;
-    info!("building: {p}");
 
     let mut mappings = HashMap::new();
     for r in &inputs {
@@ -60,7 +218,7 @@ where
         rewrite_str(v, &mappings);
     }
 
-    let outputs = if drv.fixed_hash.is_some() {
+    let outputs = if drv.fixed_hash.is_some() && !fresh {
         drv.eq_classes.clone().into_iter().collect()
     } else {
         drv.eq_classes
@@ -73,18 +231,91 @@ where
             .iter()
             .map(|(out, eq_class)| (out.clone(), S::store_path(eq_class))),
     );
+    Ok((drv, inputs, outputs))
+}
+
+/// the temporary outputs are locked so that `clean-temp` leaves them alone
+async fn lock_outputs<S>(outputs: &HashMap<Out, StorePath>) -> Result<Vec<PathLock>>
+where
+    S: Store,
+{
+    let mut locks = Vec::with_capacity(outputs.len());
+    for out in outputs.values() {
+        let lock_path = add_lock_ext(S::store_path(out));
+        locks.push(PathLock::lock_async(lock_path, LockMode::Write).await?);
+    }
+    Ok(locks)
+}
 
-    run_builder::<S>(&drv).await?;
+/// run the builder of a prepared derivation and check its outputs,
+/// returns the log of the builder
+async fn run_drv<S>(
+    store: &S,
+    p: &StorePath,
+    drv: &StoreDrv,
+    inputs: &[Realisation],
+    outputs: &HashMap<Out, StorePath>,
+    opts: &BuildOpts,
+) -> Result<Option<PathBuf>>
+where
+    S: Store,
+{
+    // the builder can only see the closure of its inputs
+    let mut input_paths = inputs.iter().map(|r| r.path.clone()).collect::<Vec<_>>();
+    input_paths.extend(drv.input_srcs.iter().cloned());
+    let input_paths = store.compute_closure(&input_paths).await?;
+    let log = run_builder::<S>(p, drv, outputs, &input_paths, opts).await?;
 
     // check that output was valid
-    valid_output::<S>(&drv, &outputs).await?;
+    valid_output::<S>(drv, outputs).await?;
+    Ok(log)
+}
 
+/// remove what is left of the temporary outputs once `res` is known,
+/// with `keep_failed` the outputs of a failed build are added to its error
+async fn clean_outputs<S, T>(
+    store: &S,
+    outputs: &HashMap<Out, StorePath>,
+    res: Result<T>,
+    opts: &BuildOpts,
+) -> Result<T>
+where
+    S: Store,
+{
+    // outputs that were moved to the store are not there anymore
+    let kept = remove_outputs(store, outputs, res.is_err() && opts.keep_failed).await?;
+    match res {
+        Err(mut e) if !kept.is_empty() => {
+            if let Some(err) = e.downcast_mut::<BuildError>() {
+                err.kept.extend(kept);
+            } else {
+                for path in kept {
+                    warn!("keeping output {}", path.display());
+                }
+            }
+            Err(e)
+        }
+        res => res,
+    }
+}
+
+/// add the outputs of a successful build to the store
+async fn add_outputs<S>(
+    store: &S,
+    p: &StorePath,
+    drv: StoreDrv,
+    outputs: &HashMap<Out, StorePath>,
+    inputs: &[Realisation],
+) -> Result<HashMap<Out, StorePath>>
+where
+    S: Store,
+{
     let mut refs = inputs
         .iter()
         .map(|r| r.path.clone())
         .collect::<HashSet<_>>();
     refs.extend(drv.input_srcs);
-    let mut outs = HashMap::new();
+    let mut built = HashMap::new();
     for (out, eq_class) in drv.eq_classes {
         let self_hash = outputs[&out].clone();
         let tmp_path = S::store_path(&self_hash);
@@ -117,12 +348,59 @@ where
                     name,
                     rewrites: HashMap::new(),
                     self_hash,
+                    deriver: Some(p.clone()),
                 },
             )
             .await?;
-        outs.insert(out, output);
+        built.insert(out, output);
     }
-    Ok(outs)
+    Ok(built)
+}
+
+/// delete the temporary outputs of a build or return them if `keep` is set
+async fn remove_outputs<S>(
+    store: &S,
+    outputs: &HashMap<Out, StorePath>,
+    keep: bool,
+) -> Result<Vec<PathBuf>>
+where
+    S: Store,
+{
+    let outputs = outputs.values().cloned().collect::<Vec<_>>();
+    let valid = store.query_valid_paths(&outputs).await?;
+    let mut kept = Vec::new();
+    for out in outputs {
+        let path = PathBuf::from(S::store_path(&out));
+        if valid.contains(&out) || fs::symlink_metadata(&path).await.is_err() {
+            continue;
+        }
+        if keep {
+            kept.push(path);
+        } else if let Err(e) = remove_path(&path).await {
+            warn!("could not remove {}: {e}", path.display());
+        }
+    }
+    Ok(kept)
+}
+
+/// the trusted path of every output, `None` if one of them has none
+async fn trusted_outs<S>(
+    store: &S,
+    eq_classes: &BTreeMap<Out, EqClass>,
+) -> Result<Option<HashMap<Out, StorePath>>>
+where
+    S: Store,
+{
+    let mut outs = HashMap::new();
+    for (out, eq_class) in eq_classes {
+        let trusted = store.trusted_paths(eq_class, out).await?;
+        if let Some(path) = trusted.into_iter().next() {
+            outs.insert(out.clone(), path);
+        } else {
+            return Ok(None);
+        }
+    }
+    Ok(Some(outs))
 }
 
 pub async fn inputs<S>(store: &S, drv: &StoreDrv) -> Result<Vec<Realisation>>
@@ -245,18 +523,13 @@ where
     S: Store,
 {
     let mut res = HashSet::new();
-    closure_helper(store, r, &mut res).await?;
-    Ok(res)
-}
-
-async fn closure_helper<S>(store: &S, r: Realisation, res: &mut HashSet<Realisation>) -> Result<()>
-where
-    S: Store,
-{
-    let closure = store.realisation_refs(&r).await?;
-    res.insert(r);
-    for r in closure {
-        Box::pin(closure_helper(store, r, res)).await?;
+    let mut stack = vec![r];
+    while let Some(r) = stack.pop() {
+        if res.contains(&r) {
+            continue;
+        }
+        stack.extend(store.realisation_refs(&r).await?);
+        res.insert(r);
     }
-    Ok(())
+    Ok(res)
 }
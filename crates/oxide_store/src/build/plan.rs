@@ -0,0 +1,151 @@
+use super::{
+    substitute::{Substitution, query_substitute, substitute},
+    trusted_outs,
+};
+use crate::api::Store;
+use anyhow::Result;
+use log::info;
+use oxide_core::{drv::StoreDrv, store::StorePath, types::Out};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+
+/// What building some derivations would do
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct BuildPlan {
+    /// derivations to build, dependencies first
+    pub build: Vec<StorePath>,
+    /// derivations whose outputs are already valid
+    pub valid: BTreeMap<StorePath, HashMap<Out, StorePath>>,
+    /// derivations whose outputs would be downloaded from a binary cache
+    pub substitute: BTreeMap<StorePath, Substitution>,
+}
+
+/// What has to be done to get the outputs of some derivations
+pub(super) struct Plan {
+    /// outputs of the derivations that are valid or were substituted
+    pub done: HashMap<StorePath, HashMap<Out, StorePath>>,
+    /// derivations that can be substituted, only when planning a dry run
+    pub substitutes: HashMap<StorePath, Substitution>,
+    /// derivations to build and the derivations they need
+    pub builds: HashMap<StorePath, (StoreDrv, BTreeSet<StorePath>)>,
+}
+
+/// walk the derivations `drvs` need, every derivation is checked once
+///
+/// on a dry run nothing is substituted
+pub(super) async fn plan<S>(store: &S, drvs: &[StorePath], dry_run: bool) -> Result<Plan>
+where
+    S: Store,
+{
+    let mut done = HashMap::new();
+    let mut substitutes = HashMap::new();
+    let mut builds = HashMap::new();
+    let mut stack = drvs.to_vec();
+    while let Some(p) = stack.pop() {
+        if done.contains_key(&p) || substitutes.contains_key(&p) || builds.contains_key(&p) {
+            continue;
+        }
+        let drv = store.read_drv(&p).await?;
+
+        // if all the eq_classes have a trusted path do not build again
+        if let Some(outs) = trusted_outs(store, &drv.eq_classes).await? {
+            info!("building: {p}: trusted path found");
+            done.insert(p, outs);
+            continue;
+        }
+
+        if dry_run {
+            if let Some(sub) = query_substitute(store, &drv.eq_classes).await? {
+                substitutes.insert(p, sub);
+                continue;
+            }
+        } else if let Some(outs) = substitute(store, &drv.eq_classes).await? {
+            info!("building: {p}: substituted");
+            done.insert(p, outs);
+            continue;
+        }
+
+        let deps = drv.input_drvs.keys().cloned().collect::<BTreeSet<_>>();
+        stack.extend(deps.iter().cloned());
+        builds.insert(p, (drv, deps));
+    }
+    Ok(Plan {
+        done,
+        substitutes,
+        builds,
+    })
+}
+
+/// what building `drvs` would do without substituting or building anything
+pub async fn plan_build<S>(store: &S, drvs: &[StorePath]) -> Result<BuildPlan>
+where
+    S: Store,
+{
+    let Plan {
+        done,
+        substitutes,
+        builds,
+    } = plan(store, drvs, true).await?;
+    Ok(BuildPlan {
+        build: build_order(&builds),
+        valid: done.into_iter().collect(),
+        substitute: substitutes.into_iter().collect(),
+    })
+}
+
+/// the derivations of `builds` with their dependencies before them
+fn build_order<T>(builds: &HashMap<StorePath, (T, BTreeSet<StorePath>)>) -> Vec<StorePath> {
+    let mut roots = builds.keys().collect::<Vec<_>>();
+    roots.sort();
+    let mut order = Vec::new();
+    let mut seen = HashSet::new();
+    for root in roots {
+        // a derivation is pushed again once its dependencies are in the order
+        let mut stack = vec![(root, false)];
+        while let Some((p, expanded)) = stack.pop() {
+            if expanded {
+                order.push(p.clone());
+                continue;
+            }
+            if !seen.insert(p) {
+                continue;
+            }
+            stack.push((p, true));
+            for d in &builds[p].1 {
+                if builds.contains_key(d) && !seen.contains(d) {
+                    stack.push((d, false));
+                }
+            }
+        }
+    }
+    order
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hash::utils::random_path;
+
+    #[test]
+    fn dependencies_come_first() {
+        // `top` needs `left` and `right` which both need `bottom`,
+        // `valid` is not built so it is left out of the order
+        let [top, left, right, bottom, valid] = ["top", "left", "right", "bottom", "valid"]
+            .map(|name| random_path(&format!("{name}.drv")));
+        let deps = |deps: &[&StorePath]| ((), deps.iter().map(|&d| d.clone()).collect());
+        let builds = HashMap::from([
+            (top.clone(), deps(&[&left, &right])),
+            (left.clone(), deps(&[&bottom, &valid])),
+            (right.clone(), deps(&[&bottom])),
+            (bottom.clone(), deps(&[])),
+        ]);
+        let order = build_order(&builds);
+        assert_eq!(order.len(), 4);
+        let pos = |p: &StorePath| order.iter().position(|o| o == p).unwrap();
+        assert!(pos(&bottom) < pos(&left) && pos(&bottom) < pos(&right));
+        assert_eq!(pos(&top), 3);
+        assert!(!order.contains(&valid));
+        // the order does not depend on the order of the map
+        assert_eq!(build_order(&builds.into_iter().collect()), order);
+    }
+}
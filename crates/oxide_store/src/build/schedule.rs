@@ -0,0 +1,186 @@
+use anyhow::{Error, Result};
+use futures_util::{StreamExt, stream::FuturesUnordered};
+use oxide_core::store::StorePath;
+use std::collections::{BTreeSet, HashMap, VecDeque};
+
+/// What happened to the jobs given to [`schedule`]
+pub(super) struct Scheduled<T> {
+    /// in the order they finished
+    pub built: Vec<(StorePath, T)>,
+    pub failed: Vec<(StorePath, Error)>,
+    /// jobs that were not run and the failed job they need
+    pub skipped: Vec<(StorePath, StorePath)>,
+}
+
+/// run every job once the jobs it needs are done, up to `max_jobs` at the same time,
+/// the dependencies of a job that are not in `jobs` must already be done
+///
+/// without `keep_going` the first error is returned and the running jobs are dropped
+pub(super) async fn schedule<J, T, F, Fut>(
+    jobs: HashMap<StorePath, (J, BTreeSet<StorePath>)>,
+    max_jobs: usize,
+    keep_going: bool,
+    mut run: F,
+) -> Result<Scheduled<T>>
+where
+    F: FnMut(StorePath, J) -> Fut,
+    Fut: Future<Output = Result<T>>,
+{
+    // the number of dependencies that are not done yet and who is waiting for them
+    let mut waiting = HashMap::new();
+    let mut dependents: HashMap<StorePath, Vec<StorePath>> = HashMap::new();
+    let mut ready = VecDeque::new();
+    for (p, (_, deps)) in &jobs {
+        let deps = deps
+            .iter()
+            .filter(|d| jobs.contains_key(*d))
+            .collect::<Vec<_>>();
+        if deps.is_empty() {
+            ready.push_back(p.clone());
+        } else {
+            waiting.insert(p.clone(), deps.len());
+        }
+        for d in deps {
+            dependents.entry(d.clone()).or_default().push(p.clone());
+        }
+    }
+    let mut jobs = jobs
+        .into_iter()
+        .map(|(p, (job, _))| (p, job))
+        .collect::<HashMap<_, _>>();
+
+    let mut scheduled = Scheduled {
+        built: Vec::new(),
+        failed: Vec::new(),
+        skipped: Vec::new(),
+    };
+    let mut running = FuturesUnordered::new();
+    loop {
+        while running.len() < max_jobs.max(1)
+            && let Some(p) = ready.pop_front()
+        {
+            let job = jobs.remove(&p).expect("ready jobs are pending");
+            let fut = run(p.clone(), job);
+            running.push(async move { (p, fut.await) });
+        }
+        let Some((p, res)) = running.next().await else {
+            break;
+        };
+        let done = match res {
+            Ok(done) => done,
+            Err(e) if keep_going => {
+                // nothing that needs `p` can be run anymore
+                let mut failed = vec![p.clone()];
+                while let Some(f) = failed.pop() {
+                    for d in dependents.remove(&f).unwrap_or_default() {
+                        if waiting.remove(&d).is_some() {
+                            jobs.remove(&d);
+                            scheduled.skipped.push((d.clone(), p.clone()));
+                            failed.push(d);
+                        }
+                    }
+                }
+                scheduled.failed.push((p, e));
+                continue;
+            }
+            Err(e) => return Err(e),
+        };
+        for d in dependents.remove(&p).unwrap_or_default() {
+            // skipped because another dependency failed
+            let Some(n) = waiting.get_mut(&d) else {
+                continue;
+            };
+            *n -= 1;
+            if *n == 0 {
+                waiting.remove(&d);
+                ready.push_back(d);
+            }
+        }
+        scheduled.built.push((p, done));
+    }
+    Ok(scheduled)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hash::utils::random_path;
+    use anyhow::bail;
+    use std::{cell::RefCell, time::Duration};
+    use tokio::time::sleep;
+
+    /// `top` needs `left` and `right` which both need `bottom`
+    fn diamond() -> [StorePath; 4] {
+        ["top", "left", "right", "bottom"].map(|name| random_path(&format!("{name}.drv")))
+    }
+
+    fn jobs(
+        [top, left, right, bottom]: &[StorePath; 4],
+    ) -> HashMap<StorePath, ((), BTreeSet<StorePath>)> {
+        let deps = |deps: &[&StorePath]| ((), deps.iter().map(|&d| d.clone()).collect());
+        HashMap::from([
+            (top.clone(), deps(&[left, right])),
+            (left.clone(), deps(&[bottom])),
+            (right.clone(), deps(&[bottom])),
+            (bottom.clone(), deps(&[])),
+        ])
+    }
+
+    #[tokio::test]
+    async fn shared_dependencies_run_once() -> Result<()> {
+        let paths = diamond();
+        let [top, left, right, bottom] = &paths;
+        let started = RefCell::new(Vec::new());
+        let running = RefCell::new(0);
+        let max_running = RefCell::new(0);
+        let scheduled = schedule(jobs(&paths), 2, false, |p, ()| {
+            let (started, running, max_running) = (&started, &running, &max_running);
+            async move {
+                started.borrow_mut().push(p.clone());
+                *running.borrow_mut() += 1;
+                let now = *running.borrow();
+                max_running.replace_with(|max| now.max(*max));
+                sleep(Duration::from_millis(50)).await;
+                *running.borrow_mut() -= 1;
+                Ok(p)
+            }
+        })
+        .await?;
+
+        let started = started.into_inner();
+        assert_eq!(started.len(), 4);
+        assert_eq!(&started[0], bottom);
+        assert!(started[1..3].contains(left) && started[1..3].contains(right));
+        assert_eq!(&started[3], top);
+        // left and right ran at the same time
+        assert_eq!(max_running.into_inner(), 2);
+        assert!(scheduled.built.iter().all(|(p, done)| p == done));
+        assert!(scheduled.failed.is_empty() && scheduled.skipped.is_empty());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn dependents_of_failed_jobs_are_skipped() -> Result<()> {
+        let paths = diamond();
+        let [top, left, right, _] = &paths;
+        let run = |p: StorePath, ()| async move {
+            if p == *left {
+                bail!("{p} failed");
+            }
+            Ok(())
+        };
+        let scheduled = schedule(jobs(&paths), 1, true, run).await?;
+        assert_eq!(scheduled.built.len(), 2);
+        assert!(scheduled.built.iter().any(|(p, ())| p == right));
+        assert_eq!(scheduled.failed.len(), 1);
+        assert_eq!(&scheduled.failed[0].0, left);
+        assert_eq!(scheduled.skipped, vec![(top.clone(), left.clone())]);
+
+        // without keep_going the first error is returned
+        let Err(e) = schedule(jobs(&paths), 1, false, run).await else {
+            panic!("the failure was ignored");
+        };
+        assert!(e.to_string().contains("failed"), "{e}");
+        Ok(())
+    }
+}
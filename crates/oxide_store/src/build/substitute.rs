@@ -0,0 +1,120 @@
+use crate::{
+    api::{CONFIG, Store},
+    stores::cache::BinaryCacheStore,
+};
+use anyhow::Result;
+use log::warn;
+use oxide_core::{
+    store::StorePath,
+    types::{EqClass, Out},
+};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+
+/// Outputs of a derivation that can be downloaded from a binary cache
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Substitution {
+    pub url: String,
+    /// size of the archives to download, unknown if the cache does not record it
+    pub download_size: Option<u64>,
+    /// size of the downloaded paths once unpacked
+    pub unpacked_size: u64,
+    /// paths of the closure of the outputs that are not in the store
+    pub paths: Vec<StorePath>,
+    pub outs: HashMap<Out, StorePath>,
+}
+
+/// try to download every output from the configured binary caches
+/// a cache that fails is skipped
+pub(super) async fn substitute<S>(
+    store: &S,
+    eq_classes: &BTreeMap<Out, EqClass>,
+) -> Result<Option<HashMap<Out, StorePath>>>
+where
+    S: Store,
+{
+    let substituters = CONFIG.substituters.clone();
+    for url in substituters {
+        match substitute_from(store, &url, eq_classes).await {
+            Ok(Some(outs)) => return Ok(Some(outs)),
+            Ok(None) => {}
+            Err(e) => warn!("substituter {url}: {e}"),
+        }
+    }
+    Ok(None)
+}
+
+/// the first configured binary cache with every output and what would be downloaded from it
+pub(super) async fn query_substitute<S>(
+    store: &S,
+    eq_classes: &BTreeMap<Out, EqClass>,
+) -> Result<Option<Substitution>>
+where
+    S: Store,
+{
+    let substituters = CONFIG.substituters.clone();
+    for url in substituters {
+        match query_from(store, &url, eq_classes).await {
+            Ok(Some(sub)) => return Ok(Some(sub)),
+            Ok(None) => {}
+            Err(e) => warn!("substituter {url}: {e}"),
+        }
+    }
+    Ok(None)
+}
+
+async fn substitute_from<S>(
+    store: &S,
+    url: &str,
+    eq_classes: &BTreeMap<Out, EqClass>,
+) -> Result<Option<HashMap<Out, StorePath>>>
+where
+    S: Store,
+{
+    let cache = BinaryCacheStore::new(url).await?;
+    let Some(outs) = cache_outs(&cache, eq_classes).await? else {
+        return Ok(None);
+    };
+    let paths = outs.values().cloned().collect::<Vec<_>>();
+    cache.substitute(store, &paths).await?;
+    Ok(Some(outs))
+}
+
+async fn query_from<S>(
+    store: &S,
+    url: &str,
+    eq_classes: &BTreeMap<Out, EqClass>,
+) -> Result<Option<Substitution>>
+where
+    S: Store,
+{
+    let cache = BinaryCacheStore::new(url).await?;
+    let Some(outs) = cache_outs(&cache, eq_classes).await? else {
+        return Ok(None);
+    };
+    let paths = outs.values().cloned().collect::<Vec<_>>();
+    let missing = cache.missing_paths(store, &paths).await?;
+    Ok(Some(Substitution {
+        url: url.to_string(),
+        // unknown as soon as the size of one of the archives is unknown
+        download_size: missing.iter().map(|info| info.file_size).sum(),
+        unpacked_size: missing.iter().map(|info| info.size).sum(),
+        paths: missing.into_iter().map(|info| info.info.path).collect(),
+        outs,
+    }))
+}
+
+/// the paths in `cache` realising every output
+async fn cache_outs(
+    cache: &BinaryCacheStore,
+    eq_classes: &BTreeMap<Out, EqClass>,
+) -> Result<Option<HashMap<Out, StorePath>>> {
+    let mut outs = HashMap::new();
+    for (out, eq_class) in eq_classes {
+        let Some(path) = cache.trusted_paths(eq_class, out).await?.into_iter().next() else {
+            return Ok(None);
+        };
+        outs.insert(out.clone(), path);
+    }
+    Ok(Some(outs))
+}
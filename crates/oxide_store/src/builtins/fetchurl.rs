@@ -6,7 +6,11 @@ use oxide_core::{
     builtins::BUILTIN_PREFIX,
     utils::{EXEC_FILE_PERMISSION, FILE_PERMISSION},
 };
-use std::{fs::Permissions, os::unix::fs::PermissionsExt, path::PathBuf, time::Duration};
+use reqwest::{Client, NoProxy, Proxy};
+use std::{
+    collections::HashMap, fs::Permissions, os::unix::fs::PermissionsExt, path::PathBuf,
+    time::Duration,
+};
 use tokio::{
     fs::{self, File},
     io::{AsyncWriteExt, BufWriter},
@@ -38,10 +42,7 @@ pub async fn fetch_url(ctx: Ctx<'_>) -> Result<()> {
         todo!()
     }
     let executable = ctx.drv.envs.get("executable").is_some_and(|v| v == "1");
-    let client = reqwest::Client::builder()
-        .connect_timeout(Duration::from_secs(10))
-        .timeout(Duration::from_secs(10))
-        .build()?;
+    let client = client(&ctx.impure_envs)?;
     info!("fetching: {main_url}");
     let response = client.get(main_url).send().await?;
 
@@ -69,3 +70,94 @@ pub async fn fetch_url(ctx: Ctx<'_>) -> Result<()> {
 
     Ok(())
 }
+
+/// a client using the proxies of the caller, the ones of this process are ignored
+/// since it can be the daemon building for someone else
+fn client(impure_envs: &HashMap<String, String>) -> Result<Client> {
+    let env = |name: &str| {
+        impure_envs
+            .get(name)
+            .or_else(|| impure_envs.get(&name.to_uppercase()))
+            .filter(|v| !v.is_empty())
+    };
+    let no_proxy = env("no_proxy").and_then(|v| NoProxy::from_string(v));
+    let mut builder = Client::builder()
+        .no_proxy()
+        .connect_timeout(Duration::from_secs(10))
+        .timeout(Duration::from_secs(10));
+    if let Some(proxy) = env("http_proxy") {
+        builder = builder.proxy(Proxy::http(proxy)?.no_proxy(no_proxy.clone()));
+    }
+    if let Some(proxy) = env("https_proxy") {
+        builder = builder.proxy(Proxy::https(proxy)?.no_proxy(no_proxy.clone()));
+    }
+    if let Some(proxy) = env("all_proxy") {
+        builder = builder.proxy(Proxy::all(proxy)?.no_proxy(no_proxy));
+    }
+    Ok(builder.build()?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::{remove_path, tempfile::tempdir_in};
+    use futures_util::future::join;
+    use oxide_core::{drv::StoreDrv, hash::Hash, system::System};
+    use std::collections::{BTreeMap, BTreeSet};
+    use tokio::{
+        io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+        net::TcpListener,
+    };
+
+    fn fetch_drv(url: &str) -> StoreDrv {
+        StoreDrv {
+            eq_classes: BTreeMap::new(),
+            fixed_hash: Some(Hash::placeholder()),
+            input_drvs: BTreeMap::new(),
+            input_srcs: BTreeSet::new(),
+            system: System::x86_64_linux,
+            builder: format!("{BUILTIN_PREFIX}fetchurl"),
+            args: Vec::new(),
+            envs: BTreeMap::from([("url".to_string(), url.to_string())]),
+        }
+    }
+
+    #[tokio::test]
+    async fn downloads_go_through_the_proxy_of_the_caller() -> Result<()> {
+        let dir = tempdir_in(std::env::temp_dir()).await?;
+        let out = dir.join("out");
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let proxy = format!("http://{}", listener.local_addr()?);
+        let drv = fetch_drv("http://oxide.invalid/file");
+        let ctx = Ctx {
+            drv: &drv,
+            outputs: HashMap::from([("out".to_string(), out.display().to_string())]),
+            impure_envs: HashMap::from([("http_proxy".to_string(), proxy)]),
+        };
+
+        // the host does not exist so only the proxy can answer
+        let proxy = async {
+            let (stream, _) = listener.accept().await?;
+            let mut stream = BufReader::new(stream);
+            let mut request = String::new();
+            stream.read_line(&mut request).await?;
+            // skip the headers
+            let mut line = String::new();
+            while stream.read_line(&mut line).await? > 2 {
+                line.clear();
+            }
+            stream
+                .write_all(
+                    b"HTTP/1.1 200 OK\r\ncontent-length: 5\r\nconnection: close\r\n\r\nproxy",
+                )
+                .await?;
+            Ok::<_, anyhow::Error>(request)
+        };
+        let (res, request) = join(fetch_url(ctx), proxy).await;
+        res?;
+        let request = request?;
+        assert!(request.starts_with("GET http://oxide.invalid/file "));
+        assert_eq!(fs::read_to_string(&out).await?, "proxy");
+        remove_path(&dir).await
+    }
+}
@@ -8,4 +8,6 @@ use oxide_core::drv::StoreDrv;
 pub struct Ctx<'a> {
     pub drv: &'a StoreDrv,
     pub outputs: HashMap<String, String>,
+    /// environment of the caller like proxies, see `impure_env_vars` in the config
+    pub impure_envs: HashMap<String, String>,
 }
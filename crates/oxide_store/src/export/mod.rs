@@ -0,0 +1,383 @@
+use crate::{
+    api::Store,
+    archive::{read_archive, read_bytes, read_u64, write_archive, write_bytes, write_u64},
+    hash::utils::make_path,
+    os::lock::{LockMode, PathLock},
+    types::ObjInfo,
+    utils::{add_lock_ext, remove_path, tempfile::temppath_in},
+};
+use anyhow::{Result, bail};
+use futures_util::future::join;
+use oxide_core::{hash::Hash, store::StorePath};
+use std::{
+    collections::{BTreeSet, HashMap},
+    path::{Path, PathBuf},
+};
+use tokio::io::{self, AsyncRead, AsyncWrite, AsyncWriteExt, BufReader, BufWriter};
+
+pub const EXPORT_MAGIC: &str = "oxide-export-1";
+
+/// metadata of a single path cannot be longer than this
+const MAX_INFO_LEN: u64 = 16 * 1024 * 1024;
+
+/// size of the pipe between the two stores of [`copy_paths`]
+const PIPE_SIZE: usize = 64 * 1024;
+
+const END: u64 = 0;
+const MORE: u64 = 1;
+/// only the metadata of a path that the reader already has
+const VALID: u64 = 2;
+
+/// Everything needed to register `path` in another store
+pub async fn obj_info<S>(store: &S, path: &StorePath) -> Result<ObjInfo>
+where
+    S: Store + ?Sized,
+{
+    let Some(info) = store.query_path_info(path).await? else {
+        bail!("path {} is not valid", S::store_path(path));
+    };
+    Ok(ObjInfo {
+        path: info.path,
+        hash: info.hash,
+        deriver: info.deriver,
+        refs: info.refs,
+        realisations: store.query_realisations(path).await?,
+    })
+}
+
+/// sort `paths` so that every path comes after its references
+pub async fn sort_paths<S>(store: &S, paths: BTreeSet<StorePath>) -> Result<Vec<StorePath>>
+where
+    S: Store,
+{
+    let mut refs = HashMap::new();
+    for path in &paths {
+        let Some(info) = store.query_path_info(path).await? else {
+            bail!("path {} is not valid", S::store_path(path));
+        };
+        let deps = info
+            .refs
+            .into_iter()
+            .filter(|r| r != path && paths.contains(r))
+            .collect::<BTreeSet<_>>();
+        refs.insert(path.clone(), deps);
+    }
+    let mut sorted = Vec::new();
+    let mut done = BTreeSet::new();
+    while done.len() < paths.len() {
+        let ready = paths
+            .iter()
+            .filter(|p| !done.contains(*p) && refs[*p].iter().all(|r| done.contains(r)))
+            .cloned()
+            .collect::<Vec<_>>();
+        if ready.is_empty() {
+            bail!("cycle in the references of the paths to sort");
+        }
+        done.extend(ready.iter().cloned());
+        sorted.extend(ready);
+    }
+    Ok(sorted)
+}
+
+/// write the closure of `paths` to `writer`, see [`write_export`]
+pub async fn export_closure<S, W>(store: &S, paths: &[StorePath], writer: W) -> Result<()>
+where
+    S: Store,
+    W: AsyncWrite + Unpin,
+{
+    let closure = store.compute_closure(paths).await?;
+    let sorted = sort_paths(store, closure).await?;
+    store.export_paths(&sorted, &BTreeSet::new(), writer).await
+}
+
+/// read a stream written by [`export_closure`], verify every path and register them
+/// returns the imported paths
+pub async fn import_closure<S, R>(store: &S, reader: R) -> Result<Vec<StorePath>>
+where
+    S: Store,
+    R: AsyncRead + Unpin,
+{
+    store.import_stream(reader).await
+}
+
+/// copy the closure of `paths` from `src` to `dst` with its realisations
+/// only the paths that `dst` does not have are transferred
+/// returns the transferred paths
+pub async fn copy_paths<S, D>(src: &S, dst: &D, paths: &[StorePath]) -> Result<Vec<StorePath>>
+where
+    S: Store,
+    D: Store,
+{
+    let closure = src.compute_closure(paths).await?;
+    let valid = dst
+        .query_valid_paths(&closure.iter().cloned().collect::<Vec<_>>())
+        .await?;
+    let sorted = sort_paths(src, closure).await?;
+    let (reader, writer) = io::duplex(PIPE_SIZE);
+    let (exported, imported) = join(
+        src.export_paths(&sorted, &valid, writer),
+        dst.import_stream(reader),
+    )
+    .await;
+    // if the import fails the export only sees a broken pipe
+    imported?;
+    exported?;
+    Ok(sorted.into_iter().filter(|p| !valid.contains(p)).collect())
+}
+
+/// write `paths` to `writer`, `paths` must be sorted with [`sort_paths`]
+///
+/// The stream starts with [`EXPORT_MAGIC`] and contains one entry for each path
+/// followed by an end marker.
+/// Each entry is the toml encoded [`ObjInfo`] followed by the archive of the path,
+/// the archive is omitted for paths in `valid` that the reader already has.
+/// Every string is prefixed by its length and every integer is a big endian u64.
+pub async fn write_export<S, W>(
+    store: &S,
+    paths: &[StorePath],
+    valid: &BTreeSet<StorePath>,
+    writer: W,
+) -> Result<()>
+where
+    S: Store + ?Sized,
+    W: AsyncWrite + Unpin,
+{
+    let mut writer = BufWriter::new(writer);
+    write_bytes(&mut writer, EXPORT_MAGIC.as_bytes()).await?;
+    for path in paths {
+        let info = obj_info(store, path).await?;
+        if valid.contains(path) {
+            write_u64(&mut writer, VALID).await?;
+            write_bytes(&mut writer, toml::to_string(&info)?.as_bytes()).await?;
+            continue;
+        }
+        write_u64(&mut writer, MORE).await?;
+        write_bytes(&mut writer, toml::to_string(&info)?.as_bytes()).await?;
+        store.dump_path(path, &mut writer).await?;
+    }
+    write_u64(&mut writer, END).await?;
+    writer.shutdown().await?;
+    Ok(())
+}
+
+/// write paths unpacked in a temporary location as an export stream
+/// the paths without a temporary location are written as already valid
+pub(crate) async fn write_objs<W>(objs: &[(ObjInfo, Option<PathBuf>)], writer: W) -> Result<()>
+where
+    W: AsyncWrite + Unpin,
+{
+    let mut writer = BufWriter::new(writer);
+    write_bytes(&mut writer, EXPORT_MAGIC.as_bytes()).await?;
+    for (info, tmp_path) in objs {
+        write_u64(&mut writer, if tmp_path.is_some() { MORE } else { VALID }).await?;
+        write_bytes(&mut writer, toml::to_string(info)?.as_bytes()).await?;
+        if let Some(tmp_path) = tmp_path {
+            write_archive(tmp_path, &mut writer).await?;
+        }
+    }
+    write_u64(&mut writer, END).await?;
+    writer.shutdown().await?;
+    Ok(())
+}
+
+/// read a stream written by [`write_export`], verify every path and register them
+/// returns the paths in the stream
+pub async fn read_export<S, R>(store: &S, reader: R) -> Result<Vec<StorePath>>
+where
+    S: Store + ?Sized,
+    R: AsyncRead + Unpin,
+{
+    read_export_checked(store, reader, |_| Ok(())).await
+}
+
+/// like [`read_export`] but nothing is imported if `check` fails for a path
+pub(crate) async fn read_export_checked<S, R, F>(
+    store: &S,
+    reader: R,
+    check: F,
+) -> Result<Vec<StorePath>>
+where
+    S: Store + ?Sized,
+    R: AsyncRead + Unpin,
+    F: Fn(&ObjInfo) -> Result<()>,
+{
+    let mut reader = BufReader::new(reader);
+    let magic = read_bytes(&mut reader, EXPORT_MAGIC.len() as u64).await?;
+    if magic != EXPORT_MAGIC.as_bytes() {
+        bail!("not an oxide export stream");
+    }
+    let mut objs = Vec::new();
+    let mut tmp_locks = Vec::new();
+    let res = async {
+        loop {
+            let marker = read_u64(&mut reader).await?;
+            if marker == END {
+                break;
+            }
+            if marker != MORE && marker != VALID {
+                bail!("invalid export stream");
+            }
+            let info = read_bytes(&mut reader, MAX_INFO_LEN).await?;
+            let info: ObjInfo = toml::from_str(&String::from_utf8(info)?)?;
+            check(&info)?;
+            if marker == VALID {
+                objs.push((info, None));
+                continue;
+            }
+            let algo = info.hash.algo();
+            if store.query_path_info(&info.path).await?.is_some() {
+                let hash = read_archive(&mut reader, None, algo, Some(&info.path)).await?;
+                verify_hash(&info, &hash)?;
+                objs.push((info, None));
+                continue;
+            }
+            let (tmp_path, tmp_lock) = unpack_obj(&store.import_dir(), &info, &mut reader).await?;
+            objs.push((info, Some(tmp_path)));
+            tmp_locks.push(tmp_lock);
+        }
+        Ok::<_, anyhow::Error>(())
+    }
+    .await;
+    if let Err(e) = res {
+        for (_, tmp_path) in objs {
+            if let Some(tmp_path) = tmp_path {
+                _ = remove_path(tmp_path).await;
+            }
+        }
+        return Err(e);
+    }
+
+    let paths = objs.iter().map(|(info, _)| info.path.clone()).collect();
+    store.import_paths(objs).await?;
+    drop(tmp_locks);
+    Ok(paths)
+}
+
+/// unpack the archive of `info` in a temporary path inside of `dir` and verify it,
+/// `dir` is the [`Store::import_dir`] of the store importing the path
+/// the temporary path is removed if the archive is not valid
+///
+/// the path is locked until the returned lock is dropped so that `clean-temp` leaves it alone
+pub(crate) async fn unpack_obj<R>(
+    dir: &Path,
+    info: &ObjInfo,
+    reader: &mut R,
+) -> Result<(PathBuf, PathLock)>
+where
+    R: AsyncRead + Unpin,
+{
+    let tmp_path = temppath_in(dir);
+    let tmp_lock = PathLock::lock_async(add_lock_ext(&tmp_path), LockMode::Write).await?;
+    let res = async {
+        let hash =
+            read_archive(reader, Some(&tmp_path), info.hash.algo(), Some(&info.path)).await?;
+        verify_hash(info, &hash)
+    }
+    .await;
+    if let Err(e) = res {
+        _ = remove_path(&tmp_path).await;
+        return Err(e);
+    }
+    Ok((tmp_path, tmp_lock))
+}
+
+/// check that the hash of the archive matches the path described by `info`
+fn verify_hash(info: &ObjInfo, hash: &Hash) -> Result<()> {
+    if *hash != info.hash || make_path(hash, info.path.name_part()) != info.path {
+        bail!(
+            r"hash mismatch importing {}:
+expected:
+  {}
+got:
+  {hash}",
+            info.path,
+            info.hash
+        );
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{stores::cache::BinaryCacheStore, utils::tempfile::tempdir_in};
+    use oxide_core::hash::HashAlgo;
+    use tokio::fs;
+
+    /// a path in `dir` and the metadata it would have in a store
+    async fn make_obj(dir: &Path, name: &str) -> Result<(ObjInfo, PathBuf)> {
+        let tmp_path = dir.join(name);
+        fs::create_dir(&tmp_path).await?;
+        fs::write(tmp_path.join("greeting"), name).await?;
+        fs::symlink("greeting", tmp_path.join("link")).await?;
+        let mut archive = Vec::new();
+        write_archive(&tmp_path, &mut archive).await?;
+        let hash = read_archive(&mut archive.as_slice(), None, HashAlgo::Sha512, None).await?;
+        let info = ObjInfo {
+            path: make_path(&hash, name),
+            hash,
+            deriver: None,
+            refs: BTreeSet::new(),
+            realisations: Vec::new(),
+        };
+        Ok((info, tmp_path))
+    }
+
+    async fn stream(objs: &[(ObjInfo, Option<PathBuf>)]) -> Result<Vec<u8>> {
+        let mut buff = Vec::new();
+        write_objs(objs, &mut buff).await?;
+        Ok(buff)
+    }
+
+    #[tokio::test]
+    async fn export_import_round_trip() -> Result<()> {
+        let dir = tempdir_in(std::env::temp_dir()).await?;
+        let url = |name: &str| format!("file://{}", dir.join(name).display());
+        let src = BinaryCacheStore::create(&url("src")).await?;
+        let dst = BinaryCacheStore::create(&url("dst")).await?;
+        let (info, tmp_path) = make_obj(&dir, "hello").await?;
+
+        let buff = stream(&[(info.clone(), Some(tmp_path.clone()))]).await?;
+        assert_eq!(
+            read_export(&src, buff.as_slice()).await?,
+            vec![info.path.clone()]
+        );
+        let mut buff = Vec::new();
+        export_closure(&src, std::slice::from_ref(&info.path), &mut buff).await?;
+        assert_eq!(
+            import_closure(&dst, buff.as_slice()).await?,
+            vec![info.path.clone()]
+        );
+        let mut expected = Vec::new();
+        write_archive(&tmp_path, &mut expected).await?;
+        let mut archive = Vec::new();
+        dst.dump_path(&info.path, &mut archive).await?;
+        assert_eq!(archive, expected);
+        assert_eq!(
+            dst.query_path_info(&info.path).await?.unwrap().hash,
+            info.hash
+        );
+
+        // nothing is imported if one of the paths does not match its hash
+        let (other, other_path) = make_obj(&dir, "other").await?;
+        let rejected = BinaryCacheStore::create(&url("rejected")).await?;
+        let mut wrong_hash = other.clone();
+        wrong_hash.hash = info.hash.clone();
+        let mut wrong_path = other.clone();
+        wrong_path.path = info.path.clone();
+        for bad in [wrong_hash, wrong_path] {
+            let buff = stream(&[
+                (info.clone(), Some(tmp_path.clone())),
+                (bad, Some(other_path.clone())),
+            ])
+            .await?;
+            let err = read_export(&rejected, buff.as_slice()).await.unwrap_err();
+            assert!(err.to_string().contains("hash mismatch"), "{err}");
+            assert!(rejected.query_path_info(&info.path).await?.is_none());
+        }
+        assert!(read_export(&rejected, &b"not an export"[..]).await.is_err());
+
+        remove_path(&dir).await?;
+        Ok(())
+    }
+}
@@ -1,10 +1,13 @@
+use crate::hash::search_self_hash;
 use crate::hash::utils::ChunkReader;
 use anyhow::{Result, bail};
 use oxide_core::hash::{Hash, HashAlgo};
 use oxide_core::store::{HASH_PART_LEN, HashPart, StorePath};
 use sha2::{Digest, Sha256, Sha512};
+use std::ffi::OsStr;
 use std::fs::Metadata;
 use std::io::SeekFrom;
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
 use std::os::unix::fs::PermissionsExt;
 use std::{
     collections::{BTreeMap, HashMap},
@@ -79,11 +82,14 @@ where
     P: AsRef<Path>,
 {
     let path = path.as_ref();
-    Ok(if path.is_dir() {
+    // symlinks are hashed as symlinks and not as what they point to,
+    // paths added before this was the case and containing symlinks get a different hash
+    let metadata = fs::symlink_metadata(path).await?;
+    Ok(if metadata.is_dir() {
         Some(hash_dir::<H, _>(path, rewrites, self_hash).await?)
-    } else if path.is_file() {
+    } else if metadata.is_file() {
         Some(hash_file::<H, _>(path, rewrites, self_hash).await?)
-    } else if path.is_symlink() {
+    } else if metadata.is_symlink() {
         Some(hash_symlink::<H, _>(path, rewrites, self_hash).await?)
     } else {
         None
@@ -149,7 +155,7 @@ where
     Ok(hash.to_vec())
 }
 
-// TODO: hash symlink
+/// the target is hashed like the content of a file, rewrites are applied to the link
 async fn hash_symlink<H, P>(
     path: P,
     rewrites: &HashMap<StorePath, StorePath>,
@@ -159,13 +165,44 @@ where
     H: Digest,
     P: AsRef<Path>,
 {
-    let target = fs::read_link(path).await?;
-    let buff = target.as_os_str().as_encoded_bytes();
-    #[allow(clippy::never_loop)]
-    for (_i, _hash) in search_rewrites(buff, rewrites, self_hash) {
-        unimplemented!()
+    let path = path.as_ref();
+    let mut target = fs::read_link(path).await?.into_os_string().into_vec();
+    let mut rewritten = false;
+    for (i, hash) in search_rewrites(&target, rewrites, self_hash) {
+        if self_hash != Some(hash) {
+            rewrite_hash(&mut target, i, hash);
+            rewritten = true;
+        }
+    }
+    if rewritten {
+        fs::remove_file(path).await?;
+        fs::symlink(OsStr::from_bytes(&target), path).await?;
     }
-    Ok(H::digest(buff).to_vec())
+    Ok(hash_symlink_target::<H>(&target, self_hash))
+}
+
+/// the hash of a symlink pointing to `target`
+/// occurrences of `self_hash` are zeroed and their offsets are appended to the hash
+/// so that a link without them is hashed as its target
+pub(crate) fn hash_symlink_target<H>(target: &[u8], self_hash: Option<&StorePath>) -> Vec<u8>
+where
+    H: Digest,
+{
+    let modulos = match self_hash {
+        Some(self_hash) => search_self_hash(target, self_hash),
+        None => Vec::new(),
+    };
+    let mut target = target.to_vec();
+    for &i in &modulos {
+        zeroo_hash(&mut target, i);
+    }
+    let mut hasher = H::new();
+    hasher.update(target);
+    for modulo in modulos {
+        hasher.update(u64::MAX.to_be_bytes());
+        hasher.update((modulo as u64).to_be_bytes());
+    }
+    hasher.finalize().to_vec()
 }
 
 #[inline]
@@ -187,13 +224,15 @@ where
     H: Digest,
     P: AsRef<Path>,
 {
-    // this is usually the case in fixed-output derivations which have write perm disabled
-    let reader = if rewrites.is_empty() && self_hash.is_none() {
-        OpenOptions::new().read(true).open(path).await?
+    let path = path.as_ref();
+    let reader = OpenOptions::new().read(true).open(path).await?;
+    // fixed-output derivations have write perm disabled, they never have rewrites
+    // the writer is not a clone of the reader so that seeking it does not move the reader
+    let mut writer = if rewrites.is_empty() {
+        None
     } else {
-        OpenOptions::new().read(true).write(true).open(path).await?
+        Some(OpenOptions::new().write(true).open(path).await?)
     };
-    let mut writer = reader.try_clone().await?;
 
     let mut hasher = H::new();
     let mut modulos = Vec::new();
@@ -205,6 +244,7 @@ where
             let absolute_pos = chunk.chunk_offset() + i as u64;
             if let Some(rewrite) = rewrites.get(hash) {
                 rewrite_hash(chunk.chunk(), i, rewrite);
+                let writer = writer.as_mut().unwrap();
                 writer.seek(SeekFrom::Start(absolute_pos)).await?;
                 writer.write_all(rewrite.hash_bytes()).await?;
             } else if let Some(self_hash) = self_hash {
@@ -218,6 +258,9 @@ where
         hasher.update(lhs);
         remaining = rhs.to_vec();
     }
+    if let Some(mut writer) = writer {
+        writer.flush().await?;
+    }
     hasher.update(remaining);
 
     hasher.update(u64::MAX.to_be_bytes());
@@ -2,7 +2,13 @@ use super::utils::is_valid_hash_char;
 use crate::hash::{rewrite_hash, search_rewrites, utils::ChunkReader};
 use anyhow::{Result, bail};
 use oxide_core::store::{HASH_PART_LEN, HashPart, StorePath};
-use std::{collections::HashMap, io::SeekFrom, path::Path};
+use std::{
+    collections::HashMap,
+    ffi::OsStr,
+    io::SeekFrom,
+    os::unix::ffi::{OsStrExt, OsStringExt},
+    path::Path,
+};
 use tokio::{
     fs::{self, OpenOptions},
     io::{AsyncSeekExt, AsyncWriteExt},
@@ -41,11 +47,12 @@ where
     P: AsRef<Path>,
 {
     let path = path.as_ref();
-    Ok(if path.is_dir() {
+    let metadata = fs::symlink_metadata(path).await?;
+    Ok(if metadata.is_dir() {
         Some(rewrite_dir(path, self_hash, rewrite).await?)
-    } else if path.is_file() {
+    } else if metadata.is_file() {
         Some(rewrite_file(path, self_hash, rewrite).await?)
-    } else if path.is_symlink() {
+    } else if metadata.is_symlink() {
         Some(rewrite_symlink(path, self_hash, rewrite).await?)
     } else {
         None
@@ -64,17 +71,22 @@ where
     Ok(())
 }
 
-// TODO: rewrite symlink
-async fn rewrite_symlink<P>(path: P, self_hash: &StorePath, _rewrite: &StorePath) -> Result<()>
+/// symlinks cannot be modified, the link is replaced if its target changes
+async fn rewrite_symlink<P>(path: P, self_hash: &StorePath, rewrite: &StorePath) -> Result<()>
 where
     P: AsRef<Path>,
 {
-    let target = fs::read_link(path).await?;
-    let buff = target.as_os_str().as_encoded_bytes();
-    #[allow(clippy::never_loop)]
-    for _i in search_self_hash(buff, self_hash) {
-        unimplemented!()
+    let path = path.as_ref();
+    let mut target = fs::read_link(path).await?.into_os_string().into_vec();
+    let occ = search_self_hash(&target, self_hash);
+    if occ.is_empty() {
+        return Ok(());
+    }
+    for i in occ {
+        rewrite_hash(&mut target, i, rewrite);
     }
+    fs::remove_file(path).await?;
+    fs::symlink(OsStr::from_bytes(&target), path).await?;
     Ok(())
 }
 
@@ -82,12 +94,9 @@ async fn rewrite_file<P>(path: P, self_hash: &StorePath, rewrite: &StorePath) ->
 where
     P: AsRef<Path>,
 {
-    let reader = OpenOptions::new()
-        .read(true)
-        .write(true)
-        .open(&path)
-        .await?;
-    let mut writer = reader.try_clone().await?;
+    let reader = OpenOptions::new().read(true).open(&path).await?;
+    // not a clone of the reader so that seeking it does not move the reader
+    let mut writer = OpenOptions::new().write(true).open(&path).await?;
 
     let mut reader = ChunkReader::new(reader);
     while let Some(mut chunk) = reader.next().await? {
@@ -97,11 +106,12 @@ where
             writer.write_all(rewrite.hash_bytes()).await?;
         }
     }
+    writer.flush().await?;
 
     Ok(())
 }
 
-fn search_self_hash(buff: &[u8], self_hash: &StorePath) -> Vec<usize> {
+pub(crate) fn search_self_hash(buff: &[u8], self_hash: &StorePath) -> Vec<usize> {
     let mut i = 0;
     let mut occ = Vec::new();
     'outer: while i + HASH_PART_LEN <= buff.len() {
@@ -1,7 +1,9 @@
+use crate::hash::search_self_hash;
 use crate::hash::utils::ChunkReader;
+use crate::types::HashOccurrence;
 use anyhow::{Result, bail};
 use oxide_core::store::{HASH_PART_LEN, HashPart, StorePath};
-use std::collections::HashSet;
+use std::collections::{BTreeMap, HashSet};
 use std::path::Path;
 use tokio::fs::{self, File};
 
@@ -15,75 +17,67 @@ where
     P: AsRef<Path>,
 {
     let mut res = HashSet::new();
-    if scan_root(path, &mut hashes, &mut res).await?.is_none() {
+    let mut search = |_: &Path, _: u64, buff: &[u8]| search_refs(buff, &mut hashes, &mut res);
+    if scan_root(path, &mut search).await?.is_none() {
         bail!("unknown file type");
     }
     Ok(res)
 }
 
-async fn scan_root<P>(
-    path: P,
-    hashes: &mut HashSet<StorePath>,
-    res: &mut HashSet<StorePath>,
-) -> Result<Option<()>>
+/// call `search` with the content of every file and symlink target inside of `path`,
+/// with the file it comes from and its offset in it, directory entries are visited in order
+async fn scan_root<P, F>(path: P, search: &mut F) -> Result<Option<()>>
 where
     P: AsRef<Path>,
+    F: FnMut(&Path, u64, &[u8]),
 {
     let path = path.as_ref();
-    Ok(if path.is_dir() {
-        Some(scan_dir(path, hashes, res).await?)
-    } else if path.is_file() {
-        Some(scan_file(path, hashes, res).await?)
-    } else if path.is_symlink() {
-        Some(scan_symlink(path, hashes, res).await?)
+    let metadata = fs::symlink_metadata(path).await?;
+    Ok(if metadata.is_dir() {
+        Some(scan_dir(path, search).await?)
+    } else if metadata.is_file() {
+        Some(scan_file(path, search).await?)
+    } else if metadata.is_symlink() {
+        Some(scan_symlink(path, search).await?)
     } else {
         None
     })
 }
 
-async fn scan_dir<P>(
-    path: P,
-    hashes: &mut HashSet<StorePath>,
-    res: &mut HashSet<StorePath>,
-) -> Result<()>
+async fn scan_dir<F>(path: &Path, search: &mut F) -> Result<()>
 where
-    P: AsRef<Path>,
+    F: FnMut(&Path, u64, &[u8]),
 {
-    let mut entries = fs::read_dir(&path).await?;
+    let mut entries = fs::read_dir(path).await?;
+    let mut sorted_entries = BTreeMap::new();
     while let Some(entry) = entries.next_entry().await? {
-        let path = entry.path();
-        _ = Box::pin(scan_root(path, hashes, res)).await?;
+        sorted_entries.insert(entry.file_name(), entry.path());
+    }
+    for path in sorted_entries.into_values() {
+        _ = Box::pin(scan_root(path, search)).await?;
     }
     Ok(())
 }
 
-async fn scan_symlink<P>(
-    path: P,
-    hashes: &mut HashSet<StorePath>,
-    res: &mut HashSet<StorePath>,
-) -> Result<()>
+async fn scan_symlink<F>(path: &Path, search: &mut F) -> Result<()>
 where
-    P: AsRef<Path>,
+    F: FnMut(&Path, u64, &[u8]),
 {
     let target = fs::read_link(path).await?;
-    let buff = target.as_os_str().as_encoded_bytes();
-    search_refs(buff, hashes, res);
+    search(path, 0, target.as_os_str().as_encoded_bytes());
     Ok(())
 }
 
-async fn scan_file<P>(
-    path: P,
-    hashes: &mut HashSet<StorePath>,
-    res: &mut HashSet<StorePath>,
-) -> Result<()>
+async fn scan_file<F>(path: &Path, search: &mut F) -> Result<()>
 where
-    P: AsRef<Path>,
+    F: FnMut(&Path, u64, &[u8]),
 {
     let file = File::open(path).await?;
 
     let mut reader = ChunkReader::new(file);
     while let Some(mut chunk) = reader.next().await? {
-        search_refs(chunk.chunk(), hashes, res);
+        let offset = chunk.chunk_offset();
+        search(path, offset, chunk.chunk());
     }
     Ok(())
 }
@@ -110,3 +104,42 @@ fn search_refs(buff: &[u8], hashes: &mut HashSet<StorePath>, res: &mut HashSet<S
         i += 1;
     }
 }
+
+/// number of bytes shown before and after an occurrence
+const CONTEXT_LEN: usize = 32;
+
+/// find every occurrence of the hash part of `hash` inside of `path`
+pub(crate) async fn scan_for_hash<P>(path: P, hash: &StorePath) -> Result<Vec<HashOccurrence>>
+where
+    P: AsRef<Path>,
+{
+    let mut res = Vec::new();
+    let mut search = |file: &Path, offset: u64, buff: &[u8]| {
+        for i in search_self_hash(buff, hash) {
+            res.push(HashOccurrence {
+                file: file.to_path_buf(),
+                offset: offset + i as u64,
+                context: context(buff, i),
+            });
+        }
+    };
+    if scan_root(path, &mut search).await?.is_none() {
+        bail!("unknown file type");
+    }
+    Ok(res)
+}
+
+fn context(buff: &[u8], i: usize) -> String {
+    let start = i.saturating_sub(CONTEXT_LEN);
+    let end = (i + HASH_PART_LEN + CONTEXT_LEN).min(buff.len());
+    buff[start..end]
+        .iter()
+        .map(|&b| {
+            if b.is_ascii_graphic() || b == b' ' {
+                b as char
+            } else {
+                '.'
+            }
+        })
+        .collect()
+}
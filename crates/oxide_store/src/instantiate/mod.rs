@@ -9,7 +9,7 @@ use oxide_core::{
     expr::Expr,
     hash::{Hash, HashAlgo},
     store::StorePath,
-    types::Out,
+    types::{EqClass, Out},
     utils::file_name,
 };
 use sha2::{Digest, Sha512};
@@ -124,20 +124,7 @@ where
     };
     let drv_hash = hash_drv(store, d.clone()).await?;
     // actual eq_classes
-    d.eq_classes = drv
-        .outputs
-        .into_iter()
-        .enumerate()
-        .map(|(i, out)| {
-            let name = if i == 0 {
-                drv.name.as_ref()
-            } else {
-                &format!("{}-{}", drv.name, out)
-            };
-            let eq_class = make_path(&drv_hash, name);
-            (out.into_owned(), eq_class)
-        })
-        .collect();
+    d.eq_classes = make_eq_classes(&drv_hash, &drv.name, drv.outputs.iter().map(AsRef::as_ref));
     // actual (output, eq_class) in envs
     d.envs.extend(
         d.eq_classes
@@ -154,12 +141,69 @@ where
                 name: drv.name.into_owned() + DRV_EXT,
                 rewrites: HashMap::new(),
                 self_hash: None,
+                deriver: None,
             },
         )
         .await?;
     Ok((d, p))
 }
 
+/// the first output is named after the derivation and the others after the output too
+fn make_eq_classes<'a, I>(drv_hash: &Hash, name: &str, outputs: I) -> BTreeMap<Out, EqClass>
+where
+    I: IntoIterator<Item = &'a str>,
+{
+    outputs
+        .into_iter()
+        .enumerate()
+        .map(|(i, out)| {
+            let eq_class = if i == 0 {
+                make_path(drv_hash, name)
+            } else {
+                make_path(drv_hash, &format!("{name}-{out}"))
+            };
+            (out.to_string(), eq_class)
+        })
+        .collect()
+}
+
+/// check that `p` and every derivation it depends on have the equivalence classes
+/// that [`instantiate`] would give them, since the outputs built for an equivalence class
+/// are trusted by everyone using the store
+pub async fn verify_drv<S>(store: &S, p: &StorePath) -> Result<()>
+where
+    S: Store,
+{
+    let mut todo = vec![p.clone()];
+    let mut seen = HashSet::new();
+    while let Some(p) = todo.pop() {
+        if !seen.insert(p.clone()) {
+            continue;
+        }
+        let drv = store.read_drv(&p).await?;
+        todo.extend(drv.input_drvs.keys().cloned());
+        let (Some(name), Some(outputs)) = (drv.envs.get(NAME_KEY), drv.envs.get(OUTPUTS_KEY))
+        else {
+            bail!("invalid derivation {p}");
+        };
+        // the derivation as it was hashed by instantiate
+        let mut masked = drv.clone();
+        for (out, eq_class) in &mut masked.eq_classes {
+            *eq_class = unsafe { StorePath::empty() };
+            masked.envs.insert(out.clone(), String::new());
+        }
+        let drv_hash = hash_drv(store, masked).await?;
+        let eq_classes = make_eq_classes(&drv_hash, name, outputs.split(' '));
+        let envs_match = eq_classes
+            .iter()
+            .all(|(out, eq_class)| drv.envs.get(out) == Some(&S::store_path(eq_class)));
+        if eq_classes != drv.eq_classes || !envs_match {
+            bail!("the equivalence classes of {p} do not match its content");
+        }
+    }
+    Ok(())
+}
+
 fn default_envs(drv: &Drv) -> BTreeMap<String, String> {
     // default envs
     // no need to add builder and args because they are added later
@@ -280,6 +324,7 @@ where
                         name: file_name(&path),
                         rewrites: HashMap::new(),
                         self_hash: None,
+                        deriver: None,
                     },
                 )
                 .await?;
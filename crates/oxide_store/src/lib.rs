@@ -1,9 +1,16 @@
 pub mod api;
+pub mod archive;
 pub mod build;
 pub mod builtins;
+pub mod export;
 pub(crate) mod hash;
 pub mod instantiate;
 pub(crate) mod os;
+pub mod protocol;
+pub mod signing;
 pub mod stores;
 pub mod types;
 pub mod utils;
+pub mod why_depends;
+
+pub use os::users;
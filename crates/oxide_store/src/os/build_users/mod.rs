@@ -0,0 +1,135 @@
+//! Builds running as root get a uid of their own, see `build_users_group` in the config
+
+use super::{
+    lock::{LockMode, PathLock},
+    users::{group_by_name, user_by_name},
+    utils::errno,
+};
+use crate::api::CONFIG;
+use anyhow::{Result, bail};
+use libc::{gid_t, uid_t};
+use log::info;
+use std::{os::unix::fs::MetadataExt, path::Path, ptr, time::Duration};
+use tokio::{fs, time::sleep};
+
+/// how often to look for a free uid when all of them are in use
+const RETRY_INTERVAL: Duration = Duration::from_secs(1);
+
+/// builds without a sandbox write their outputs to the store dir as the build user,
+/// if other users can write it the sticky bit keeps them from removing each others' paths
+pub async fn check_store_dir(store_dir: &str) -> Result<()> {
+    if CONFIG.build_users_group.is_none() || unsafe { libc::geteuid() } != 0 {
+        return Ok(());
+    }
+    let mode = fs::metadata(store_dir).await?.mode();
+    if mode & 0o022 != 0 && mode & libc::S_ISVTX == 0 {
+        bail!("the store dir {store_dir} is writable by other users but not sticky");
+    }
+    Ok(())
+}
+
+/// A uid used by a single build until it is released
+pub struct BuildUser {
+    pub uid: uid_t,
+    pub gid: gid_t,
+    lock: PathLock,
+}
+
+impl BuildUser {
+    /// `None` if builds run as the current user,
+    /// waits until one of the uids is not used by another build
+    pub async fn acquire() -> Result<Option<Self>> {
+        let Some(group) = CONFIG.build_users_group.clone() else {
+            return Ok(None);
+        };
+        if unsafe { libc::geteuid() } != 0 {
+            return Ok(None);
+        }
+        let Some((gid, members)) = group_by_name(&group) else {
+            bail!("build users group {group} does not exist");
+        };
+        let uids = if CONFIG.auto_allocate_uids {
+            (0..CONFIG.id_count).map(|i| CONFIG.start_id + i).collect()
+        } else {
+            let mut uids = Vec::new();
+            for member in members {
+                match user_by_name(&member) {
+                    Some(0) => bail!("build user {member} cannot be root"),
+                    Some(uid) => uids.push(uid),
+                    None => bail!("build user {member} does not exist"),
+                }
+            }
+            uids
+        };
+        if uids.is_empty() {
+            bail!("build users group {group} has no members");
+        }
+
+        // every uid has a lock file that is held while a build runs as it
+        let pool_dir = format!("{}/userpool", CONFIG.state_dir);
+        fs::create_dir_all(&pool_dir).await?;
+        let mut waiting = false;
+        loop {
+            for &uid in &uids {
+                let lock_path = Path::new(&pool_dir).join(uid.to_string());
+                if let Some(lock) = PathLock::try_lock(lock_path, LockMode::Write)? {
+                    return Ok(Some(Self { uid, gid, lock }));
+                }
+            }
+            if !waiting {
+                info!("waiting for a free build user");
+                waiting = true;
+            }
+            sleep(RETRY_INTERVAL).await;
+        }
+    }
+
+    /// switch the calling process to this user, meant for a child process after fork
+    pub fn switch_to(&self) -> bool {
+        // drop the supplementary groups first, they cannot be changed after setuid
+        unsafe {
+            libc::setgroups(0, ptr::null()) == 0
+                && libc::setgid(self.gid) == 0
+                && libc::setuid(self.uid) == 0
+        }
+    }
+
+    /// kill every process running as this user, like the ones left behind by a build
+    pub fn kill_processes(&self) -> Result<()> {
+        // kill(-1) from a process running as the user reaches every process of the user
+        let pid = unsafe { libc::fork() };
+        if pid == 0 {
+            unsafe {
+                if libc::setuid(self.uid) != 0 {
+                    libc::_exit(1);
+                }
+                // kill(-1) succeeds as long as any other process exists, a process
+                // forked while it ran is only killed by the next one,
+                // ESRCH and EPERM both mean that nothing was left to kill
+                loop {
+                    if libc::kill(-1, libc::SIGKILL) == 0 {
+                        continue;
+                    }
+                    match errno() {
+                        libc::EINTR => {}
+                        libc::ESRCH | libc::EPERM => libc::_exit(0),
+                        _ => libc::_exit(1),
+                    }
+                }
+            }
+        } else if pid == -1 {
+            bail!("unable to fork process");
+        }
+        let mut status = 0 as libc::c_int;
+        unsafe { libc::waitpid(pid, &raw mut status, 0) };
+        if !libc::WIFEXITED(status) || libc::WEXITSTATUS(status) != 0 {
+            bail!("could not kill the processes of build user {}", self.uid);
+        }
+        Ok(())
+    }
+
+    /// let other builds use this uid
+    pub fn release(self) {
+        self.lock.unlock();
+    }
+}
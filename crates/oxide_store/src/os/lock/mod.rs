@@ -1,5 +1,6 @@
 use anyhow::{Result, bail};
 use std::{ffi::CString, mem, os::unix::ffi::OsStrExt, path::Path};
+use tokio::task;
 
 #[derive(Clone, Copy, PartialEq, Eq, Hash)]
 #[repr(u8)]
@@ -15,6 +16,7 @@ pub enum LockMode {
 pub struct PathLock {
     fd: libc::c_int,
     path: CString,
+    mode: LockMode,
 }
 
 impl PathLock {
@@ -22,27 +24,62 @@ impl PathLock {
     where
         P: AsRef<Path>,
     {
-        let p = p.as_ref();
+        Ok(Self::lock_impl(p.as_ref(), mode, true)?.unwrap())
+    }
+
+    /// like [`PathLock::lock`] but returns `None` if the lock is held by someone else
+    pub fn try_lock<P>(p: P, mode: LockMode) -> Result<Option<Self>>
+    where
+        P: AsRef<Path>,
+    {
+        Self::lock_impl(p.as_ref(), mode, false)
+    }
+
+    /// like [`PathLock::lock`] but waits on the blocking thread pool
+    /// so that async code is not blocked while someone else holds the lock
+    pub async fn lock_async<P>(p: P, mode: LockMode) -> Result<Self>
+    where
+        P: AsRef<Path>,
+    {
+        if let Some(lock) = Self::try_lock(&p, mode)? {
+            return Ok(lock);
+        }
+        let p = p.as_ref().to_path_buf();
+        task::spawn_blocking(move || Self::lock(p, mode)).await?
+    }
+
+    fn lock_impl(p: &Path, mode: LockMode, block: bool) -> Result<Option<Self>> {
         loop {
             let (fd, path) = PathLock::open_lock(p)?;
-            let mode = match mode {
+            let mut op = match mode {
                 LockMode::Read => libc::LOCK_SH,
                 LockMode::Write => libc::LOCK_EX,
                 LockMode::UnLock => libc::LOCK_UN,
             };
-            if unsafe { libc::flock(fd, mode) } != 0 {
-                bail!("could not lock file {:?}", p)
+            if !block {
+                op |= libc::LOCK_NB;
+            }
+            if unsafe { libc::flock(fd, op) } != 0 {
+                let err = std::io::Error::last_os_error();
+                unsafe { libc::close(fd) };
+                if !block && err.kind() == std::io::ErrorKind::WouldBlock {
+                    return Ok(None);
+                }
+                bail!("could not lock file {}: {err}", p.display())
             }
             let mut stat = mem::MaybeUninit::<libc::stat>::uninit();
             if unsafe { libc::fstat(fd, stat.as_mut_ptr()) } != 0 {
-                bail!("cloud not fstat lock file {:?}:", p)
+                let err = std::io::Error::last_os_error();
+                unsafe { libc::close(fd) };
+                bail!("could not fstat lock file {}: {err}", p.display())
             }
             let stat = unsafe { stat.assume_init() };
             if stat.st_size != 0 {
-                // stale lock
+                // stale lock, it was deleted by its owner and the next open creates a new one
+                unsafe { libc::close(fd) };
                 continue;
             }
-            return Ok(PathLock { fd, path });
+            return Ok(Some(PathLock { fd, path, mode }));
         }
     }
 
@@ -60,8 +97,8 @@ impl PathLock {
                 666,
             )
         };
-        if fd == 0 {
-            bail!("could not open lock file {:?}", p)
+        if fd < 0 {
+            bail!("could not open lock file {}", p.display())
         }
         Ok((fd, path))
     }
@@ -73,8 +110,11 @@ impl PathLock {
 
     fn unlock_ref(&self) {
         unsafe {
-            libc::unlink(self.path.as_ptr());
-            libc::write(self.fd, b" ".as_ptr().cast::<libc::c_void>(), 1);
+            // other readers may still hold the file, only a writer knows it is alone
+            if self.mode == LockMode::Write {
+                libc::unlink(self.path.as_ptr());
+                libc::write(self.fd, b" ".as_ptr().cast::<libc::c_void>(), 1);
+            }
             libc::close(self.fd);
         }
     }
@@ -1,3 +1,6 @@
+pub mod build_users;
 pub mod lock;
 pub mod sandbox;
+pub mod seccomp;
+pub mod users;
 pub mod utils;
@@ -1,11 +1,511 @@
-use anyhow::Result;
-use std::path::Path;
+//! Builders run in new user, mount, pid, network, ipc and uts namespaces
+//! and only see their inputs, the build dir, the outputs and a minimal `/dev`, `/proc` and `/etc`,
+//! fixed-output derivations keep the network of the host
 
-// TODO: actually sandbox
-#[allow(clippy::unnecessary_wraps)]
-pub fn prepare_sandbox<P>(_p: P) -> Result<()>
+use super::{
+    build_users::BuildUser,
+    utils::{ChildError, die_with_parent},
+};
+use crate::api::CONFIG;
+use anyhow::{Result, bail};
+use log::warn;
+use oxide_core::store::config::SandboxMode;
+use std::{
+    ffi::{CStr, CString},
+    os::unix::ffi::OsStrExt,
+    path::{Path, PathBuf},
+    ptr,
+    sync::OnceLock,
+};
+use tokio::fs;
+
+pub const SANDBOX_BUILD_DIR: &str = "/build";
+
+/// uid and gid of the builder inside of the sandbox
+const SANDBOX_UID: u32 = 1000;
+const SANDBOX_GID: u32 = 100;
+
+const NAMESPACES: libc::c_int = libc::CLONE_NEWUSER
+    | libc::CLONE_NEWNS
+    | libc::CLONE_NEWPID
+    | libc::CLONE_NEWNET
+    | libc::CLONE_NEWIPC
+    | libc::CLONE_NEWUTS;
+
+/// devices of the host available in the sandbox
+const DEVICES: &[&str] = &[
+    "/dev/full",
+    "/dev/null",
+    "/dev/random",
+    "/dev/tty",
+    "/dev/urandom",
+    "/dev/zero",
+];
+
+/// files of the host needed to use the network
+const NETWORK_FILES: &[&str] = &["/etc/resolv.conf", "/etc/services", "/etc/hosts"];
+
+const HOSTNAME: &str = "localhost";
+
+#[allow(clippy::cast_possible_truncation)]
+const LOOPBACK_FLAGS: libc::c_short =
+    (libc::IFF_UP | libc::IFF_LOOPBACK | libc::IFF_RUNNING) as libc::c_short;
+
+/// whether builds run in a sandbox, `user` is the build user the builder will run as
+pub fn use_sandbox(user: Option<&BuildUser>) -> Result<bool> {
+    match CONFIG.sandbox {
+        SandboxMode::Enabled => {
+            if !supported(user) {
+                bail!(
+                    "this system does not support user namespaces needed by the sandbox, \
+                     set OXIDE_SANDBOX to false or relaxed to build without it"
+                );
+            }
+            Ok(true)
+        }
+        SandboxMode::Disabled => Ok(false),
+        SandboxMode::Relaxed => {
+            let supported = supported(user);
+            if !supported {
+                warn!("this system does not support the sandbox, building without it");
+            }
+            Ok(supported)
+        }
+    }
+}
+
+/// whether the namespaces can be created, checked once in a child process
+fn supported(user: Option<&BuildUser>) -> bool {
+    static SUPPORTED: OnceLock<bool> = OnceLock::new();
+    *SUPPORTED.get_or_init(|| {
+        let pid = unsafe { libc::fork() };
+        if pid == 0 {
+            unsafe {
+                if user.is_some_and(|user| !user.switch_to()) {
+                    libc::_exit(1);
+                }
+                libc::_exit(i32::from(libc::unshare(NAMESPACES) != 0));
+            }
+        } else if pid == -1 {
+            return false;
+        }
+        let mut status = 0 as libc::c_int;
+        unsafe { libc::waitpid(pid, &raw mut status, 0) };
+        libc::WIFEXITED(status) && libc::WEXITSTATUS(status) == 0
+    })
+}
+
+struct Mount {
+    source: CString,
+    target: CString,
+    read_only: bool,
+    error: String,
+    stat_error: String,
+}
+
+/// The filesystem of a build, `root` becomes `/` for the builder
+///
+/// everything is prepared before forking so the child does not have to allocate
+pub struct Sandbox {
+    root: PathBuf,
+    c_root: CString,
+    proc_dir: CString,
+    shm_dir: CString,
+    build_dir: CString,
+    mounts: Vec<Mount>,
+    uid_map: String,
+    gid_map: String,
+    /// whether the builder uses the network namespace of the host
+    network: bool,
+}
+
+impl Sandbox {
+    /// `inputs` are the full paths in the store the builder can read,
+    /// `uid` and `gid` are the ids the builder runs as outside of the sandbox
+    pub async fn new<P>(
+        root: P,
+        inputs: &[String],
+        uid: u32,
+        gid: u32,
+        network: bool,
+    ) -> Result<Self>
+    where
+        P: AsRef<Path>,
+    {
+        let root = root.as_ref().to_path_buf();
+        let mut mounts = Vec::new();
+        for input in inputs {
+            Self::add_mount(&root, &mut mounts, input, input, true).await?;
+        }
+        for path in &CONFIG.sandbox_paths {
+            let (target, source) = path.split_once('=').unwrap_or((path, path));
+            Self::add_mount(&root, &mut mounts, source, target, true).await?;
+        }
+
+        // outputs are created here and moved to the store after the build
+        fs::create_dir_all(inner_path(&root, &CONFIG.store_dir)).await?;
+
+        let dev = root.join("dev");
+        fs::create_dir_all(dev.join("shm")).await?;
+        for device in DEVICES {
+            Self::add_mount(&root, &mut mounts, device, device, false).await?;
+        }
+        fs::symlink("/proc/self/fd", dev.join("fd")).await?;
+        fs::symlink("/proc/self/fd/0", dev.join("stdin")).await?;
+        fs::symlink("/proc/self/fd/1", dev.join("stdout")).await?;
+        fs::symlink("/proc/self/fd/2", dev.join("stderr")).await?;
+        fs::create_dir(root.join("proc")).await?;
+
+        let etc = root.join("etc");
+        fs::create_dir(&etc).await?;
+        fs::write(
+            etc.join("passwd"),
+            format!(
+                "root:x:0:0:root:{SANDBOX_BUILD_DIR}:/noshell\n\
+                 oxidebld:x:{SANDBOX_UID}:{SANDBOX_GID}:build user:{SANDBOX_BUILD_DIR}:/noshell\n\
+                 nobody:x:65534:65534:nobody:/:/noshell\n"
+            ),
+        )
+        .await?;
+        fs::write(
+            etc.join("group"),
+            format!("root:x:0:\noxidebld:x:{SANDBOX_GID}:\nnogroup:x:65534:\n"),
+        )
+        .await?;
+        if network {
+            // copied since they are often symlinks to somewhere else
+            for file in NETWORK_FILES {
+                if fs::metadata(file).await.is_ok() {
+                    fs::copy(file, inner_path(&root, file)).await?;
+                }
+            }
+        } else {
+            fs::write(
+                etc.join("hosts"),
+                format!("127.0.0.1 {HOSTNAME}\n::1 {HOSTNAME}\n"),
+            )
+            .await?;
+        }
+
+        Ok(Self {
+            c_root: path_to_cstring(&root)?,
+            proc_dir: path_to_cstring(root.join("proc"))?,
+            shm_dir: path_to_cstring(dev.join("shm"))?,
+            build_dir: CString::new(SANDBOX_BUILD_DIR)?,
+            root,
+            mounts,
+            uid_map: format!("{SANDBOX_UID} {uid} 1"),
+            gid_map: format!("{SANDBOX_GID} {gid} 1"),
+            network,
+        })
+    }
+
+    /// make `source` of the host visible at `target` inside of the sandbox
+    async fn add_mount(
+        root: &Path,
+        mounts: &mut Vec<Mount>,
+        source: &str,
+        target: &str,
+        read_only: bool,
+    ) -> Result<()> {
+        let inner = inner_path(root, target);
+        if let Some(parent) = inner.parent() {
+            fs::create_dir_all(parent).await?;
+        }
+        let metadata = fs::symlink_metadata(source).await?;
+        if metadata.is_symlink() {
+            // a bind mount would follow the link
+            fs::symlink(fs::read_link(source).await?, inner).await?;
+            return Ok(());
+        }
+        if metadata.is_dir() {
+            fs::create_dir(&inner).await?;
+        } else {
+            fs::File::create(&inner).await?;
+        }
+        mounts.push(Mount {
+            error: format!("could not mount {}", inner.display()),
+            stat_error: format!("could not stat {source}"),
+            source: CString::new(source)?,
+            target: path_to_cstring(inner)?,
+            read_only,
+        });
+        Ok(())
+    }
+
+    /// called in the child before running the builder,
+    /// it returns in a new process that is the first process of the namespaces
+    /// and has the root of the sandbox as `/` and the build dir as working directory,
+    /// it cannot allocate so every error message is made by `new`
+    ///
+    /// with `interactive` the process waiting outside of the namespaces ignores ctrl-c
+    pub fn enter(&self, interactive: bool) -> Result<(), ChildError<'_>> {
+        unsafe {
+            let namespaces = if self.network {
+                NAMESPACES & !libc::CLONE_NEWNET
+            } else {
+                NAMESPACES
+            };
+            let parent = libc::getppid();
+            if libc::unshare(namespaces) != 0 {
+                return Err(ChildError::last("could not create namespaces"));
+            }
+            // entering the user namespace changes the credentials
+            die_with_parent(parent);
+            // switching to a build user makes /proc/self owned by root
+            libc::prctl(libc::PR_SET_DUMPABLE, 1);
+            write_file(
+                c"/proc/self/setgroups",
+                b"deny",
+                "could not write /proc/self/setgroups",
+            )?;
+            write_file(
+                c"/proc/self/uid_map",
+                self.uid_map.as_bytes(),
+                "could not write /proc/self/uid_map",
+            )?;
+            write_file(
+                c"/proc/self/gid_map",
+                self.gid_map.as_bytes(),
+                "could not write /proc/self/gid_map",
+            )?;
+
+            // the parent stays outside of the pid namespace so the init cannot
+            // use getppid() to see it exit, it notices the pipe closing instead
+            let mut fds = [0 as libc::c_int; 2];
+            if libc::pipe2(fds.as_mut_ptr(), libc::O_CLOEXEC) != 0 {
+                return Err(ChildError::last("could not create a pipe"));
+            }
+            let [read_fd, write_fd] = fds;
+            // only the children are in the new pid namespace
+            let pid = libc::fork();
+            if pid == -1 {
+                return Err(ChildError::last("unable to fork process"));
+            } else if pid != 0 {
+                libc::close(read_fd);
+                if interactive {
+                    // a debug shell shares the terminal, ctrl-c is not meant for this process
+                    libc::signal(libc::SIGINT, libc::SIG_IGN);
+                    libc::signal(libc::SIGQUIT, libc::SIG_IGN);
+                }
+                let mut status = 0 as libc::c_int;
+                libc::waitpid(pid, &raw mut status, 0);
+                if libc::WIFEXITED(status) {
+                    libc::_exit(libc::WEXITSTATUS(status));
+                }
+                // die the same way so the parent sees the signal
+                let sig = libc::WTERMSIG(status);
+                libc::signal(sig, libc::SIG_DFL);
+                libc::raise(sig);
+                libc::_exit(128 + sig);
+            }
+            // the whole namespace dies with its init if the build is cancelled
+            libc::prctl(libc::PR_SET_PDEATHSIG, libc::SIGKILL);
+            libc::close(write_fd);
+            let mut pfd = libc::pollfd {
+                fd: read_fd,
+                events: 0,
+                revents: 0,
+            };
+            if libc::poll(&raw mut pfd, 1, 0) > 0 {
+                libc::_exit(1);
+            }
+            libc::close(read_fd);
+        }
+        self.setup()
+    }
+
+    fn setup(&self) -> Result<(), ChildError<'_>> {
+        unsafe {
+            // do not propagate the mounts to the host
+            mount(
+                None,
+                c"/",
+                None,
+                libc::MS_REC | libc::MS_PRIVATE,
+                None,
+                "could not make the mounts private",
+            )?;
+            // pivot_root needs the new root to be a mount point
+            mount(
+                Some(&self.c_root),
+                &self.c_root,
+                None,
+                libc::MS_BIND,
+                None,
+                "could not mount the root of the sandbox",
+            )?;
+            for m in &self.mounts {
+                bind(m)?;
+            }
+            mount(
+                Some(c"proc"),
+                &self.proc_dir,
+                Some(c"proc"),
+                libc::MS_NOSUID | libc::MS_NODEV | libc::MS_NOEXEC,
+                None,
+                "could not mount /proc",
+            )?;
+            mount(
+                Some(c"tmpfs"),
+                &self.shm_dir,
+                Some(c"tmpfs"),
+                libc::MS_NOSUID | libc::MS_NODEV,
+                Some(c"size=50%"),
+                "could not mount /dev/shm",
+            )?;
+            if libc::sethostname(HOSTNAME.as_ptr().cast(), HOSTNAME.len()) != 0 {
+                return Err(ChildError::last("could not set the hostname"));
+            }
+            if !self.network {
+                loopback_up()?;
+            }
+
+            if libc::chdir(self.c_root.as_ptr()) != 0 {
+                return Err(ChildError::last("could not enter the sandbox"));
+            }
+            // stack the new root on top of the old one and detach the old one
+            if libc::syscall(libc::SYS_pivot_root, c".".as_ptr(), c".".as_ptr()) != 0 {
+                return Err(ChildError::last("could not pivot root"));
+            }
+            if libc::umount2(c".".as_ptr(), libc::MNT_DETACH) != 0 {
+                return Err(ChildError::last("could not detach the old root"));
+            }
+            if libc::chdir(self.build_dir.as_ptr()) != 0 {
+                return Err(ChildError::last("could not enter the build dir"));
+            }
+        }
+        Ok(())
+    }
+
+    /// move the outputs the builder created inside of the sandbox to the store
+    pub async fn take_outputs<I, P>(&self, outputs: I) -> Result<()>
+    where
+        I: IntoIterator<Item = P>,
+        P: AsRef<Path>,
+    {
+        for output in outputs {
+            let inner = inner_path(&self.root, output.as_ref());
+            if fs::symlink_metadata(&inner).await.is_ok() {
+                fs::rename(&inner, output).await?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// where the absolute path `p` is inside of `root`
+fn inner_path<P>(root: &Path, p: P) -> PathBuf
+where
+    P: AsRef<Path>,
+{
+    let p = p.as_ref();
+    root.join(p.strip_prefix("/").unwrap_or(p))
+}
+
+fn path_to_cstring<P>(p: P) -> Result<CString>
 where
     P: AsRef<Path>,
 {
+    Ok(CString::new(p.as_ref().as_os_str().as_bytes())?)
+}
+
+unsafe fn mount<'a>(
+    source: Option<&CStr>,
+    target: &CStr,
+    fstype: Option<&CStr>,
+    flags: libc::c_ulong,
+    data: Option<&CStr>,
+    error: &'a str,
+) -> Result<(), ChildError<'a>> {
+    let code = unsafe {
+        libc::mount(
+            source.map_or(ptr::null(), CStr::as_ptr),
+            target.as_ptr(),
+            fstype.map_or(ptr::null(), CStr::as_ptr),
+            flags,
+            data.map_or(ptr::null(), |d| d.as_ptr().cast()),
+        )
+    };
+    if code != 0 {
+        return Err(ChildError::last(error));
+    }
+    Ok(())
+}
+
+unsafe fn bind(m: &Mount) -> Result<(), ChildError<'_>> {
+    unsafe {
+        mount(
+            Some(&m.source),
+            &m.target,
+            None,
+            libc::MS_BIND | libc::MS_REC,
+            None,
+            &m.error,
+        )?;
+        if m.read_only {
+            // the flags of the original mount are locked and have to be kept
+            let mut stat: libc::statvfs = std::mem::zeroed();
+            if libc::statvfs(m.source.as_ptr(), &raw mut stat) != 0 {
+                return Err(ChildError::last(&m.stat_error));
+            }
+            let mut flags = libc::MS_BIND | libc::MS_REMOUNT | libc::MS_RDONLY;
+            for (st, ms) in [
+                (libc::ST_NOSUID, libc::MS_NOSUID),
+                (libc::ST_NODEV, libc::MS_NODEV),
+                (libc::ST_NOEXEC, libc::MS_NOEXEC),
+                (libc::ST_NOATIME, libc::MS_NOATIME),
+                (libc::ST_NODIRATIME, libc::MS_NODIRATIME),
+                (libc::ST_RELATIME, libc::MS_RELATIME),
+            ] {
+                if stat.f_flag & st != 0 {
+                    flags |= ms;
+                }
+            }
+            mount(None, &m.target, None, flags, None, &m.error)?;
+        }
+    }
+    Ok(())
+}
+
+unsafe fn write_file<'a>(
+    path: &CStr,
+    content: &[u8],
+    error: &'a str,
+) -> Result<(), ChildError<'a>> {
+    unsafe {
+        let fd = libc::open(path.as_ptr(), libc::O_WRONLY | libc::O_CLOEXEC);
+        if fd == -1 {
+            return Err(ChildError::last(error));
+        }
+        let written = libc::write(fd, content.as_ptr().cast(), content.len());
+        let err = ChildError::last(error);
+        libc::close(fd);
+        if usize::try_from(written).ok() != Some(content.len()) {
+            return Err(err);
+        }
+    }
+    Ok(())
+}
+
+/// the loopback interface of a new network namespace is down
+unsafe fn loopback_up() -> Result<(), ChildError<'static>> {
+    unsafe {
+        let fd = libc::socket(libc::AF_INET, libc::SOCK_DGRAM | libc::SOCK_CLOEXEC, 0);
+        if fd == -1 {
+            return Err(ChildError::last("could not open a socket"));
+        }
+        let mut ifr: libc::ifreq = std::mem::zeroed();
+        for (dst, src) in ifr.ifr_name.iter_mut().zip(b"lo") {
+            *dst = libc::c_char::from_ne_bytes([*src]);
+        }
+        ifr.ifr_ifru.ifru_flags = LOOPBACK_FLAGS;
+        let code = libc::ioctl(fd, libc::SIOCSIFFLAGS, &raw mut ifr);
+        let err = ChildError::last("could not set up the loopback interface");
+        libc::close(fd);
+        if code != 0 {
+            return Err(err);
+        }
+    }
     Ok(())
 }
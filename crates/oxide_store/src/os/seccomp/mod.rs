@@ -0,0 +1,288 @@
+//! A seccomp filter installed in the builder before it is executed,
+//! blocked syscalls fail with `EPERM` so builders can recover from them
+//!
+//! it denies setuid and setgid modes, extended attributes (and so ACLs),
+//! attaching to processes with `ptrace` and syscalls that change the host
+//! like loading kernel modules, setting the clock or using the keyring
+
+use super::utils::ChildError;
+use anyhow::{Result, bail};
+
+// from linux/audit.h
+const AUDIT_ARCH_X86_64: u32 = 0xc000_003e;
+const AUDIT_ARCH_I386: u32 = 0x4000_0003;
+/// set in the syscall number by x32 binaries
+const X32_SYSCALL_BIT: u32 = 0x4000_0000;
+
+// offsets in `seccomp_data`
+const NR_OFFSET: u32 = 0;
+const ARCH_OFFSET: u32 = 4;
+const ARGS_OFFSET: u32 = 16;
+
+const RET_ALLOW: u32 = libc::SECCOMP_RET_ALLOW;
+const RET_EPERM: u32 = libc::SECCOMP_RET_ERRNO | libc::EPERM as u32;
+
+/// mode bits a builder cannot set
+const SET_ID_BITS: u32 = libc::S_ISUID | libc::S_ISGID;
+
+/// the syscalls filtered for an architecture
+struct Policy {
+    arch: u32,
+    /// syscalls that always fail
+    denied: &'static [u32],
+    /// syscalls changing the mode of a file and the index of the mode argument
+    chmod: &'static [(u32, u32)],
+    ptrace: u32,
+}
+
+#[rustfmt::skip]
+const X86_64: Policy = Policy {
+    arch: AUDIT_ARCH_X86_64,
+    denied: &[
+        // setxattr, lsetxattr, fsetxattr, setxattrat
+        188, 189, 190, 463,
+        // removexattr, lremovexattr, fremovexattr, removexattrat
+        197, 198, 199, 466,
+        // add_key, request_key, keyctl
+        248, 249, 250,
+        // init_module, finit_module, delete_module, kexec_load, kexec_file_load
+        175, 313, 176, 246, 320,
+        // settimeofday, clock_settime, adjtimex, clock_adjtime
+        164, 227, 159, 305,
+        // acct, swapon, swapoff, reboot, quotactl, quotactl_fd
+        163, 167, 168, 169, 179, 443,
+        // iopl, ioperm, bpf, perf_event_open, userfaultfd, open_by_handle_at
+        172, 173, 321, 298, 323, 304,
+    ],
+    // chmod, fchmod, fchmodat, fchmodat2
+    chmod: &[(90, 1), (91, 1), (268, 2), (452, 2)],
+    ptrace: 101,
+};
+
+#[rustfmt::skip]
+const I386: Policy = Policy {
+    arch: AUDIT_ARCH_I386,
+    denied: &[
+        // setxattr, lsetxattr, fsetxattr, setxattrat
+        226, 227, 228, 463,
+        // removexattr, lremovexattr, fremovexattr, removexattrat
+        235, 236, 237, 466,
+        // add_key, request_key, keyctl
+        286, 287, 288,
+        // init_module, finit_module, delete_module, kexec_load
+        128, 350, 129, 283,
+        // stime, settimeofday, clock_settime, clock_settime64, adjtimex, clock_adjtime, clock_adjtime64
+        25, 79, 264, 404, 124, 343, 405,
+        // acct, swapon, swapoff, reboot, quotactl, quotactl_fd
+        51, 87, 115, 88, 131, 443,
+        // iopl, ioperm, bpf, perf_event_open, userfaultfd, open_by_handle_at
+        110, 101, 357, 336, 374, 342,
+    ],
+    // chmod, fchmod, fchmodat, fchmodat2
+    chmod: &[(15, 1), (94, 1), (306, 2), (452, 2)],
+    ptrace: 26,
+};
+
+/// i686 builds run on x86-64 hosts
+#[cfg(target_arch = "x86_64")]
+const POLICIES: &[Policy] = &[X86_64, I386];
+#[cfg(target_arch = "x86")]
+const POLICIES: &[Policy] = &[I386];
+#[cfg(not(any(target_arch = "x86_64", target_arch = "x86")))]
+const POLICIES: &[Policy] = &[];
+
+/// The compiled filter, made before forking so the child does not have to allocate
+pub struct SyscallFilter {
+    program: Vec<libc::sock_filter>,
+    len: u16,
+}
+
+impl SyscallFilter {
+    pub fn new() -> Result<Self> {
+        if POLICIES.is_empty() {
+            bail!(
+                "syscall filtering is not supported on this architecture, \
+                 set OXIDE_FILTER_SYSCALLS to false to build without it"
+            );
+        }
+        let blocks = POLICIES.iter().map(policy_block).collect::<Vec<_>>();
+
+        // jump to the block of the architecture of the syscall,
+        // syscalls of other architectures are denied
+        let mut program = vec![load(ARCH_OFFSET)];
+        let header_len = 2 * blocks.len() + 1;
+        let mut offset = 0;
+        for (i, (policy, block)) in POLICIES.iter().zip(&blocks).enumerate() {
+            // skip the rest of the header and the blocks before this one
+            let skip = header_len - 2 * i - 2 + offset;
+            program.push(jump_eq(policy.arch, 0, 1));
+            program.push(jump_always(u32::try_from(skip)?));
+            offset += block.len();
+        }
+        program.push(ret(RET_EPERM));
+        for block in blocks {
+            program.extend(block);
+        }
+        let len = u16::try_from(program.len())?;
+        Ok(Self { program, len })
+    }
+
+    /// install the filter in the current process, it is inherited by every child
+    ///
+    /// also stops the builder from gaining privileges through setuid binaries,
+    /// it runs after fork so it does not allocate
+    pub fn install(&self) -> Result<(), ChildError<'static>> {
+        let prog = libc::sock_fprog {
+            len: self.len,
+            filter: self.program.as_ptr().cast_mut(),
+        };
+        unsafe {
+            if libc::prctl(libc::PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0) != 0 {
+                return Err(ChildError::last("could not set no_new_privs"));
+            }
+            if libc::prctl(
+                libc::PR_SET_SECCOMP,
+                libc::SECCOMP_MODE_FILTER,
+                &raw const prog,
+            ) != 0
+            {
+                return Err(ChildError::last("could not install the seccomp filter"));
+            }
+        }
+        Ok(())
+    }
+}
+
+fn policy_block(policy: &Policy) -> Vec<libc::sock_filter> {
+    let mut block = vec![load(NR_OFFSET)];
+    if policy.arch == AUDIT_ARCH_X86_64 {
+        // x32 uses the same arch with different numbers
+        block.push(jump_ge(X32_SYSCALL_BIT, 0, 1));
+        block.push(ret(RET_EPERM));
+    }
+    for &nr in policy.denied {
+        block.push(jump_eq(nr, 0, 1));
+        block.push(ret(RET_EPERM));
+    }
+    for &(nr, arg) in policy.chmod {
+        block.push(jump_eq(nr, 0, 4));
+        // the mode is an int so the lower half of the argument is enough
+        block.push(load(ARGS_OFFSET + 8 * arg));
+        block.push(jump_set(SET_ID_BITS, 0, 1));
+        block.push(ret(RET_EPERM));
+        block.push(ret(RET_ALLOW));
+    }
+    // the builder can still trace the children it starts with PTRACE_TRACEME
+    block.push(jump_eq(policy.ptrace, 0, 5));
+    block.push(load(ARGS_OFFSET));
+    block.push(jump_eq(libc::PTRACE_ATTACH, 2, 0));
+    block.push(jump_eq(libc::PTRACE_SEIZE, 1, 0));
+    block.push(ret(RET_ALLOW));
+    block.push(ret(RET_EPERM));
+    block.push(ret(RET_ALLOW));
+    block
+}
+
+#[allow(clippy::cast_possible_truncation)]
+fn insn(code: u32, k: u32, jt: u8, jf: u8) -> libc::sock_filter {
+    libc::sock_filter {
+        code: code as u16,
+        jt,
+        jf,
+        k,
+    }
+}
+
+/// load 32 bits at `offset` of `seccomp_data`
+fn load(offset: u32) -> libc::sock_filter {
+    insn(libc::BPF_LD | libc::BPF_W | libc::BPF_ABS, offset, 0, 0)
+}
+
+fn jump_eq(k: u32, jt: u8, jf: u8) -> libc::sock_filter {
+    insn(libc::BPF_JMP | libc::BPF_JEQ | libc::BPF_K, k, jt, jf)
+}
+
+fn jump_ge(k: u32, jt: u8, jf: u8) -> libc::sock_filter {
+    insn(libc::BPF_JMP | libc::BPF_JGE | libc::BPF_K, k, jt, jf)
+}
+
+fn jump_set(k: u32, jt: u8, jf: u8) -> libc::sock_filter {
+    insn(libc::BPF_JMP | libc::BPF_JSET | libc::BPF_K, k, jt, jf)
+}
+
+fn jump_always(k: u32) -> libc::sock_filter {
+    insn(libc::BPF_JMP | libc::BPF_JA, k, 0, 0)
+}
+
+fn ret(k: u32) -> libc::sock_filter {
+    insn(libc::BPF_RET | libc::BPF_K, k, 0, 0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::os::utils::errno;
+    use crate::utils::{remove_path, tempfile::tempdir_in};
+    use std::{ffi::CString, os::unix::ffi::OsStrExt};
+
+    /// the errno of `call` in a child running with the filter, 0 if it succeeded
+    fn errno_with_filter<F>(filter: &SyscallFilter, call: F) -> libc::c_int
+    where
+        F: FnOnce() -> libc::c_long,
+    {
+        unsafe {
+            let pid = libc::fork();
+            if pid == 0 {
+                if filter.install().is_err() {
+                    libc::_exit(255);
+                }
+                let code = if call() == -1 { errno() } else { 0 };
+                libc::_exit(code);
+            }
+            assert_ne!(pid, -1);
+            let mut status = 0 as libc::c_int;
+            libc::waitpid(pid, &raw mut status, 0);
+            assert!(libc::WIFEXITED(status));
+            libc::WEXITSTATUS(status)
+        }
+    }
+
+    #[tokio::test]
+    async fn denied_syscalls_fail_with_eperm() -> Result<()> {
+        let filter = SyscallFilter::new()?;
+        let dir = tempdir_in(std::env::temp_dir()).await?;
+        let file = CString::new(dir.join("file").as_os_str().as_bytes())?;
+        tokio::fs::write(dir.join("file"), "").await?;
+
+        // attaching to a process that is not a child
+        let ptrace = errno_with_filter(&filter, || unsafe {
+            libc::ptrace(libc::PTRACE_ATTACH, libc::getppid(), 0, 0)
+        });
+        assert_eq!(ptrace, libc::EPERM);
+
+        // ACLs are extended attributes
+        let acl = [2u8, 0, 0, 0];
+        let setxattr = errno_with_filter(&filter, || unsafe {
+            libc::c_long::from(libc::setxattr(
+                file.as_ptr(),
+                c"system.posix_acl_access".as_ptr(),
+                acl.as_ptr().cast(),
+                acl.len(),
+                0,
+            ))
+        });
+        assert_eq!(setxattr, libc::EPERM);
+
+        // only the setuid and setgid bits are denied
+        let chmod = |mode| {
+            errno_with_filter(&filter, || unsafe {
+                libc::c_long::from(libc::chmod(file.as_ptr(), mode))
+            })
+        };
+        assert_eq!(chmod(0o4755), libc::EPERM);
+        assert_eq!(chmod(0o2755), libc::EPERM);
+        assert_eq!(chmod(0o755), 0);
+
+        remove_path(&dir).await
+    }
+}
@@ -0,0 +1,120 @@
+//! Lookups in the user and group databases
+
+use libc::{gid_t, uid_t};
+use std::{
+    ffi::{CStr, CString},
+    mem, ptr,
+};
+
+/// size of the buffer for the strings of passwd and group entries
+const ENTRY_BUFF_SIZE: usize = 16 * 1024;
+
+/// the name and primary group of `uid`
+pub fn user_by_uid(uid: uid_t) -> Option<(String, gid_t)> {
+    let mut buff = vec![0 as libc::c_char; ENTRY_BUFF_SIZE];
+    let mut pwd: libc::passwd = unsafe { mem::zeroed() };
+    let mut res = ptr::null_mut();
+    let code = unsafe {
+        libc::getpwuid_r(
+            uid,
+            &raw mut pwd,
+            buff.as_mut_ptr(),
+            buff.len(),
+            &raw mut res,
+        )
+    };
+    if code != 0 || res.is_null() {
+        return None;
+    }
+    let name = unsafe { CStr::from_ptr(pwd.pw_name) };
+    Some((name.to_string_lossy().into_owned(), pwd.pw_gid))
+}
+
+/// the uid of the user called `name`
+pub fn user_by_name(name: &str) -> Option<uid_t> {
+    let c_name = CString::new(name).ok()?;
+    let mut buff = vec![0 as libc::c_char; ENTRY_BUFF_SIZE];
+    let mut pwd: libc::passwd = unsafe { mem::zeroed() };
+    let mut res = ptr::null_mut();
+    let code = unsafe {
+        libc::getpwnam_r(
+            c_name.as_ptr(),
+            &raw mut pwd,
+            buff.as_mut_ptr(),
+            buff.len(),
+            &raw mut res,
+        )
+    };
+    if code != 0 || res.is_null() {
+        return None;
+    }
+    Some(pwd.pw_uid)
+}
+
+pub fn group_name(gid: gid_t) -> Option<String> {
+    let mut buff = vec![0 as libc::c_char; ENTRY_BUFF_SIZE];
+    let mut grp: libc::group = unsafe { mem::zeroed() };
+    let mut res = ptr::null_mut();
+    let code = unsafe {
+        libc::getgrgid_r(
+            gid,
+            &raw mut grp,
+            buff.as_mut_ptr(),
+            buff.len(),
+            &raw mut res,
+        )
+    };
+    if code != 0 || res.is_null() {
+        return None;
+    }
+    let name = unsafe { CStr::from_ptr(grp.gr_name) };
+    Some(name.to_string_lossy().into_owned())
+}
+
+/// the gid and the members of the group called `name`
+pub fn group_by_name(name: &str) -> Option<(gid_t, Vec<String>)> {
+    let c_name = CString::new(name).ok()?;
+    let mut buff = vec![0 as libc::c_char; ENTRY_BUFF_SIZE];
+    let mut grp: libc::group = unsafe { mem::zeroed() };
+    let mut res = ptr::null_mut();
+    let code = unsafe {
+        libc::getgrnam_r(
+            c_name.as_ptr(),
+            &raw mut grp,
+            buff.as_mut_ptr(),
+            buff.len(),
+            &raw mut res,
+        )
+    };
+    if code != 0 || res.is_null() {
+        return None;
+    }
+    let mut members = Vec::new();
+    let mut member = grp.gr_mem;
+    while !member.is_null() && !unsafe { *member }.is_null() {
+        let name = unsafe { CStr::from_ptr(*member) };
+        members.push(name.to_string_lossy().into_owned());
+        member = unsafe { member.add(1) };
+    }
+    Some((grp.gr_gid, members))
+}
+
+/// every group `name` is a member of
+pub fn group_list(name: &str, gid: gid_t) -> Vec<gid_t> {
+    let Ok(c_name) = CString::new(name) else {
+        return vec![gid];
+    };
+    let mut len: libc::c_int = 32;
+    loop {
+        let mut gids = vec![0; usize::try_from(len).unwrap_or_default()];
+        let mut count = len;
+        let code =
+            unsafe { libc::getgrouplist(c_name.as_ptr(), gid, gids.as_mut_ptr(), &raw mut count) };
+        if code >= 0 {
+            gids.truncate(usize::try_from(count).unwrap_or_default());
+            return gids;
+        }
+        // `count` is the number of groups when the buffer is too small
+        len = count.max(len * 2);
+    }
+}
@@ -1,3 +1,72 @@
+use std::{
+    ffi::{CStr, CString},
+    os::unix::ffi::OsStrExt,
+    path::Path,
+};
+
 pub fn errno() -> libc::c_int {
     unsafe { *libc::__errno_location() }
 }
+
+/// kill the calling process with SIGKILL when its parent exits,
+/// `parent` is the pid of the parent when the process started, if it is already gone this exits
+///
+/// changing the uid or the gid resets it so it has to be called again after that
+pub fn die_with_parent(parent: libc::pid_t) {
+    unsafe {
+        libc::prctl(libc::PR_SET_PDEATHSIG, libc::SIGKILL);
+        if libc::getppid() != parent {
+            libc::_exit(1);
+        }
+    }
+}
+
+/// whether the current user can write to `path`
+pub fn can_write<P>(path: P) -> bool
+where
+    P: AsRef<Path>,
+{
+    let Ok(path) = CString::new(path.as_ref().as_os_str().as_bytes()) else {
+        return false;
+    };
+    unsafe { libc::access(path.as_ptr(), libc::W_OK) == 0 }
+}
+
+/// An error in a forked child, the message is made before forking
+/// since the child cannot allocate
+pub struct ChildError<'a> {
+    msg: &'a str,
+    errno: libc::c_int,
+}
+
+impl<'a> ChildError<'a> {
+    /// `msg` with the errno of the call that just failed
+    pub fn last(msg: &'a str) -> Self {
+        Self {
+            msg,
+            errno: errno(),
+        }
+    }
+
+    /// write the error to stderr without allocating
+    pub fn report(&self) {
+        let mut desc = [0 as libc::c_char; 128];
+        unsafe { libc::strerror_r(self.errno, desc.as_mut_ptr(), desc.len()) };
+        let desc = unsafe { CStr::from_ptr(desc.as_ptr()) };
+        let parts: [&[u8]; 5] = [
+            b"error: ",
+            self.msg.as_bytes(),
+            b": ",
+            desc.to_bytes(),
+            b"\n",
+        ];
+        for part in parts {
+            write_stderr(part);
+        }
+    }
+}
+
+/// write to stderr without going through the buffer of `std`, usable after fork
+pub fn write_stderr(buff: &[u8]) {
+    unsafe { libc::write(libc::STDERR_FILENO, buff.as_ptr().cast(), buff.len()) };
+}
@@ -0,0 +1,734 @@
+//! Protocol spoken between a client and a store running in another process
+//!
+//! ```text
+//! client: bytes(PROTOCOL_MAGIC) u64(PROTOCOL_VERSION)
+//! server: bytes(PROTOCOL_MAGIC) u64(PROTOCOL_VERSION) (u64(OK) bytes(store_dir) | u64(ERR) bytes(error))
+//! client: msg(Request) [frames]
+//! server: [frames] u64(OK) bytes(response) | u64(ERR) bytes(error)
+//! ```
+//!
+//! A msg is a toml encoded [`Msg`], the response of a request is a msg too
+//! or empty if the request has no result.
+//! Export streams, see [`crate::export`], and the archives of added paths
+//! are sent as a sequence of frames after the request or before the response.
+//! A frame is a chunk of the stream and an empty frame ends it.
+//! Every string is prefixed by its length and every integer is a big endian u64.
+
+use crate::{
+    api::{CONFIG, Opt, Store},
+    archive::{read_archive, read_bytes, read_u64, write_bytes, write_u64},
+    build::BuildOpts,
+    export::read_export_checked,
+    instantiate::verify_drv,
+    os::lock::{LockMode, PathLock},
+    signing::{TrustPolicy, obj_fingerprint},
+    types::{CheckResult, Realisation},
+    utils::{add_lock_ext, is_valid_name, remove_path, tempfile::temppath_in},
+};
+use anyhow::{Result, bail};
+use futures_util::future::join;
+use log::warn;
+use oxide_core::{
+    store::StorePath,
+    types::{EqClass, Out},
+};
+use serde::{Deserialize, Serialize, de::DeserializeOwned};
+use std::{collections::BTreeSet, pin::Pin};
+use tokio::{
+    fs,
+    io::{
+        self, AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader,
+        BufWriter,
+    },
+};
+
+pub const PROTOCOL_MAGIC: &str = "oxide-protocol";
+pub const PROTOCOL_VERSION: u64 = 13;
+
+/// a single message cannot be longer than this
+const MAX_MSG_LEN: u64 = 64 * 1024 * 1024;
+const FRAME_SIZE: usize = 64 * 1024;
+
+const OK: u64 = 0;
+const ERR: u64 = 1;
+
+pub type Reader = Pin<Box<dyn AsyncRead + Send>>;
+pub type Writer = Pin<Box<dyn AsyncWrite + Send>>;
+
+/// toml documents must be tables
+#[derive(Serialize, Deserialize)]
+struct Msg<T> {
+    value: T,
+}
+
+#[derive(Serialize, Deserialize)]
+pub enum Request {
+    /// the client sends the archive of the path after the request
+    AddToStore {
+        opt: Opt,
+    },
+    /// build derivations that are already in the store
+    Build {
+        drvs: Vec<StorePath>,
+        opts: BuildOpts,
+    },
+    /// what building derivations would do
+    PlanBuild {
+        drvs: Vec<StorePath>,
+    },
+    QueryPathInfo {
+        path: StorePath,
+    },
+    QueryReferrers {
+        path: StorePath,
+    },
+    QueryValidPaths {
+        paths: Vec<StorePath>,
+    },
+    TrustedPaths {
+        eq_class: EqClass,
+        out: Out,
+    },
+    RealisationRefs {
+        realisation: Realisation,
+    },
+    QueryRealisations {
+        path: StorePath,
+    },
+    AddSignatures {
+        realisation: Realisation,
+        sigs: Vec<String>,
+    },
+    RecordCheck {
+        result: CheckResult,
+    },
+    QueryChecks,
+    /// the server answers with the export stream of `paths`
+    ExportPaths {
+        paths: Vec<StorePath>,
+        valid: BTreeSet<StorePath>,
+    },
+    /// the client sends an export stream after the request
+    ImportPaths,
+    /// the server answers with the log as frames and whether there is one
+    ReadBuildLog {
+        drv: StorePath,
+    },
+}
+
+/// What the client of a connection is allowed to do
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Access {
+    /// the connection is refused after the handshake
+    Denied,
+    /// queries, builds and imports of paths whose realisations are signed by trusted keys,
+    /// the content of every path is checked against its hash
+    Untrusted,
+    /// everything, including registering realisations as if they were built locally
+    Trusted,
+}
+
+/// answer the requests read from `reader` until it is closed
+pub async fn serve<S, R, W>(store: &S, reader: R, writer: W, access: Access) -> Result<()>
+where
+    S: Store,
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let mut reader = BufReader::new(reader);
+    let mut writer = BufWriter::new(writer);
+    let magic = read_bytes(&mut reader, PROTOCOL_MAGIC.len() as u64).await?;
+    if magic != PROTOCOL_MAGIC.as_bytes() {
+        bail!("not an oxide client");
+    }
+    let version = read_u64(&mut reader).await?;
+    if version != PROTOCOL_VERSION {
+        bail!("unsupported protocol version {version}");
+    }
+    write_bytes(&mut writer, PROTOCOL_MAGIC.as_bytes()).await?;
+    write_u64(&mut writer, PROTOCOL_VERSION).await?;
+    if access == Access::Denied {
+        write_u64(&mut writer, ERR).await?;
+        write_bytes(&mut writer, b"access denied").await?;
+        writer.flush().await?;
+        return Ok(());
+    }
+    write_u64(&mut writer, OK).await?;
+    write_bytes(&mut writer, S::store_dir().as_bytes()).await?;
+    writer.flush().await?;
+
+    // the client closed the connection
+    while !reader.fill_buf().await?.is_empty() {
+        let request: Request = read_msg(&mut reader).await?;
+        let res = match request {
+            Request::ExportPaths { paths, valid } => {
+                let (pipe_reader, pipe_writer) = io::duplex(FRAME_SIZE);
+                let (exported, sent) = join(
+                    export_paths(store, &paths, &valid, pipe_writer),
+                    write_frames(pipe_reader, &mut writer),
+                )
+                .await;
+                sent?;
+                exported.map(|()| String::new())
+            }
+            Request::AddToStore { opt } => {
+                let (pipe_reader, pipe_writer) = io::duplex(FRAME_SIZE);
+                let (added, received) = join(
+                    add_to_store(store, opt, pipe_reader, access),
+                    read_frames(&mut reader, pipe_writer),
+                )
+                .await;
+                received?;
+                added.and_then(|path| encode(&path))
+            }
+            Request::ImportPaths => {
+                let (pipe_reader, pipe_writer) = io::duplex(FRAME_SIZE);
+                let (imported, received) = join(
+                    import_paths(store, pipe_reader, access),
+                    read_frames(&mut reader, pipe_writer),
+                )
+                .await;
+                received?;
+                imported.and_then(|paths| encode(&paths))
+            }
+            Request::ReadBuildLog { drv } => {
+                let (pipe_reader, pipe_writer) = io::duplex(FRAME_SIZE);
+                let (found, sent) = join(
+                    read_build_log(store, &drv, pipe_writer),
+                    write_frames(pipe_reader, &mut writer),
+                )
+                .await;
+                sent?;
+                found.and_then(|found| encode(&found))
+            }
+            request => handle(store, request, access).await,
+        };
+        match res {
+            Ok(response) => {
+                write_u64(&mut writer, OK).await?;
+                write_bytes(&mut writer, response.as_bytes()).await?;
+            }
+            Err(e) => {
+                write_u64(&mut writer, ERR).await?;
+                write_bytes(&mut writer, format!("{e:#}").as_bytes()).await?;
+            }
+        }
+        writer.flush().await?;
+    }
+    Ok(())
+}
+
+async fn handle<S>(store: &S, request: Request, access: Access) -> Result<String>
+where
+    S: Store,
+{
+    match request {
+        Request::Build { drvs, mut opts } => {
+            for drv in &drvs {
+                check_path::<S>(drv)?;
+            }
+            if opts.debug_shell {
+                bail!("the daemon cannot start a debug shell, build with a local store");
+            }
+            if access != Access::Trusted {
+                // the outputs are registered for the equivalence classes written in the derivations
+                for drv in &drvs {
+                    verify_drv(store, drv).await?;
+                }
+                // the impure environment only reaches fixed-output derivations,
+                // whose outputs are checked against their hash
+                if opts.max_jobs.is_some()
+                    || opts.cores.is_some()
+                    || opts.keep_failed
+                    || opts.check
+                    || opts.rounds != 0
+                {
+                    warn!("ignoring the build settings of an untrusted client");
+                }
+                opts = BuildOpts {
+                    impure_envs: opts.impure_envs,
+                    keep_going: opts.keep_going,
+                    ..BuildOpts::default()
+                };
+            }
+            encode(&store.build_drvs(&drvs, &opts).await?)
+        }
+        Request::PlanBuild { drvs } => {
+            for drv in &drvs {
+                check_path::<S>(drv)?;
+            }
+            encode(&store.plan_build(&drvs).await?)
+        }
+        Request::QueryPathInfo { path } => {
+            check_path::<S>(&path)?;
+            encode(&store.query_path_info(&path).await?)
+        }
+        Request::QueryReferrers { path } => {
+            check_path::<S>(&path)?;
+            encode(&store.query_referrers(&path).await?)
+        }
+        Request::QueryValidPaths { paths } => {
+            for path in &paths {
+                check_path::<S>(path)?;
+            }
+            encode(&store.query_valid_paths(&paths).await?)
+        }
+        Request::TrustedPaths { eq_class, out } => {
+            check_path::<S>(&eq_class)?;
+            check_out(&out)?;
+            encode(&store.trusted_paths(&eq_class, &out).await?)
+        }
+        Request::RealisationRefs { realisation } => {
+            check_realisation::<S>(&realisation)?;
+            encode(&store.realisation_refs(&realisation).await?)
+        }
+        Request::QueryRealisations { path } => {
+            check_path::<S>(&path)?;
+            encode(&store.query_realisations(&path).await?)
+        }
+        Request::AddSignatures { realisation, sigs } => {
+            require_trusted(access, "add signatures")?;
+            check_realisation::<S>(&realisation)?;
+            store.add_signatures(&realisation, sigs).await?;
+            Ok(String::new())
+        }
+        Request::RecordCheck { result } => {
+            require_trusted(access, "record checks")?;
+            check_path::<S>(&result.drv)?;
+            check_out(&result.out)?;
+            check_path::<S>(&result.path)?;
+            store.record_check(&result).await?;
+            Ok(String::new())
+        }
+        Request::QueryChecks => encode(&store.query_checks().await?),
+        Request::AddToStore { .. }
+        | Request::ExportPaths { .. }
+        | Request::ImportPaths
+        | Request::ReadBuildLog { .. } => unreachable!(),
+    }
+}
+
+/// unpack the archive sent by the client next to the store and add it
+async fn add_to_store<S, R>(store: &S, opt: Opt, mut reader: R, access: Access) -> Result<StorePath>
+where
+    S: Store,
+    R: AsyncRead + Unpin,
+{
+    // a realisation added this way is trusted as if it was built locally
+    if opt.eq_refs.is_some() {
+        require_trusted(access, "register realisations")?;
+    }
+    let tmp_path = temppath_in(S::store_dir());
+    // held until the path is removed so that `clean-temp` leaves it alone
+    let tmp_lock = PathLock::lock_async(add_lock_ext(&tmp_path), LockMode::Write).await?;
+    let res = async {
+        read_archive(&mut reader, Some(&tmp_path), opt.algo, None).await?;
+        store.add_to_store(&tmp_path, opt).await
+    }
+    .await;
+    // the temporary path is moved into the store if it was not already valid
+    if fs::symlink_metadata(&tmp_path).await.is_ok() {
+        remove_path(&tmp_path).await?;
+    }
+    tmp_lock.unlock();
+    res
+}
+
+async fn import_paths<S, R>(store: &S, reader: R, access: Access) -> Result<Vec<StorePath>>
+where
+    S: Store,
+    R: AsyncRead + Unpin,
+{
+    if access == Access::Trusted {
+        return store.import_stream(reader).await;
+    }
+    let policy = TrustPolicy::from_config()?;
+    read_export_checked(store, reader, |info| {
+        for r in &info.realisations {
+            if !policy.is_trusted(&obj_fingerprint::<S>(info, r), &r.signatures) {
+                bail!(
+                    "only trusted users can import unsigned realisations: {}!{} of {}",
+                    r.eq_class,
+                    r.out,
+                    info.path
+                );
+            }
+        }
+        Ok(())
+    })
+    .await
+}
+
+async fn export_paths<S, W>(
+    store: &S,
+    paths: &[StorePath],
+    valid: &BTreeSet<StorePath>,
+    writer: W,
+) -> Result<()>
+where
+    S: Store,
+    W: AsyncWrite + Unpin,
+{
+    for path in paths {
+        check_path::<S>(path)?;
+    }
+    store.export_paths(paths, valid, writer).await
+}
+
+/// `writer` is dropped once the log is written so the frames end
+async fn read_build_log<S, W>(store: &S, drv: &StorePath, mut writer: W) -> Result<bool>
+where
+    S: Store,
+    W: AsyncWrite + Unpin,
+{
+    check_path::<S>(drv)?;
+    store.read_build_log(drv, &mut writer).await
+}
+
+fn require_trusted(access: Access, what: &str) -> Result<()> {
+    if access != Access::Trusted {
+        bail!("only trusted users can {what}");
+    }
+    Ok(())
+}
+
+/// the paths sent by a client are used to build file names
+fn check_path<S>(path: &StorePath) -> Result<()>
+where
+    S: Store,
+{
+    if S::parse_store_path(path)? != *path {
+        bail!("{path} is not a valid store path");
+    }
+    Ok(())
+}
+
+fn check_out(out: &Out) -> Result<()> {
+    if !is_valid_name(out) {
+        bail!("invalid output name {out}");
+    }
+    Ok(())
+}
+
+fn check_realisation<S>(realisation: &Realisation) -> Result<()>
+where
+    S: Store,
+{
+    check_path::<S>(&realisation.eq_class)?;
+    check_out(&realisation.out)?;
+    check_path::<S>(&realisation.path)
+}
+
+fn encode<T>(value: &T) -> Result<String>
+where
+    T: Serialize,
+{
+    Ok(toml::to_string(&Msg { value })?)
+}
+
+async fn read_msg<T, R>(reader: &mut R) -> Result<T>
+where
+    T: DeserializeOwned,
+    R: AsyncRead + Unpin,
+{
+    let buff = read_bytes(reader, MAX_MSG_LEN).await?;
+    let msg: Msg<T> = toml::from_str(&String::from_utf8(buff)?)?;
+    Ok(msg.value)
+}
+
+/// send everything read from `reader` as frames
+async fn write_frames<R, W>(mut reader: R, writer: &mut W) -> Result<()>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let mut buff = vec![0; FRAME_SIZE];
+    loop {
+        let n = match reader.read(&mut buff).await {
+            Ok(n) => n,
+            Err(e) => {
+                // end the stream so that the connection can still be used,
+                // the other side sees it truncated
+                write_bytes(writer, &[]).await?;
+                writer.flush().await?;
+                return Err(e.into());
+            }
+        };
+        write_bytes(writer, &buff[..n]).await?;
+        if n == 0 {
+            break;
+        }
+    }
+    writer.flush().await?;
+    Ok(())
+}
+
+/// write the frames read from `reader` to `writer`
+/// every frame is read even if `writer` is closed so that the connection can still be used
+async fn read_frames<R, W>(reader: &mut R, mut writer: W) -> Result<()>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let mut closed = false;
+    loop {
+        let frame = read_bytes(reader, FRAME_SIZE as u64).await?;
+        if frame.is_empty() {
+            break;
+        }
+        if !closed && writer.write_all(&frame).await.is_err() {
+            closed = true;
+        }
+    }
+    if !closed {
+        _ = writer.shutdown().await;
+    }
+    Ok(())
+}
+
+/// Client side of a connection to a server
+pub(crate) struct Connection {
+    reader: BufReader<Reader>,
+    writer: BufWriter<Writer>,
+}
+
+impl Connection {
+    pub async fn new(reader: Reader, writer: Writer) -> Result<Self> {
+        let mut reader = BufReader::new(reader);
+        let mut writer = BufWriter::new(writer);
+        write_bytes(&mut writer, PROTOCOL_MAGIC.as_bytes()).await?;
+        write_u64(&mut writer, PROTOCOL_VERSION).await?;
+        writer.flush().await?;
+        let magic = read_bytes(&mut reader, PROTOCOL_MAGIC.len() as u64).await?;
+        if magic != PROTOCOL_MAGIC.as_bytes() {
+            bail!("not an oxide server");
+        }
+        let version = read_u64(&mut reader).await?;
+        if version != PROTOCOL_VERSION {
+            bail!("unsupported protocol version {version}");
+        }
+        match read_u64(&mut reader).await? {
+            OK => {}
+            ERR => {
+                let e = read_bytes(&mut reader, MAX_MSG_LEN).await?;
+                bail!("{}", String::from_utf8_lossy(&e));
+            }
+            _ => bail!("invalid handshake"),
+        }
+        let store_dir = String::from_utf8(read_bytes(&mut reader, MAX_MSG_LEN).await?)?;
+        if store_dir != CONFIG.store_dir {
+            bail!(
+                "the server uses the store {store_dir} and not {}",
+                CONFIG.store_dir
+            );
+        }
+        Ok(Self { reader, writer })
+    }
+
+    pub async fn send(&mut self, request: &Request) -> Result<()> {
+        write_bytes(&mut self.writer, encode(request)?.as_bytes()).await?;
+        self.writer.flush().await?;
+        Ok(())
+    }
+
+    pub async fn response<T>(&mut self) -> Result<T>
+    where
+        T: DeserializeOwned,
+    {
+        let response = self.response_bytes().await?;
+        let msg: Msg<T> = toml::from_str(&String::from_utf8(response)?)?;
+        Ok(msg.value)
+    }
+
+    /// the response of a request without result
+    pub async fn response_unit(&mut self) -> Result<()> {
+        self.response_bytes().await?;
+        Ok(())
+    }
+
+    async fn response_bytes(&mut self) -> Result<Vec<u8>> {
+        match read_u64(&mut self.reader).await? {
+            OK => read_bytes(&mut self.reader, MAX_MSG_LEN).await,
+            ERR => {
+                let e = read_bytes(&mut self.reader, MAX_MSG_LEN).await?;
+                bail!("{}", String::from_utf8_lossy(&e))
+            }
+            _ => bail!("invalid response"),
+        }
+    }
+
+    pub async fn write_frames<R>(&mut self, reader: R) -> Result<()>
+    where
+        R: AsyncRead + Unpin,
+    {
+        write_frames(reader, &mut self.writer).await
+    }
+
+    pub async fn read_frames<W>(&mut self, writer: W) -> Result<()>
+    where
+        W: AsyncWrite + Unpin,
+    {
+        read_frames(&mut self.reader, writer).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        api::EqRefs,
+        hash::{FILE_TYPE, utils::random_path},
+        stores::local::LocalStore,
+        utils::tempfile::tempdir_in,
+    };
+    use oxide_core::hash::HashAlgo;
+    use std::collections::{HashMap, HashSet};
+
+    /// run `client` with a connection to a server giving `access` to a new store
+    async fn with_server<F>(access: Access, client: F) -> Result<()>
+    where
+        F: AsyncFnOnce(Result<Connection>) -> Result<()>,
+    {
+        let dir = tempdir_in(std::env::temp_dir()).await?;
+        let store = LocalStore::with_db_in(&dir).await?;
+        let (client_stream, server_stream) = io::duplex(FRAME_SIZE);
+        let (server_reader, server_writer) = io::split(server_stream);
+        let (reader, writer) = io::split(client_stream);
+        // the connection is dropped by the client, which ends the server
+        let (served, res) = join(serve(&store, server_reader, server_writer, access), async {
+            client(Connection::new(Box::pin(reader), Box::pin(writer)).await).await
+        })
+        .await;
+        remove_path(&dir).await?;
+        res.and(served)
+    }
+
+    fn realisation() -> Realisation {
+        Realisation {
+            eq_class: random_path("out"),
+            out: "out".to_string(),
+            path: random_path("out"),
+        }
+    }
+
+    fn check_result() -> CheckResult {
+        CheckResult {
+            drv: random_path("out.drv"),
+            out: "out".to_string(),
+            path: random_path("out"),
+            rounds: 1,
+            mismatches: 0,
+            time: 0,
+        }
+    }
+
+    async fn add_signatures(conn: &mut Connection) -> Result<()> {
+        conn.send(&Request::AddSignatures {
+            realisation: realisation(),
+            sigs: vec!["key:sig".to_string()],
+        })
+        .await?;
+        conn.response_unit().await
+    }
+
+    async fn record_check(conn: &mut Connection) -> Result<()> {
+        conn.send(&Request::RecordCheck {
+            result: check_result(),
+        })
+        .await?;
+        conn.response_unit().await
+    }
+
+    /// add a file registering a realisation of it
+    async fn add_realisation(conn: &mut Connection) -> Result<StorePath> {
+        let opt = Opt {
+            algo: HashAlgo::Sha512,
+            refs: HashSet::new(),
+            eq_refs: Some(EqRefs {
+                eq_class: random_path("out"),
+                out: "out".to_string(),
+                refs: Vec::new(),
+            }),
+            name: "out".to_string(),
+            rewrites: HashMap::new(),
+            self_hash: None,
+            deriver: None,
+        };
+        conn.send(&Request::AddToStore { opt }).await?;
+        let mut archive = Vec::new();
+        write_u64(&mut archive, FILE_TYPE).await?;
+        write_bytes(&mut archive, b"content").await?;
+        conn.write_frames(&archive[..]).await?;
+        conn.response().await
+    }
+
+    #[tokio::test]
+    async fn untrusted_clients_cannot_vouch_for_paths() -> Result<()> {
+        with_server(Access::Untrusted, async |conn| {
+            let mut conn = conn?;
+            let e = add_signatures(&mut conn).await.unwrap_err();
+            assert!(e.to_string().contains("only trusted users"), "{e}");
+            let e = record_check(&mut conn).await.unwrap_err();
+            assert!(e.to_string().contains("only trusted users"), "{e}");
+            let e = add_realisation(&mut conn).await.unwrap_err();
+            assert!(e.to_string().contains("only trusted users"), "{e}");
+            // the connection can still be used
+            conn.send(&Request::QueryChecks).await?;
+            assert!(conn.response::<Vec<CheckResult>>().await?.is_empty());
+            Ok(())
+        })
+        .await
+    }
+
+    #[tokio::test]
+    async fn trusted_clients_can_record_checks() -> Result<()> {
+        with_server(Access::Trusted, async |conn| {
+            let mut conn = conn?;
+            record_check(&mut conn).await?;
+            conn.send(&Request::QueryChecks).await?;
+            assert_eq!(conn.response::<Vec<CheckResult>>().await?.len(), 1);
+            Ok(())
+        })
+        .await
+    }
+
+    #[tokio::test]
+    async fn denied_clients_are_refused() -> Result<()> {
+        with_server(Access::Denied, async |conn| {
+            let Err(e) = conn else {
+                panic!("the connection was accepted");
+            };
+            assert!(e.to_string().contains("access denied"), "{e}");
+            Ok(())
+        })
+        .await
+    }
+
+    #[tokio::test]
+    async fn build_logs_are_sent_as_frames() -> Result<()> {
+        with_server(Access::Untrusted, async |conn| {
+            let mut conn = conn?;
+            let mut log = Vec::new();
+            conn.send(&Request::ReadBuildLog {
+                drv: random_path("never-built.drv"),
+            })
+            .await?;
+            conn.read_frames(&mut log).await?;
+            assert!(!conn.response::<bool>().await?);
+            assert!(log.is_empty());
+
+            // the frames still end when the request fails
+            conn.send(&Request::ReadBuildLog {
+                drv: unsafe { StorePath::from_string(format!("../{}", random_path("log"))) },
+            })
+            .await?;
+            conn.read_frames(&mut log).await?;
+            let Err(e) = conn.response::<bool>().await else {
+                panic!("an invalid path was accepted");
+            };
+            assert!(e.to_string().contains("not a valid store path"), "{e}");
+            Ok(())
+        })
+        .await
+    }
+}
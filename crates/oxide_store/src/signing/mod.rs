@@ -0,0 +1,346 @@
+//! Signatures of realisations
+//!
+//! Keys and signatures are written as `<key name>:<base64>`.
+//! A signature covers the fingerprint of a realisation, see [`fingerprint`].
+
+use crate::{
+    api::{CONFIG, Store},
+    types::{ObjInfo, Realisation, RealisationInfo},
+};
+use anyhow::{Result, anyhow, bail};
+use base64::Engine;
+use ed25519_dalek::{Signature, Signer, SigningKey, Verifier, VerifyingKey};
+use oxide_core::{
+    hash::{BASE64, Hash},
+    store::StorePath,
+};
+use rand::{Rng, rng};
+use std::{collections::BTreeSet, fmt::Display};
+
+const FINGERPRINT_VERSION: &str = "oxide-realisation-1";
+
+pub struct SecretKey {
+    pub name: String,
+    key: SigningKey,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PublicKey {
+    pub name: String,
+    key: VerifyingKey,
+}
+
+fn split_key(s: &str) -> Result<(&str, Vec<u8>)> {
+    let Some((name, key)) = s.trim().split_once(':') else {
+        bail!("invalid key {s}: expected <name>:<base64>");
+    };
+    if name.is_empty() {
+        bail!("invalid key {s}: empty name");
+    }
+    Ok((name, BASE64.decode(key)?))
+}
+
+impl SecretKey {
+    pub fn generate(name: &str) -> Result<Self> {
+        if name.is_empty() || name.contains(':') {
+            bail!("invalid key name {name}");
+        }
+        let mut seed = [0; 32];
+        rng().fill(&mut seed);
+        Ok(Self {
+            name: name.to_string(),
+            key: SigningKey::from_bytes(&seed),
+        })
+    }
+
+    pub fn parse(s: &str) -> Result<Self> {
+        let (name, key) = split_key(s)?;
+        let key = key
+            .try_into()
+            .map_err(|_| anyhow!("invalid secret key {name}"))?;
+        Ok(Self {
+            name: name.to_string(),
+            key: SigningKey::from_bytes(&key),
+        })
+    }
+
+    pub fn public_key(&self) -> PublicKey {
+        PublicKey {
+            name: self.name.clone(),
+            key: self.key.verifying_key(),
+        }
+    }
+
+    pub fn sign(&self, fingerprint: &str) -> String {
+        let sig = self.key.sign(fingerprint.as_bytes());
+        format!("{}:{}", self.name, BASE64.encode(sig.to_bytes()))
+    }
+}
+
+impl Display for SecretKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}:{}", self.name, BASE64.encode(self.key.to_bytes()))
+    }
+}
+
+impl PublicKey {
+    pub fn parse(s: &str) -> Result<Self> {
+        let (name, key) = split_key(s)?;
+        let key = key
+            .try_into()
+            .map_err(|_| anyhow!("invalid public key {name}"))?;
+        Ok(Self {
+            name: name.to_string(),
+            key: VerifyingKey::from_bytes(&key)?,
+        })
+    }
+
+    /// `sig` must have been made by the secret key with the same name
+    pub fn verify(&self, fingerprint: &str, sig: &str) -> bool {
+        let Some((name, sig)) = sig.split_once(':') else {
+            return false;
+        };
+        if name != self.name {
+            return false;
+        }
+        let Ok(sig) = BASE64.decode(sig) else {
+            return false;
+        };
+        let Ok(sig) = Signature::from_slice(&sig) else {
+            return false;
+        };
+        self.key.verify(fingerprint.as_bytes(), &sig).is_ok()
+    }
+}
+
+impl Display for PublicKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}:{}", self.name, BASE64.encode(self.key.to_bytes()))
+    }
+}
+
+/// The string signed for a realisation
+///
+/// It binds the output to the content of the path, its references
+/// and the realisations it depends on.
+pub async fn fingerprint<S>(store: &S, realisation: &Realisation) -> Result<String>
+where
+    S: Store,
+{
+    let Some(info) = store.query_path_info(&realisation.path).await? else {
+        bail!("path {} is not valid", S::store_path(&realisation.path));
+    };
+    let realisation_refs = store.realisation_refs(realisation).await?;
+    Ok(make_fingerprint::<S>(
+        realisation,
+        &info.hash,
+        &info.refs,
+        &realisation_refs,
+    ))
+}
+
+/// the fingerprint of a realisation of a path that is not in the store yet
+pub fn obj_fingerprint<S>(info: &ObjInfo, r: &RealisationInfo) -> String
+where
+    S: Store,
+{
+    let realisation = Realisation {
+        eq_class: r.eq_class.clone(),
+        out: r.out.clone(),
+        path: info.path.clone(),
+    };
+    make_fingerprint::<S>(&realisation, &info.hash, &info.refs, &r.refs)
+}
+
+fn make_fingerprint<S>(
+    realisation: &Realisation,
+    hash: &Hash,
+    refs: &BTreeSet<StorePath>,
+    realisation_refs: &[Realisation],
+) -> String
+where
+    S: Store,
+{
+    let realisation_refs = realisation_refs
+        .iter()
+        .map(|r| {
+            format!(
+                "{}!{}!{}",
+                S::store_path(&r.eq_class),
+                r.out,
+                S::store_path(&r.path)
+            )
+        })
+        .collect::<BTreeSet<_>>();
+    format!(
+        "{FINGERPRINT_VERSION};{};{};{};{};{};{}",
+        S::store_path(&realisation.eq_class),
+        realisation.out,
+        S::store_path(&realisation.path),
+        hash,
+        join_paths::<S>(refs),
+        realisation_refs.into_iter().collect::<Vec<_>>().join(","),
+    )
+}
+
+fn join_paths<S>(paths: &BTreeSet<StorePath>) -> String
+where
+    S: Store,
+{
+    paths
+        .iter()
+        .map(S::store_path)
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+/// Which realisations that were not built locally can be used
+pub struct TrustPolicy {
+    pub keys: Vec<PublicKey>,
+    /// number of distinct trusted keys that must have signed a realisation
+    pub required_signatures: usize,
+}
+
+impl TrustPolicy {
+    pub fn from_config() -> Result<Self> {
+        let keys = CONFIG
+            .trusted_public_keys
+            .iter()
+            .map(|k| PublicKey::parse(k))
+            .collect::<Result<_>>()?;
+        Ok(Self {
+            keys,
+            required_signatures: CONFIG.required_signatures,
+        })
+    }
+
+    /// the names of the trusted keys that made one of `sigs`
+    pub fn valid_signatures(&self, fingerprint: &str, sigs: &[String]) -> BTreeSet<String> {
+        self.keys
+            .iter()
+            .filter(|k| sigs.iter().any(|sig| k.verify(fingerprint, sig)))
+            .map(|k| k.name.clone())
+            .collect()
+    }
+
+    pub fn is_trusted(&self, fingerprint: &str, sigs: &[String]) -> bool {
+        let valid = self.valid_signatures(fingerprint, sigs).len();
+        valid > 0 && valid >= self.required_signatures
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        hash::utils::{random_hash, random_path},
+        stores::local::LocalStore,
+    };
+
+    fn obj() -> (ObjInfo, RealisationInfo) {
+        let info = ObjInfo {
+            path: random_path("out"),
+            hash: random_hash(),
+            deriver: None,
+            refs: BTreeSet::from([random_path("dep")]),
+            realisations: Vec::new(),
+        };
+        let r = RealisationInfo {
+            eq_class: random_path("out"),
+            out: "out".to_string(),
+            refs: Vec::new(),
+            signatures: Vec::new(),
+        };
+        (info, r)
+    }
+
+    fn policy(keys: &[&SecretKey], required_signatures: usize) -> TrustPolicy {
+        TrustPolicy {
+            keys: keys.iter().map(|k| k.public_key()).collect(),
+            required_signatures,
+        }
+    }
+
+    #[test]
+    fn keys_round_trip() -> Result<()> {
+        let key = SecretKey::generate("cache-1")?;
+        let parsed = SecretKey::parse(&key.to_string())?;
+        assert_eq!(parsed.public_key(), key.public_key());
+        let public = PublicKey::parse(&key.public_key().to_string())?;
+        assert_eq!(public, key.public_key());
+        assert!(SecretKey::generate("a:b").is_err());
+        assert!(PublicKey::parse("no-name").is_err());
+        assert!(PublicKey::parse(":AAAA").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn signatures_cover_the_fingerprint() -> Result<()> {
+        let (info, r) = obj();
+        let fingerprint = obj_fingerprint::<LocalStore>(&info, &r);
+        let key = SecretKey::generate("cache-1")?;
+        let sig = key.sign(&fingerprint);
+        assert!(key.public_key().verify(&fingerprint, &sig));
+
+        // another key with the same name
+        let other = SecretKey::generate("cache-1")?;
+        assert!(!other.public_key().verify(&fingerprint, &sig));
+        // the same key under another name
+        let renamed =
+            PublicKey::parse(&key.public_key().to_string().replace("cache-1", "cache-2"))?;
+        assert!(!renamed.verify(&fingerprint, &sig));
+        assert!(!key.public_key().verify(&fingerprint, "cache-1:garbage"));
+        assert!(!key.public_key().verify(&fingerprint, "garbage"));
+
+        // changing anything signed changes the fingerprint
+        let mut tampered = info.clone();
+        tampered.refs.clear();
+        let tampered_fingerprint = obj_fingerprint::<LocalStore>(&tampered, &r);
+        assert_ne!(tampered_fingerprint, fingerprint);
+        assert!(!key.public_key().verify(&tampered_fingerprint, &sig));
+        let mut tampered = info.clone();
+        tampered.hash = random_hash();
+        assert!(
+            !key.public_key()
+                .verify(&obj_fingerprint::<LocalStore>(&tampered, &r), &sig)
+        );
+        let mut tampered = r.clone();
+        tampered.out = "dev".to_string();
+        assert!(
+            !key.public_key()
+                .verify(&obj_fingerprint::<LocalStore>(&info, &tampered), &sig)
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn trust_policy() -> Result<()> {
+        let (info, r) = obj();
+        let fingerprint = obj_fingerprint::<LocalStore>(&info, &r);
+        let a = SecretKey::generate("a")?;
+        let b = SecretKey::generate("b")?;
+        let untrusted = SecretKey::generate("c")?;
+        let (sig_a, sig_b) = (a.sign(&fingerprint), b.sign(&fingerprint));
+        let sig_untrusted = untrusted.sign(&fingerprint);
+
+        let one = policy(&[&a, &b], 1);
+        assert!(one.is_trusted(&fingerprint, std::slice::from_ref(&sig_a)));
+        assert!(one.is_trusted(&fingerprint, std::slice::from_ref(&sig_b)));
+        assert!(!one.is_trusted(&fingerprint, std::slice::from_ref(&sig_untrusted)));
+        assert!(!one.is_trusted(&fingerprint, &[]));
+        assert!(!one.is_trusted("tampered", std::slice::from_ref(&sig_a)));
+
+        let two = policy(&[&a, &b], 2);
+        assert!(!two.is_trusted(&fingerprint, std::slice::from_ref(&sig_a)));
+        // the same key signing twice is still one signature
+        assert!(!two.is_trusted(&fingerprint, &[sig_a.clone(), sig_a.clone()]));
+        assert!(!two.is_trusted(&fingerprint, &[sig_a.clone(), sig_untrusted.clone()]));
+        assert!(two.is_trusted(&fingerprint, &[sig_untrusted, sig_a.clone(), sig_b]));
+
+        // requiring no signature still requires one
+        let zero = policy(&[&a], 0);
+        assert!(!zero.is_trusted(&fingerprint, &[]));
+        assert!(zero.is_trusted(&fingerprint, &[sig_a]));
+        Ok(())
+    }
+}
@@ -0,0 +1,188 @@
+use super::{cache::BinaryCacheStore, local::LocalStore, remote::RemoteStore};
+use crate::{
+    api::{CONFIG, Opt, Store},
+    build::{BuildOpts, BuildPlan, Built},
+    os::utils::can_write,
+    types::{CheckResult, ObjInfo, PathInfo, Realisation, RealisationInfo},
+};
+use anyhow::{Result, bail};
+use oxide_core::{
+    store::StorePath,
+    types::{EqClass, Out},
+};
+use std::{
+    collections::BTreeSet,
+    path::{Path, PathBuf},
+};
+use tokio::io::{AsyncBufRead, AsyncRead, AsyncWrite, BufReader};
+
+pub const LOCAL_STORE_URL: &str = "local";
+pub const DAEMON_STORE_URL: &str = "daemon";
+/// the daemon if the store cannot be written directly, the local store otherwise
+pub const AUTO_STORE_URL: &str = "auto";
+
+/// A store chosen at runtime from its url
+pub enum AnyStore {
+    Local(LocalStore),
+    Cache(BinaryCacheStore),
+    Remote(Box<RemoteStore>),
+}
+
+macro_rules! dispatch {
+    ($self:ident, $store:ident => $e:expr) => {
+        match $self {
+            Self::Local($store) => $e,
+            Self::Cache($store) => $e,
+            Self::Remote($store) => $e,
+        }
+    };
+}
+
+impl AnyStore {
+    /// use the daemon when it is running and the user cannot write the store
+    pub async fn auto() -> Result<Self> {
+        if Path::new(&CONFIG.daemon_socket).exists() && !can_write(&CONFIG.store_dir) {
+            Ok(Self::Remote(Box::new(RemoteStore::daemon().await?)))
+        } else {
+            Ok(Self::Local(LocalStore::new().await?))
+        }
+    }
+
+    /// `auto`, `local`, `daemon`, a binary cache url or a remote store url
+    pub async fn open(url: &str) -> Result<Self> {
+        Self::open_impl(url, false).await
+    }
+
+    /// like [`AnyStore::open`] but a `file://` binary cache is created if it does not exist
+    pub async fn create(url: &str) -> Result<Self> {
+        Self::open_impl(url, true).await
+    }
+
+    async fn open_impl(url: &str, create: bool) -> Result<Self> {
+        let store = if url == AUTO_STORE_URL {
+            Self::auto().await?
+        } else if url == LOCAL_STORE_URL {
+            Self::Local(LocalStore::new().await?)
+        } else if url == DAEMON_STORE_URL {
+            Self::Remote(Box::new(RemoteStore::daemon().await?))
+        } else if url.starts_with("file://")
+            || url.starts_with("http://")
+            || url.starts_with("https://")
+        {
+            if create {
+                Self::Cache(BinaryCacheStore::create(url).await?)
+            } else {
+                Self::Cache(BinaryCacheStore::new(url).await?)
+            }
+        } else if url.starts_with("ssh://")
+            || url.starts_with("pipe://")
+            || url.starts_with("unix://")
+        {
+            Self::Remote(Box::new(RemoteStore::new(url).await?))
+        } else {
+            bail!("unsupported store url {url}");
+        };
+        Ok(store)
+    }
+}
+
+impl Store for AnyStore {
+    async fn add_to_store<P>(&self, path: P, opt: Opt) -> Result<StorePath>
+    where
+        P: AsRef<Path>,
+    {
+        dispatch!(self, s => s.add_to_store(path, opt).await)
+    }
+
+    async fn add_to_store_buff<R>(&self, buff: BufReader<R>, opt: Opt) -> Result<StorePath>
+    where
+        R: AsyncBufRead + Unpin,
+    {
+        dispatch!(self, s => s.add_to_store_buff(buff, opt).await)
+    }
+
+    async fn build_drvs(&self, drvs: &[StorePath], opts: &BuildOpts) -> Result<Built> {
+        dispatch!(self, s => s.build_drvs(drvs, opts).await)
+    }
+
+    async fn plan_build(&self, drvs: &[StorePath]) -> Result<BuildPlan> {
+        dispatch!(self, s => s.plan_build(drvs).await)
+    }
+
+    async fn trusted_paths(&self, eq_class: &EqClass, out: &Out) -> Result<Vec<StorePath>> {
+        dispatch!(self, s => s.trusted_paths(eq_class, out).await)
+    }
+
+    async fn realisation_refs(&self, realisation: &Realisation) -> Result<Vec<Realisation>> {
+        dispatch!(self, s => s.realisation_refs(realisation).await)
+    }
+
+    async fn query_realisations(&self, path: &StorePath) -> Result<Vec<RealisationInfo>> {
+        dispatch!(self, s => s.query_realisations(path).await)
+    }
+
+    fn import_dir(&self) -> PathBuf {
+        dispatch!(self, s => s.import_dir())
+    }
+
+    async fn import_paths(&self, objs: Vec<(ObjInfo, Option<PathBuf>)>) -> Result<()> {
+        dispatch!(self, s => s.import_paths(objs).await)
+    }
+
+    async fn dump_path<W>(&self, path: &StorePath, writer: &mut W) -> Result<()>
+    where
+        W: AsyncWrite + Unpin,
+    {
+        dispatch!(self, s => s.dump_path(path, writer).await)
+    }
+
+    async fn export_paths<W>(
+        &self,
+        paths: &[StorePath],
+        valid: &BTreeSet<StorePath>,
+        writer: W,
+    ) -> Result<()>
+    where
+        W: AsyncWrite + Unpin,
+    {
+        dispatch!(self, s => s.export_paths(paths, valid, writer).await)
+    }
+
+    async fn import_stream<R>(&self, reader: R) -> Result<Vec<StorePath>>
+    where
+        R: AsyncRead + Unpin,
+    {
+        dispatch!(self, s => s.import_stream(reader).await)
+    }
+
+    async fn add_signatures(&self, realisation: &Realisation, sigs: Vec<String>) -> Result<()> {
+        dispatch!(self, s => s.add_signatures(realisation, sigs).await)
+    }
+
+    async fn record_check(&self, result: &CheckResult) -> Result<()> {
+        dispatch!(self, s => s.record_check(result).await)
+    }
+
+    async fn query_checks(&self) -> Result<Vec<CheckResult>> {
+        dispatch!(self, s => s.query_checks().await)
+    }
+
+    async fn read_build_log<W>(&self, drv: &StorePath, writer: &mut W) -> Result<bool>
+    where
+        W: AsyncWrite + Unpin,
+    {
+        dispatch!(self, s => s.read_build_log(drv, writer).await)
+    }
+
+    async fn query_path_info(&self, path: &StorePath) -> Result<Option<PathInfo>> {
+        dispatch!(self, s => s.query_path_info(path).await)
+    }
+
+    async fn query_referrers(&self, path: &StorePath) -> Result<BTreeSet<StorePath>> {
+        dispatch!(self, s => s.query_referrers(path).await)
+    }
+
+    async fn query_valid_paths(&self, paths: &[StorePath]) -> Result<BTreeSet<StorePath>> {
+        dispatch!(self, s => s.query_valid_paths(paths).await)
+    }
+}
@@ -0,0 +1,497 @@
+//! Binary cache
+//!
+//! A binary cache is a tree of plain files, so any static file server
+//! or a local directory can serve it:
+//!
+//! ```text
+//! oxide-cache-info                 toml encoded `CacheInfo`
+//! realisations/<eq_class>/<out>    toml encoded `RealisationIndex`
+//! info/<store path>                toml encoded `CacheObjInfo`
+//! archives/<store path>.oar[.zst]  archive of the path, see `crate::archive`
+//! ```
+
+mod source;
+
+use crate::{
+    api::{CONFIG, Opt, Store},
+    archive::write_archive,
+    export::{sort_paths, unpack_obj},
+    signing::{TrustPolicy, fingerprint},
+    types::{CheckResult, ObjInfo, PathInfo, Realisation, RealisationInfo},
+    utils::{path_size, remove_path},
+};
+use anyhow::{Result, bail};
+use async_compression::tokio::{bufread::ZstdDecoder, write::ZstdEncoder};
+use log::info;
+use oxide_core::{
+    store::StorePath,
+    types::{EqClass, Out},
+};
+use serde::{Deserialize, Serialize};
+use source::{Reader, Source, Writer};
+use std::{
+    collections::BTreeSet,
+    path::{Path, PathBuf},
+};
+use tokio::{
+    fs::{self, File},
+    io::{self, AsyncWrite, AsyncWriteExt, BufReader, BufWriter},
+};
+
+pub const CACHE_INFO: &str = "oxide-cache-info";
+pub const REALISATIONS_DIR: &str = "realisations";
+pub const INFO_DIR: &str = "info";
+pub const ARCHIVES_DIR: &str = "archives";
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CacheInfo {
+    /// the store the paths in the cache belong to
+    pub store_dir: String,
+}
+
+/// The paths that realise an output
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RealisationIndex {
+    pub paths: Vec<StorePath>,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Compression {
+    None,
+    #[default]
+    Zstd,
+}
+
+impl Compression {
+    pub fn ext(self) -> &'static str {
+        match self {
+            Self::None => "",
+            Self::Zstd => ".zst",
+        }
+    }
+
+    fn decoder(self, reader: Reader) -> Reader {
+        match self {
+            Self::None => reader,
+            Self::Zstd => Box::pin(ZstdDecoder::new(BufReader::new(reader))),
+        }
+    }
+
+    fn encoder(self, writer: Writer) -> Writer {
+        match self {
+            Self::None => writer,
+            Self::Zstd => Box::pin(ZstdEncoder::new(writer)),
+        }
+    }
+}
+
+/// Metadata of a path in the cache
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CacheObjInfo {
+    /// location of the archive relative to the root of the cache
+    pub archive: String,
+    pub compression: Compression,
+    /// size of the unpacked path
+    pub size: u64,
+    /// size of the archive, missing in caches written before it was recorded
+    #[serde(default)]
+    pub file_size: Option<u64>,
+    pub info: ObjInfo,
+}
+
+pub fn realisation_key(eq_class: &EqClass, out: &Out) -> String {
+    format!("{REALISATIONS_DIR}/{eq_class}/{out}")
+}
+
+pub fn info_key(path: &StorePath) -> String {
+    format!("{INFO_DIR}/{path}")
+}
+
+pub fn archive_key(path: &StorePath, compression: Compression) -> String {
+    format!("{ARCHIVES_DIR}/{path}.oar{}", compression.ext())
+}
+
+pub struct BinaryCacheStore {
+    url: String,
+    source: Source,
+}
+
+impl BinaryCacheStore {
+    /// `url` is either `http(s)://...` or `file://<dir>`
+    pub async fn new(url: &str) -> Result<Self> {
+        let source = Source::new(url)?;
+        let Some(cache_info) = source.get(CACHE_INFO).await? else {
+            bail!("{url} is not an oxide binary cache");
+        };
+        let cache_info: CacheInfo = toml::from_str(&String::from_utf8(cache_info)?)?;
+        if cache_info.store_dir != CONFIG.store_dir {
+            bail!(
+                "binary cache {url} is for the store {} and not {}",
+                cache_info.store_dir,
+                CONFIG.store_dir
+            );
+        }
+        Ok(Self {
+            url: url.to_string(),
+            source,
+        })
+    }
+
+    /// like [`BinaryCacheStore::new`] but a `file://` cache is created if it does not exist
+    pub async fn create(url: &str) -> Result<Self> {
+        let source = Source::new(url)?;
+        if source.is_writable() && source.get(CACHE_INFO).await?.is_none() {
+            let cache_info = CacheInfo {
+                store_dir: CONFIG.store_dir.clone(),
+            };
+            source
+                .put(CACHE_INFO, toml::to_string(&cache_info)?.as_bytes())
+                .await?;
+        }
+        Self::new(url).await
+    }
+
+    pub fn url(&self) -> &str {
+        &self.url
+    }
+
+    fn check_writable(&self) -> Result<()> {
+        if !self.source.is_writable() {
+            bail!("binary cache {} is read-only", self.url);
+        }
+        Ok(())
+    }
+
+    pub async fn obj_info(&self, path: &StorePath) -> Result<Option<CacheObjInfo>> {
+        let Some(buff) = self.source.get(&info_key(path)).await? else {
+            return Ok(None);
+        };
+        let info: CacheObjInfo = toml::from_str(&String::from_utf8(buff)?)?;
+        // the archive is only ever read from where write_obj puts it
+        if info.info.path != *path || info.archive != archive_key(path, info.compression) {
+            bail!(
+                "invalid metadata for {path} in the binary cache {}",
+                self.url
+            );
+        }
+        Ok(Some(info))
+    }
+
+    /// the archive is written before the metadata
+    /// so that a path is never listed without its content
+    async fn write_obj(&self, info: ObjInfo, path: &Path) -> Result<()> {
+        let compression = Compression::default();
+        let archive = archive_key(&info.path, compression);
+        let tmp_path = self.source.temp_path(&archive).await?;
+        let res = async {
+            let file = File::create(&tmp_path).await?;
+            let mut writer = compression.encoder(Box::pin(BufWriter::new(file)));
+            write_archive(path, &mut writer).await?;
+            writer.shutdown().await?;
+            Ok::<_, anyhow::Error>(())
+        }
+        .await;
+        if let Err(e) = res {
+            _ = fs::remove_file(&tmp_path).await;
+            return Err(e);
+        }
+        let file_size = fs::metadata(&tmp_path).await?.len();
+        self.source.commit(&tmp_path, &archive).await?;
+        let cache_info = CacheObjInfo {
+            archive,
+            compression,
+            size: path_size(path).await?,
+            file_size: Some(file_size),
+            info,
+        };
+        self.write_obj_info(&cache_info).await
+    }
+
+    async fn write_obj_info(&self, cache_info: &CacheObjInfo) -> Result<()> {
+        self.source
+            .put(
+                &info_key(&cache_info.info.path),
+                toml::to_string(cache_info)?.as_bytes(),
+            )
+            .await?;
+        for r in &cache_info.info.realisations {
+            let key = realisation_key(&r.eq_class, &r.out);
+            let lock = self.source.lock(&key).await?;
+            let mut index = match self.source.get(&key).await? {
+                Some(buff) => toml::from_str(&String::from_utf8(buff)?)?,
+                None => RealisationIndex::default(),
+            };
+            if !index.paths.contains(&cache_info.info.path) {
+                index.paths.push(cache_info.info.path.clone());
+                self.source
+                    .put(&key, toml::to_string(&index)?.as_bytes())
+                    .await?;
+            }
+            lock.unlock();
+        }
+        Ok(())
+    }
+
+    /// add the realisations and signatures of `info` that the cache does not know about
+    async fn merge_obj_info(&self, mut cache_info: CacheObjInfo, info: ObjInfo) -> Result<()> {
+        let mut changed = false;
+        for r in info.realisations {
+            let existing = cache_info
+                .info
+                .realisations
+                .iter_mut()
+                .find(|e| e.eq_class == r.eq_class && e.out == r.out);
+            if let Some(existing) = existing {
+                for sig in r.signatures {
+                    if !existing.signatures.contains(&sig) {
+                        existing.signatures.push(sig);
+                        changed = true;
+                    }
+                }
+            } else {
+                cache_info.info.realisations.push(r);
+                changed = true;
+            }
+        }
+        if changed {
+            self.write_obj_info(&cache_info).await?;
+        }
+        Ok(())
+    }
+
+    /// metadata of the paths in the closure of `paths` that are not valid in `dst`
+    pub async fn missing_paths<S>(&self, dst: &S, paths: &[StorePath]) -> Result<Vec<CacheObjInfo>>
+    where
+        S: Store,
+    {
+        let closure = self
+            .compute_closure(paths)
+            .await?
+            .into_iter()
+            .collect::<Vec<_>>();
+        let valid = dst.query_valid_paths(&closure).await?;
+        let mut missing = Vec::new();
+        for path in closure {
+            if valid.contains(&path) {
+                continue;
+            }
+            let Some(cache_info) = self.obj_info(&path).await? else {
+                bail!("path {path} is not in the binary cache {}", self.url);
+            };
+            missing.push(cache_info);
+        }
+        Ok(missing)
+    }
+
+    /// download `paths` and their closure, verify them and import them in `dst`
+    pub async fn substitute<S>(&self, dst: &S, paths: &[StorePath]) -> Result<()>
+    where
+        S: Store,
+    {
+        let closure = self.compute_closure(paths).await?;
+        let valid = dst
+            .query_valid_paths(&closure.iter().cloned().collect::<Vec<_>>())
+            .await?;
+        let sorted = sort_paths(self, closure).await?;
+
+        let mut objs = Vec::new();
+        let mut tmp_locks = Vec::new();
+        let res = async {
+            for path in sorted {
+                let Some(cache_info) = self.obj_info(&path).await? else {
+                    bail!("path {path} is not in the binary cache {}", self.url);
+                };
+                // the realisations of valid paths are still imported
+                if valid.contains(&path) {
+                    objs.push((cache_info.info, None));
+                    continue;
+                }
+                info!("substituting: {path} from {}", self.url);
+                let reader = self.source.reader(&cache_info.archive).await?;
+                let mut reader = cache_info.compression.decoder(reader);
+                let (tmp_path, tmp_lock) =
+                    unpack_obj(&dst.import_dir(), &cache_info.info, &mut reader).await?;
+                objs.push((cache_info.info, Some(tmp_path)));
+                tmp_locks.push(tmp_lock);
+            }
+            Ok::<_, anyhow::Error>(())
+        }
+        .await;
+        if let Err(e) = res {
+            for (_, tmp_path) in objs {
+                if let Some(tmp_path) = tmp_path {
+                    _ = remove_path(tmp_path).await;
+                }
+            }
+            return Err(e);
+        }
+        dst.import_paths(objs).await?;
+        drop(tmp_locks);
+        Ok(())
+    }
+}
+
+impl Store for BinaryCacheStore {
+    async fn add_to_store<P>(&self, _path: P, _opt: Opt) -> Result<StorePath>
+    where
+        P: AsRef<Path>,
+    {
+        bail!("binary cache {} is read-only", self.url);
+    }
+
+    async fn trusted_paths(&self, eq_class: &EqClass, out: &Out) -> Result<Vec<StorePath>> {
+        let Some(buff) = self.source.get(&realisation_key(eq_class, out)).await? else {
+            return Ok(Vec::new());
+        };
+        let index: RealisationIndex = toml::from_str(&String::from_utf8(buff)?)?;
+        // paths in a cache are never built locally, they must be signed
+        let policy = TrustPolicy::from_config()?;
+        let mut trusted = Vec::new();
+        for path in index.paths {
+            let Some(cache_info) = self.obj_info(&path).await? else {
+                continue;
+            };
+            let Some(r) = cache_info
+                .info
+                .realisations
+                .into_iter()
+                .find(|r| &r.eq_class == eq_class && &r.out == out)
+            else {
+                continue;
+            };
+            let realisation = Realisation {
+                eq_class: eq_class.clone(),
+                out: out.clone(),
+                path,
+            };
+            if policy.is_trusted(&fingerprint(self, &realisation).await?, &r.signatures) {
+                trusted.push(realisation.path);
+            }
+        }
+        Ok(trusted)
+    }
+
+    async fn realisation_refs(&self, realisation: &Realisation) -> Result<Vec<Realisation>> {
+        let Some(cache_info) = self.obj_info(&realisation.path).await? else {
+            bail!(
+                "path {} is not in the binary cache {}",
+                realisation.path,
+                self.url
+            );
+        };
+        let Some(r) = cache_info
+            .info
+            .realisations
+            .into_iter()
+            .find(|r| r.eq_class == realisation.eq_class && r.out == realisation.out)
+        else {
+            bail!(
+                "missing realisation {}!{} of {}",
+                realisation.eq_class,
+                realisation.out,
+                realisation.path
+            );
+        };
+        Ok(r.refs)
+    }
+
+    async fn query_realisations(&self, path: &StorePath) -> Result<Vec<RealisationInfo>> {
+        Ok(self
+            .obj_info(path)
+            .await?
+            .map(|cache_info| cache_info.info.realisations)
+            .unwrap_or_default())
+    }
+
+    /// the paths are only read again to be archived in the cache
+    fn import_dir(&self) -> PathBuf {
+        std::env::temp_dir()
+    }
+
+    async fn import_paths(&self, objs: Vec<(ObjInfo, Option<PathBuf>)>) -> Result<()> {
+        self.check_writable()?;
+        for (info, tmp_path) in objs {
+            // the metadata is read and written again, nobody else may write it in between
+            let lock = self.source.lock(&info_key(&info.path)).await?;
+            if let Some(cache_info) = self.obj_info(&info.path).await? {
+                self.merge_obj_info(cache_info, info).await?;
+            } else {
+                let Some(ref tmp_path) = tmp_path else {
+                    bail!("path {} is not in the binary cache {}", info.path, self.url);
+                };
+                self.write_obj(info, tmp_path).await?;
+            }
+            lock.unlock();
+            if let Some(tmp_path) = tmp_path {
+                remove_path(tmp_path).await?;
+            }
+        }
+        Ok(())
+    }
+
+    async fn dump_path<W>(&self, path: &StorePath, writer: &mut W) -> Result<()>
+    where
+        W: AsyncWrite + Unpin,
+    {
+        let Some(cache_info) = self.obj_info(path).await? else {
+            bail!("path {path} is not in the binary cache {}", self.url);
+        };
+        let reader = self.source.reader(&cache_info.archive).await?;
+        let mut reader = cache_info.compression.decoder(reader);
+        io::copy(&mut reader, writer).await?;
+        Ok(())
+    }
+
+    async fn add_signatures(&self, realisation: &Realisation, sigs: Vec<String>) -> Result<()> {
+        self.check_writable()?;
+        let lock = self.source.lock(&info_key(&realisation.path)).await?;
+        let Some(cache_info) = self.obj_info(&realisation.path).await? else {
+            bail!(
+                "path {} is not in the binary cache {}",
+                realisation.path,
+                self.url
+            );
+        };
+        let mut info = cache_info.info.clone();
+        info.realisations = vec![RealisationInfo {
+            eq_class: realisation.eq_class.clone(),
+            out: realisation.out.clone(),
+            refs: self.realisation_refs(realisation).await?,
+            signatures: sigs,
+        }];
+        self.merge_obj_info(cache_info, info).await?;
+        lock.unlock();
+        Ok(())
+    }
+
+    async fn query_path_info(&self, path: &StorePath) -> Result<Option<PathInfo>> {
+        Ok(self.obj_info(path).await?.map(|cache_info| PathInfo {
+            path: cache_info.info.path,
+            hash: cache_info.info.hash,
+            size: cache_info.size,
+            refs: cache_info.info.refs,
+            deriver: cache_info.info.deriver,
+            registration_time: None,
+        }))
+    }
+
+    async fn record_check(&self, _result: &CheckResult) -> Result<()> {
+        bail!("binary caches do not record checks");
+    }
+
+    async fn query_checks(&self) -> Result<Vec<CheckResult>> {
+        bail!("binary caches do not record checks");
+    }
+
+    async fn read_build_log<W>(&self, _drv: &StorePath, _writer: &mut W) -> Result<bool>
+    where
+        W: AsyncWrite + Unpin,
+    {
+        bail!("binary caches do not keep build logs");
+    }
+
+    async fn query_referrers(&self, _path: &StorePath) -> Result<BTreeSet<StorePath>> {
+        bail!("binary caches cannot list the referrers of a path");
+    }
+}
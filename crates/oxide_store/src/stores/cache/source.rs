@@ -0,0 +1,158 @@
+use crate::os::lock::{LockMode, PathLock};
+use crate::utils::{add_lock_ext, tempfile::temppath_in};
+use anyhow::{Result, bail};
+use futures_util::TryStreamExt;
+use reqwest::StatusCode;
+use std::{
+    io::ErrorKind,
+    path::{Path, PathBuf},
+    pin::Pin,
+    time::Duration,
+};
+use tokio::{
+    fs::{self, File},
+    io::{self, AsyncRead, AsyncWrite},
+};
+use tokio_util::io::StreamReader;
+
+pub type Reader = Pin<Box<dyn AsyncRead + Send>>;
+pub type Writer = Pin<Box<dyn AsyncWrite + Send>>;
+
+/// Where the files of a binary cache are read from
+pub(crate) enum Source {
+    Http {
+        client: reqwest::Client,
+        url: String,
+    },
+    File(PathBuf),
+}
+
+/// keys come from the metadata of the cache, they must stay inside of it
+fn check_key(key: &str) -> Result<()> {
+    if key.starts_with('/') || key.contains('\0') || key.split('/').any(|c| c == "..") {
+        bail!("invalid binary cache key {key}");
+    }
+    Ok(())
+}
+
+impl Source {
+    pub fn new(url: &str) -> Result<Self> {
+        if let Some(path) = url.strip_prefix("file://") {
+            Ok(Self::File(PathBuf::from(path)))
+        } else if url.starts_with("http://") || url.starts_with("https://") {
+            // no total timeout, archives can take a long time to download
+            let client = reqwest::Client::builder()
+                .connect_timeout(Duration::from_secs(10))
+                .build()?;
+            Ok(Self::Http {
+                client,
+                url: url.trim_end_matches('/').to_string(),
+            })
+        } else {
+            bail!("unsupported binary cache url {url}");
+        }
+    }
+
+    /// `None` if `key` is not in the cache
+    pub async fn get(&self, key: &str) -> Result<Option<Vec<u8>>> {
+        check_key(key)?;
+        match self {
+            Self::Http { client, url } => {
+                let response = client.get(format!("{url}/{key}")).send().await?;
+                let status = response.status();
+                if status == StatusCode::NOT_FOUND || status == StatusCode::FORBIDDEN {
+                    return Ok(None);
+                }
+                if !status.is_success() {
+                    bail!("failed to download {url}/{key} with code {status}");
+                }
+                Ok(Some(response.bytes().await?.to_vec()))
+            }
+            Self::File(dir) => match fs::read(dir.join(key)).await {
+                Ok(buff) => Ok(Some(buff)),
+                Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+                Err(e) => Err(e.into()),
+            },
+        }
+    }
+
+    pub async fn reader(&self, key: &str) -> Result<Reader> {
+        check_key(key)?;
+        match self {
+            Self::Http { client, url } => {
+                let response = client.get(format!("{url}/{key}")).send().await?;
+                if !response.status().is_success() {
+                    bail!(
+                        "failed to download {url}/{key} with code {}",
+                        response.status()
+                    );
+                }
+                let stream = response.bytes_stream().map_err(io::Error::other);
+                Ok(Box::pin(StreamReader::new(stream)))
+            }
+            Self::File(dir) => Ok(Box::pin(File::open(dir.join(key)).await?)),
+        }
+    }
+
+    /// only local directories can be written
+    pub fn is_writable(&self) -> bool {
+        matches!(self, Self::File(_))
+    }
+
+    /// a temporary path next to `key`, see [`Source::commit`]
+    pub async fn temp_path(&self, key: &str) -> Result<PathBuf> {
+        check_key(key)?;
+        let Self::File(dir) = self else {
+            bail!("http binary caches are read-only");
+        };
+        let path = dir.join(key);
+        let parent = path.parent().unwrap();
+        fs::create_dir_all(parent).await?;
+        Ok(temppath_in(parent))
+    }
+
+    /// atomically move a file written at a temporary path to `key`
+    pub async fn commit(&self, tmp_path: &Path, key: &str) -> Result<()> {
+        check_key(key)?;
+        let Self::File(dir) = self else {
+            bail!("http binary caches are read-only");
+        };
+        fs::rename(tmp_path, dir.join(key)).await?;
+        Ok(())
+    }
+
+    /// lock `key` so that it can be read and written again without losing other writes
+    pub async fn lock(&self, key: &str) -> Result<PathLock> {
+        check_key(key)?;
+        let Self::File(dir) = self else {
+            bail!("http binary caches are read-only");
+        };
+        let path = dir.join(key);
+        fs::create_dir_all(path.parent().unwrap()).await?;
+        PathLock::lock_async(add_lock_ext(path), LockMode::Write).await
+    }
+
+    pub async fn put(&self, key: &str, buff: &[u8]) -> Result<()> {
+        let tmp_path = self.temp_path(key).await?;
+        if let Err(e) = fs::write(&tmp_path, buff).await {
+            _ = fs::remove_file(&tmp_path).await;
+            return Err(e.into());
+        }
+        self.commit(&tmp_path, key).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::check_key;
+
+    #[test]
+    fn keys_stay_inside_of_the_cache() {
+        assert!(check_key("archives/abc-hello.oar.zst").is_ok());
+        assert!(check_key("info/..abc").is_ok());
+        assert!(check_key("/etc/passwd").is_err());
+        assert!(check_key("archives/../../etc/passwd").is_err());
+        assert!(check_key("..").is_err());
+        assert!(check_key("info/a\0b").is_err());
+    }
+}
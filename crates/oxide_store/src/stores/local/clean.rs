@@ -0,0 +1,82 @@
+use super::{LOCAL_STORE_CONFIG, LocalStore, optimise::LINKS_DIR};
+use crate::api::Store;
+use crate::os::lock::{LockMode, PathLock};
+use crate::utils::tempfile::is_temp;
+use crate::utils::{add_lock_ext, path_size, remove_path, strip_lock_ext};
+use anyhow::Result;
+use log::info;
+use std::{
+    path::Path,
+    time::{Duration, SystemTime},
+};
+use tokio::fs;
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct CleanTempStats {
+    pub entries_removed: u64,
+    pub bytes_freed: u64,
+}
+
+impl LocalStore {
+    /// remove what crashed or failed builds and imports left in the store:
+    /// build directories, temporary files, outputs that were never registered and lock files
+    ///
+    /// an entry is only removed if its lock is free and it was not modified for `min_age`,
+    /// not every writer of temporary files locks them
+    pub async fn clean_temp(&self, min_age: Duration) -> Result<CleanTempStats> {
+        let lock = PathLock::lock_async(&LOCAL_STORE_CONFIG.gc_lock_path, LockMode::Write).await?;
+        let cutoff = SystemTime::now() - min_age;
+        let mut stats = CleanTempStats::default();
+        let mut entries = fs::read_dir(Self::store_dir()).await?;
+        while let Some(entry) = entries.next_entry().await? {
+            let path = entry.path();
+            let name = entry.file_name().to_string_lossy().into_owned();
+            if name == LINKS_DIR {
+                continue;
+            }
+            // registered paths can look like lock files, as `<hash>-Cargo.lock`
+            let p = Self::parse_store_path(&name).ok();
+            if let Some(ref p) = p
+                && self.valid(p).await?
+            {
+                continue;
+            }
+            if p.is_none() && !is_temp(&path) {
+                continue;
+            }
+            // an output that looks like a lock file is locked while it is built
+            let Some(entry_lock) = PathLock::try_lock(add_lock_ext(&path), LockMode::Write)? else {
+                continue;
+            };
+            if let Some(owner) = strip_lock_ext(&name)
+                && (is_temp(owner) || Self::parse_store_path(owner).is_ok())
+                && is_lock_file(&path).await
+            {
+                // a lock that can be taken is stale and releasing it deletes the file
+                _ = PathLock::try_lock(&path, LockMode::Write)?;
+                continue;
+            }
+            // it may have been removed by its owner in the meantime
+            let Ok(metadata) = fs::symlink_metadata(&path).await else {
+                continue;
+            };
+            if metadata.modified()? > cutoff {
+                continue;
+            }
+            info!("clean-temp: removing {}", path.display());
+            stats.bytes_freed += path_size(&path).await?;
+            remove_path(&path).await?;
+            stats.entries_removed += 1;
+            entry_lock.unlock();
+        }
+        lock.unlock();
+        Ok(stats)
+    }
+}
+
+/// locks are empty files, the ones that are released get a byte after they are deleted
+async fn is_lock_file(path: &Path) -> bool {
+    fs::symlink_metadata(path)
+        .await
+        .is_ok_and(|m| m.is_file() && m.len() == 0)
+}
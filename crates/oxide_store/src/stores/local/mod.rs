@@ -1,29 +1,43 @@
+mod clean;
+mod optimise;
 mod queries;
 
+pub use clean::*;
+pub use optimise::*;
+
 use crate::api::{CONFIG, Opt, Store};
+use crate::build::write_log;
 use crate::hash::utils::make_path;
-use crate::hash::{hash_mod_rewrites, rewrite_self_hash, rewrite_store_path};
+use crate::hash::{hash_mod_rewrites, rewrite_self_hash, rewrite_store_path, scan_for_refs};
+use crate::os::build_users::check_store_dir;
 use crate::os::lock::{LockMode, PathLock};
-use crate::types::{Realisation, StoreObj};
-use crate::utils::{add_lock_ext, is_valid_name};
+use crate::signing::{TrustPolicy, fingerprint};
+use crate::types::{CheckResult, ID, ObjInfo, PathInfo, Realisation, RealisationInfo, StoreObj};
+use crate::utils::{add_lock_ext, is_valid_name, path_size, remove_path};
 use anyhow::{Result, bail};
-use log::info;
+use log::{info, warn};
 use oxide_core::store::StorePath;
 use oxide_core::types::{EqClass, Out};
 use oxide_core::utils::file_type_to_permission;
 use sqlx::SqlitePool;
 use sqlx::migrate::Migrator;
 use std::cell::LazyCell;
+use std::collections::{BTreeSet, HashSet};
 use std::fs::Permissions;
 use std::os::unix::fs::PermissionsExt;
 use std::path::Path;
 use std::path::PathBuf;
 use tokio::fs;
+use tokio::io::AsyncWrite;
 
 pub struct LocalStoreConfig {
     pub db_dir: String,
     pub db_path: String,
     pub migrations_dir: String,
+    /// taken by whoever must not race with a garbage collection,
+    /// nothing collects garbage yet so it only keeps `optimise` and `clean-temp` apart,
+    /// paths being optimised as they are added share it
+    pub gc_lock_path: String,
 }
 
 impl LocalStoreConfig {
@@ -31,10 +45,12 @@ impl LocalStoreConfig {
         let db_dir = format!("{}/db", CONFIG.state_dir);
         let db_path = format!("{db_dir}/sqlite.db");
         let migrations_dir = format!("{db_dir}/migrations");
+        let gc_lock_path = format!("{}/gc.lock", CONFIG.state_dir);
         Self {
             db_dir,
             db_path,
             migrations_dir,
+            gc_lock_path,
         }
     }
 }
@@ -47,17 +63,35 @@ impl Default for LocalStoreConfig {
 
 pub const LOCAL_STORE_CONFIG: LazyCell<LocalStoreConfig> = LazyCell::new(LocalStoreConfig::new);
 
+#[derive(Clone)]
 pub struct LocalStore {
     db: SqlitePool,
 }
 
 impl LocalStore {
     pub async fn new() -> Result<Self> {
+        check_store_dir(&Self::store_dir()).await?;
         let db = SqlitePool::connect(&format!("sqlite://{}", LOCAL_STORE_CONFIG.db_path)).await?;
         let m = Migrator::new(PathBuf::from(&LOCAL_STORE_CONFIG.migrations_dir)).await?;
         m.run(&db).await?;
         Ok(Self { db })
     }
+
+    /// a store using a new database in `dir`
+    #[cfg(test)]
+    pub(crate) async fn with_db_in(dir: &Path) -> Result<Self> {
+        let url = format!("sqlite://{}/sqlite.db?mode=rwc", dir.display());
+        let db = SqlitePool::connect(&url).await?;
+        let migrations = Path::new(env!("CARGO_MANIFEST_DIR")).join("../../migrations");
+        Migrator::new(migrations).await?.run(&db).await?;
+        Ok(Self { db })
+    }
+
+    /// start a transaction holding the write lock, parallel builds then wait
+    /// for each other instead of failing to upgrade a read lock
+    async fn write_tx(&self) -> Result<sqlx::SqliteTransaction<'static>> {
+        Ok(self.db.begin_with("BEGIN IMMEDIATE").await?)
+    }
 }
 
 impl Store for LocalStore {
@@ -77,26 +111,33 @@ impl Store for LocalStore {
             info!("add to store: {path}");
             let full_path = Self::store_path(&path);
             let lock_file = add_lock_ext(&full_path);
-            let lock = PathLock::lock(lock_file, LockMode::Write)?;
+            let lock = PathLock::lock_async(lock_file, LockMode::Write).await?;
             if fix || !self.valid(&path).await? {
                 self.copy_path(&p, &full_path).await?;
                 if let Some(self_hash) = opt.self_hash {
                     rewrite_self_hash(&full_path, &self_hash, &path).await?;
                     opt.rewrites.insert(self_hash, path.clone());
                 }
+                Self::set_store_permissions(&full_path).await?;
                 let mut refs = Vec::new();
                 for mut r in opt.refs {
                     rewrite_store_path(&mut r, &opt.rewrites);
                     refs.push(r);
                 }
+                let size = path_size(&full_path).await?;
                 self.register_store_obj(
                     StoreObj {
                         path: path.clone(),
                         hash,
+                        size,
+                        deriver: opt.deriver,
                     },
                     refs,
                 )
                 .await?;
+                if CONFIG.auto_optimise {
+                    self.optimise_path(&full_path).await?;
+                }
             }
             lock.unlock();
         }
@@ -118,16 +159,123 @@ impl Store for LocalStore {
         Ok(path)
     }
 
-    // TODO: for now every path is trusted
-    // this is not secure and it must be changed
-    // maybe add a set of trusted distributors in config
+    /// realisations built locally or signed by enough trusted keys
     async fn trusted_paths(&self, eq_class: &EqClass, out: &Out) -> Result<Vec<StorePath>> {
-        self.get_realisation_paths(eq_class, out).await
+        let policy = TrustPolicy::from_config()?;
+        let mut trusted = Vec::new();
+        for path in self.get_realisation_paths(eq_class, out).await? {
+            let r = Realisation {
+                eq_class: eq_class.clone(),
+                out: out.clone(),
+                path,
+            };
+            if self.is_local_realisation(&r).await?
+                || policy.is_trusted(
+                    &fingerprint(self, &r).await?,
+                    &self.get_signatures(&r).await?,
+                )
+            {
+                trusted.push(r.path);
+            }
+        }
+        Ok(trusted)
     }
 
     async fn realisation_refs(&self, realisation: &Realisation) -> Result<Vec<Realisation>> {
         self.get_realisation_refs(realisation).await
     }
+
+    async fn query_realisations(&self, path: &StorePath) -> Result<Vec<RealisationInfo>> {
+        let mut realisations = Vec::new();
+        for r in self.get_path_realisations(path).await? {
+            let refs = self.get_realisation_refs(&r).await?;
+            let signatures = self.get_signatures(&r).await?;
+            realisations.push(RealisationInfo {
+                eq_class: r.eq_class,
+                out: r.out,
+                refs,
+                signatures,
+            });
+        }
+        Ok(realisations)
+    }
+
+    async fn import_paths(&self, objs: Vec<(ObjInfo, Option<PathBuf>)>) -> Result<()> {
+        // like in add_to_store the locks come before the transaction,
+        // sorted so that two imports of the same paths cannot wait on each other
+        let paths = objs
+            .iter()
+            .map(|(info, _)| Self::store_path(&info.path))
+            .collect::<BTreeSet<_>>();
+        let mut locks = Vec::new();
+        for path in &paths {
+            locks.push(PathLock::lock_async(add_lock_ext(path), LockMode::Write).await?);
+        }
+        let mut copied = Vec::new();
+        let res = self.import_paths_locked(objs, &mut copied).await;
+        if res.is_err() {
+            // nothing was registered, do not leave unknown paths in the store
+            for path in copied {
+                if let Err(e) = remove_path(&path).await {
+                    warn!("import: could not remove {path}: {e}");
+                }
+            }
+        }
+        for lock in locks {
+            lock.unlock();
+        }
+        res
+    }
+
+    async fn add_signatures(&self, realisation: &Realisation, sigs: Vec<String>) -> Result<()> {
+        let mut tx = self.write_tx().await?;
+        let Some(id) = Self::is_realisation(&mut tx, realisation).await? else {
+            bail!(
+                "missing realisation {}!{} of {}",
+                realisation.eq_class,
+                realisation.out,
+                realisation.path
+            );
+        };
+        for sig in sigs {
+            Self::add_signature(&mut tx, id, &sig).await?;
+        }
+        tx.commit().await?;
+        Ok(())
+    }
+
+    async fn record_check(&self, result: &CheckResult) -> Result<()> {
+        self.add_check(result).await
+    }
+
+    async fn query_checks(&self) -> Result<Vec<CheckResult>> {
+        self.get_checks().await
+    }
+
+    async fn read_build_log<W>(&self, drv: &StorePath, writer: &mut W) -> Result<bool>
+    where
+        W: AsyncWrite + Unpin,
+    {
+        write_log(drv, writer).await
+    }
+
+    async fn query_path_info(&self, path: &StorePath) -> Result<Option<PathInfo>> {
+        self.get_path_info(path).await
+    }
+
+    async fn query_referrers(&self, path: &StorePath) -> Result<BTreeSet<StorePath>> {
+        self.get_referrers(path).await
+    }
+
+    async fn query_valid_paths(&self, paths: &[StorePath]) -> Result<BTreeSet<StorePath>> {
+        let mut valid = BTreeSet::new();
+        for path in paths {
+            if self.valid(path).await? {
+                valid.insert(path.clone());
+            }
+        }
+        Ok(valid)
+    }
 }
 
 impl LocalStore {
@@ -145,6 +293,105 @@ impl LocalStore {
         }
     }
 
+    /// copy and register `objs` in a single transaction, the paths must be locked,
+    /// the ones copied into the store are added to `copied`
+    async fn import_paths_locked(
+        &self,
+        objs: Vec<(ObjInfo, Option<PathBuf>)>,
+        copied: &mut Vec<String>,
+    ) -> Result<()> {
+        let imported = objs
+            .iter()
+            .map(|(info, _)| info.path.clone())
+            .collect::<HashSet<_>>();
+        let mut tx = self.write_tx().await?;
+        let mut new_objs = Vec::new();
+        for (info, tmp_path) in &objs {
+            let full_path = Self::store_path(&info.path);
+            if self.valid(&info.path).await? {
+                if let Some(tmp_path) = tmp_path {
+                    remove_path(tmp_path).await?;
+                }
+                continue;
+            }
+            let Some(tmp_path) = tmp_path else {
+                bail!("path {} is not valid", info.path);
+            };
+            info!("import: {}", info.path);
+            self.copy_path(tmp_path, &full_path).await?;
+            copied.push(full_path.clone());
+            Self::set_store_permissions(&full_path).await?;
+
+            // the references come from whoever exported the path, check them against its content
+            let mut hashes = imported.clone();
+            hashes.extend(info.refs.iter().cloned());
+            let found = scan_for_refs(&full_path, hashes).await?;
+            if found.len() != info.refs.len() || found.iter().any(|r| !info.refs.contains(r)) {
+                bail!("the references of {} do not match its content", info.path);
+            }
+            let size = path_size(&full_path).await?;
+            new_objs.push(StoreObj {
+                path: info.path.clone(),
+                hash: info.hash.clone(),
+                size,
+                deriver: info.deriver.clone(),
+            });
+        }
+
+        let mut ids = Vec::new();
+        for obj in &new_objs {
+            ids.push(Self::add_store_obj(&mut tx, obj).await?);
+        }
+        // references can only be added once every path has been added
+        for (obj, referrer) in new_objs.iter().zip(ids) {
+            let (info, _) = objs.iter().find(|(info, _)| info.path == obj.path).unwrap();
+            for r in &info.refs {
+                let references = Self::get_store_obj_id(&mut tx, r).await?;
+                Self::add_ref(&mut tx, referrer, references).await?;
+            }
+        }
+        let realisations = objs
+            .into_iter()
+            .flat_map(|(info, _)| {
+                let path = info.path;
+                info.realisations.into_iter().map(move |r| {
+                    (
+                        Realisation {
+                            eq_class: r.eq_class,
+                            out: r.out,
+                            path: path.clone(),
+                        },
+                        r.refs,
+                        r.signatures,
+                    )
+                })
+            })
+            .collect::<Vec<_>>();
+        let mut ids = Vec::new();
+        for (realisation, _, sigs) in &realisations {
+            let id = Self::get_or_add_realisation(&mut tx, realisation, false).await?;
+            for sig in sigs {
+                Self::add_signature(&mut tx, id, sig).await?;
+            }
+            ids.push(id);
+        }
+        for ((_, eq_refs, _), referrer) in realisations.iter().zip(ids) {
+            for eq_ref in eq_refs {
+                let Some(references) = Self::is_realisation(&mut tx, eq_ref).await? else {
+                    bail!(
+                        "missing realisation {}!{} of {}",
+                        eq_ref.eq_class,
+                        eq_ref.out,
+                        eq_ref.path
+                    );
+                };
+                Self::add_realisation_ref(&mut tx, referrer, references).await?;
+            }
+        }
+        tx.commit().await?;
+        Ok(())
+    }
+
     async fn copy_path<P, Q>(&self, src: P, dst: Q) -> Result<()>
     where
         P: AsRef<Path>,
@@ -153,7 +400,6 @@ impl LocalStore {
         let src = src.as_ref();
         let dst = dst.as_ref();
         let metadata = fs::metadata(&src).await?;
-        let mode = file_type_to_permission(&metadata);
         // if it is a fixed-output derivation src and dst are equal
         if src != dst {
             // delete dst if already exists
@@ -176,13 +422,23 @@ impl LocalStore {
                 fs::copy(&src, &dst).await?;
             }
         }
-        fs::set_permissions(&dst, Permissions::from_mode(mode)).await?;
+        Ok(())
+    }
+
+    /// make `p` read-only once nothing has to write to it anymore
+    async fn set_store_permissions<P>(p: P) -> Result<()>
+    where
+        P: AsRef<Path>,
+    {
+        let metadata = fs::metadata(&p).await?;
+        let mode = file_type_to_permission(&metadata);
+        fs::set_permissions(&p, Permissions::from_mode(mode)).await?;
         Ok(())
     }
 
     // TODO: check for cycles
     async fn register_store_obj(&self, obj: StoreObj, refs: Vec<StorePath>) -> Result<()> {
-        let mut tx = self.db.begin().await?;
+        let mut tx = self.write_tx().await?;
         let referrer = if Self::is_store_obj(&mut tx, &obj.path).await? {
             Self::update_store_obj(&mut tx, &obj).await?
         } else {
@@ -202,16 +458,36 @@ impl LocalStore {
         realisation: Realisation,
         eq_refs: Vec<Realisation>,
     ) -> Result<()> {
-        // TODO: should we do this in a transaction???
-        let referrer = if let Some(referrer) = self.is_realisation(&realisation).await? {
-            referrer
-        } else {
-            self.add_realisation(&realisation).await?
-        };
+        let mut tx = self.write_tx().await?;
+        let referrer = Self::get_or_add_realisation(&mut tx, &realisation, true).await?;
         for eq_ref in eq_refs {
-            let references = self.get_realisation_id(&eq_ref).await?;
-            self.add_realisation_ref(referrer, references).await?;
+            let Some(references) = Self::is_realisation(&mut tx, &eq_ref).await? else {
+                bail!(
+                    "missing realisation {}!{} of {}",
+                    eq_ref.eq_class,
+                    eq_ref.out,
+                    eq_ref.path
+                );
+            };
+            Self::add_realisation_ref(&mut tx, referrer, references).await?;
         }
+        tx.commit().await?;
         Ok(())
     }
+
+    /// `local` is true if the realisation was built on this machine
+    async fn get_or_add_realisation(
+        tx: &mut sqlx::SqliteTransaction<'static>,
+        realisation: &Realisation,
+        local: bool,
+    ) -> Result<ID> {
+        if let Some(id) = Self::is_realisation(tx, realisation).await? {
+            if local {
+                Self::set_realisation_local(tx, id).await?;
+            }
+            Ok(id)
+        } else {
+            Self::add_realisation(tx, realisation, local).await
+        }
+    }
 }
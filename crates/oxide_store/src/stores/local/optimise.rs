@@ -0,0 +1,227 @@
+use super::{LOCAL_STORE_CONFIG, LocalStore};
+use crate::api::Store;
+use crate::hash::{EXEC_TYPE, FILE_TYPE};
+use crate::os::lock::{LockMode, PathLock};
+use crate::utils::tempfile::temppath_in;
+use anyhow::Result;
+use base64::Engine;
+use log::{info, warn};
+use oxide_core::hash::BASE64;
+use oxide_core::utils::file_type_to_permission;
+use sha2::{Digest, Sha512};
+use std::fs::{Metadata, Permissions};
+use std::io::ErrorKind;
+use std::os::unix::fs::{MetadataExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+use tokio::fs::{self, File};
+use tokio::io::AsyncReadExt;
+
+/// directory inside of the store containing one hard link for every optimised file
+/// the name of each link is the hash of the content of the file
+pub const LINKS_DIR: &str = ".links";
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct OptimiseStats {
+    pub files_linked: u64,
+    pub bytes_freed: u64,
+}
+
+impl LocalStore {
+    fn links_dir() -> PathBuf {
+        Path::new(&Self::store_dir()).join(LINKS_DIR)
+    }
+
+    /// replace every file in the store that has the same content of another one with a hard link
+    pub async fn optimise_store(&self) -> Result<OptimiseStats> {
+        // a gc must not delete paths or links while we are linking them
+        let lock = PathLock::lock_async(&LOCAL_STORE_CONFIG.gc_lock_path, LockMode::Write).await?;
+        let links = Self::links_dir();
+        fs::create_dir_all(&links).await?;
+        let mut stats = OptimiseStats::default();
+        for path in self.all_store_paths().await? {
+            let full_path = Self::store_path(&path);
+            optimise_path_helper(&links, Path::new(&full_path), &mut stats).await?;
+        }
+        stats.bytes_freed += prune_links(&links).await?;
+        lock.unlock();
+        info!(
+            "optimise: {} files hard-linked, {} bytes freed",
+            stats.files_linked, stats.bytes_freed
+        );
+        Ok(stats)
+    }
+
+    /// optimise a single path that was just added to the store,
+    /// paths added at the same time are optimised in parallel
+    pub(super) async fn optimise_path<P>(&self, path: P) -> Result<OptimiseStats>
+    where
+        P: AsRef<Path>,
+    {
+        let lock = PathLock::lock_async(&LOCAL_STORE_CONFIG.gc_lock_path, LockMode::Read).await?;
+        let links = Self::links_dir();
+        fs::create_dir_all(&links).await?;
+        let mut stats = OptimiseStats::default();
+        optimise_path_helper(&links, path.as_ref(), &mut stats).await?;
+        lock.unlock();
+        Ok(stats)
+    }
+}
+
+/// link the files of `path` to the ones with the same content in `links`
+async fn optimise_path_helper(links: &Path, path: &Path, stats: &mut OptimiseStats) -> Result<()> {
+    let metadata = fs::symlink_metadata(path).await?;
+    if metadata.is_dir() {
+        let mut entries = fs::read_dir(path).await?;
+        while let Some(entry) = entries.next_entry().await? {
+            Box::pin(optimise_path_helper(links, &entry.path(), stats)).await?;
+        }
+    } else if metadata.is_file() && metadata.len() > 0 {
+        optimise_file(links, path, &metadata, stats).await?;
+    }
+    Ok(())
+}
+
+async fn optimise_file(
+    links: &Path,
+    path: &Path,
+    metadata: &Metadata,
+    stats: &mut OptimiseStats,
+) -> Result<()> {
+    let link = links.join(hash_file_content(path, metadata).await?);
+    let link_metadata = match fs::symlink_metadata(&link).await {
+        Ok(link_metadata) => link_metadata,
+        Err(e) if e.kind() == ErrorKind::NotFound => {
+            // first time we see this content: make it canonical and remember it
+            let mode = file_type_to_permission(metadata);
+            if metadata.permissions().mode() & 0o777 != mode {
+                fs::set_permissions(path, Permissions::from_mode(mode)).await?;
+            }
+            match fs::hard_link(path, &link).await {
+                Ok(()) => return Ok(()),
+                // someone else linked the same content in the meantime
+                Err(e) if e.kind() == ErrorKind::AlreadyExists => {
+                    fs::symlink_metadata(&link).await?
+                }
+                Err(e) => return Err(e.into()),
+            }
+        }
+        Err(e) => return Err(e.into()),
+    };
+    if link_metadata.ino() == metadata.ino() {
+        // already linked
+        return Ok(());
+    }
+
+    // copy_path makes directories read-only, give us back the permission to write
+    let parent = path.parent().unwrap();
+    let parent_mode = fs::metadata(parent).await?.permissions().mode();
+    fs::set_permissions(parent, Permissions::from_mode(parent_mode | 0o200)).await?;
+    let res = replace_with_link(parent, path, &link).await;
+    fs::set_permissions(parent, Permissions::from_mode(parent_mode)).await?;
+    match res {
+        Ok(()) => {
+            stats.files_linked += 1;
+            // the old inode is only gone if this was its last link
+            if metadata.nlink() == 1 {
+                stats.bytes_freed += metadata.len();
+            }
+        }
+        // too many links to the same inode
+        Err(e) if e.raw_os_error() == Some(libc::EMLINK) => {
+            warn!("optimise: {} has too many links", link.display());
+        }
+        Err(e) => return Err(e.into()),
+    }
+    Ok(())
+}
+
+/// atomically replace `path` with a hard link to `link`
+async fn replace_with_link(parent: &Path, path: &Path, link: &Path) -> std::io::Result<()> {
+    let tmp = temppath_in(parent);
+    fs::hard_link(link, &tmp).await?;
+    if let Err(e) = fs::rename(&tmp, path).await {
+        _ = fs::remove_file(&tmp).await;
+        return Err(e);
+    }
+    Ok(())
+}
+
+async fn hash_file_content(path: &Path, metadata: &Metadata) -> Result<String> {
+    let mut hasher = Sha512::new();
+    // files with different permissions cannot share the same inode
+    let ft = if metadata.permissions().mode() & 0o111 == 0 {
+        FILE_TYPE
+    } else {
+        EXEC_TYPE
+    };
+    hasher.update(ft.to_be_bytes());
+    let mut file = File::open(path).await?;
+    let mut buff = vec![0; 64 * 1024];
+    loop {
+        let n = file.read(&mut buff).await?;
+        if n == 0 {
+            break;
+        }
+        hasher.update(&buff[..n]);
+    }
+    Ok(BASE64.encode(hasher.finalize()))
+}
+
+/// delete the links that are not used by any store path anymore,
+/// returns the size of the inodes that were freed by it
+async fn prune_links(links: &Path) -> Result<u64> {
+    let mut freed = 0;
+    let mut entries = fs::read_dir(links).await?;
+    while let Some(entry) = entries.next_entry().await? {
+        let metadata = entry.metadata().await?;
+        // store paths still using the content keep the inode alive
+        if metadata.nlink() == 1 {
+            fs::remove_file(entry.path()).await?;
+            freed += metadata.len();
+        }
+    }
+    Ok(freed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::{remove_path, tempfile::tempdir_in};
+
+    #[tokio::test]
+    async fn identical_files_share_an_inode() -> Result<()> {
+        let dir = tempdir_in(std::env::temp_dir()).await?;
+        let links = dir.join(LINKS_DIR);
+        let path = dir.join("path");
+        fs::create_dir_all(&links).await?;
+        fs::create_dir_all(path.join("sub")).await?;
+        fs::write(path.join("a"), "same content").await?;
+        fs::write(path.join("sub/b"), "same content").await?;
+        fs::write(path.join("c"), "other content").await?;
+        fs::write(path.join("empty"), "").await?;
+
+        let mut stats = OptimiseStats::default();
+        optimise_path_helper(&links, &path, &mut stats).await?;
+        let ino = async |p: &str| Ok::<_, anyhow::Error>(fs::metadata(path.join(p)).await?.ino());
+        assert_eq!(ino("a").await?, ino("sub/b").await?);
+        assert_ne!(ino("a").await?, ino("c").await?);
+        assert_eq!(stats.files_linked, 1);
+        // only the inode of the second copy is gone
+        assert_eq!(stats.bytes_freed, "same content".len() as u64);
+
+        // linking again changes nothing
+        let mut stats = OptimiseStats::default();
+        optimise_path_helper(&links, &path, &mut stats).await?;
+        assert_eq!((stats.files_linked, stats.bytes_freed), (0, 0));
+
+        // every link is still used by a file
+        assert_eq!(prune_links(&links).await?, 0);
+        fs::remove_file(path.join("c")).await?;
+        assert_eq!(prune_links(&links).await?, "other content".len() as u64);
+        // "a", "sub/b" and their link
+        assert_eq!(fs::metadata(path.join("a")).await?.nlink(), 3);
+
+        remove_path(&dir).await?;
+        Ok(())
+    }
+}
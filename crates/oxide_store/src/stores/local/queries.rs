@@ -2,11 +2,21 @@
 
 use super::LocalStore;
 use crate::api::Store;
-use crate::types::{ID, Realisation, StoreObj};
-use anyhow::Result;
+use crate::types::{CheckResult, ID, PathInfo, Realisation, StoreObj};
+use crate::utils::path_size;
+use anyhow::{Result, anyhow};
+use oxide_core::hash::Hash;
 use oxide_core::store::StorePath;
 use oxide_core::types::{EqClass, Out};
 use sqlx::Row;
+use std::collections::BTreeSet;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+fn now() -> Result<i64> {
+    Ok(i64::try_from(
+        SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs(),
+    )?)
+}
 
 impl LocalStore {
     pub(super) async fn valid(&self, path: &StorePath) -> Result<bool> {
@@ -17,6 +27,16 @@ impl LocalStore {
             .is_some())
     }
 
+    pub(super) async fn all_store_paths(&self) -> Result<Vec<StorePath>> {
+        let rows = sqlx::query("SELECT path FROM store_obj")
+            .fetch_all(&self.db)
+            .await?;
+        Ok(rows
+            .into_iter()
+            .map(|row| Self::path_to_store(row.get(0)))
+            .collect())
+    }
+
     pub(super) async fn is_store_obj(
         tx: &mut sqlx::SqliteTransaction<'static>,
         path: &StorePath,
@@ -43,12 +63,18 @@ impl LocalStore {
         tx: &mut sqlx::SqliteTransaction<'static>,
         obj: &StoreObj,
     ) -> Result<ID> {
-        let (id, ..): (ID,) =
-            sqlx::query_as("INSERT INTO store_obj (path, hash) VALUES (?, ?) RETURNING id")
-                .bind(Self::store_path(&obj.path))
-                .bind(obj.hash.base64_with_algo())
-                .fetch_one(&mut **tx)
-                .await?;
+        let (id, ..): (ID,) = sqlx::query_as(
+            r#"INSERT INTO store_obj (path, hash, size, deriver, registration_time)
+            VALUES (?, ?, ?, ?, ?)
+            RETURNING id"#,
+        )
+        .bind(Self::store_path(&obj.path))
+        .bind(obj.hash.base64_with_algo())
+        .bind(i64::try_from(obj.size)?)
+        .bind(obj.deriver.as_ref().map(Self::store_path))
+        .bind(now()?)
+        .fetch_one(&mut **tx)
+        .await?;
         Ok(id)
     }
 
@@ -56,15 +82,98 @@ impl LocalStore {
         tx: &mut sqlx::SqliteTransaction<'static>,
         obj: &StoreObj,
     ) -> Result<ID> {
-        let (id, ..): (ID,) =
-            sqlx::query_as("UPDATE store_obj SET hash = ? WHERE path = ? RETURNING id")
-                .bind(obj.hash.base64_with_algo())
-                .bind(Self::store_path(&obj.path))
-                .fetch_one(&mut **tx)
-                .await?;
+        let (id, ..): (ID,) = sqlx::query_as(
+            r#"UPDATE store_obj
+            SET hash = ?, size = ?, deriver = COALESCE(?, deriver), registration_time = ?
+            WHERE path = ?
+            RETURNING id"#,
+        )
+        .bind(obj.hash.base64_with_algo())
+        .bind(i64::try_from(obj.size)?)
+        .bind(obj.deriver.as_ref().map(Self::store_path))
+        .bind(now()?)
+        .bind(Self::store_path(&obj.path))
+        .fetch_one(&mut **tx)
+        .await?;
         Ok(id)
     }
 
+    pub(super) async fn get_path_info(&self, path: &StorePath) -> Result<Option<PathInfo>> {
+        let Some(row) = sqlx::query(
+            r#"SELECT id, hash, size, deriver, registration_time
+            FROM store_obj
+            WHERE path = ?"#,
+        )
+        .bind(Self::store_path(path))
+        .fetch_optional(&self.db)
+        .await?
+        else {
+            return Ok(None);
+        };
+        let id: ID = row.get(0);
+        let hash: String = row.get(1);
+        let hash = Hash::try_from(hash.as_str()).map_err(|_| anyhow!("invalid hash {hash}"))?;
+        let deriver: Option<String> = row.get(3);
+        let refs = sqlx::query(
+            r#"
+            SELECT o.path
+            FROM ref r
+            JOIN store_obj o ON o.id = r.reference
+            WHERE r.referrer = ?
+            "#,
+        )
+        .bind(id)
+        .fetch_all(&self.db)
+        .await?
+        .into_iter()
+        .map(|row| Self::path_to_store(row.get(0)))
+        .collect();
+        let size = match u64::try_from(row.get::<i64, _>(2)) {
+            Ok(size) => size,
+            Err(_) => self.backfill_size(id, path).await?,
+        };
+        // paths registered before registration times were recorded have 0
+        let registration_time = u64::try_from(row.get::<i64, _>(4))?;
+        Ok(Some(PathInfo {
+            path: path.clone(),
+            hash,
+            size,
+            refs,
+            deriver: deriver.as_deref().map(Self::path_to_store),
+            registration_time: (registration_time != 0).then_some(registration_time),
+        }))
+    }
+
+    /// compute and record the size of a path registered before sizes were recorded
+    async fn backfill_size(&self, id: ID, path: &StorePath) -> Result<u64> {
+        let size = path_size(Self::store_path(path)).await?;
+        sqlx::query("UPDATE store_obj SET size = ? WHERE id = ?")
+            .bind(i64::try_from(size)?)
+            .bind(id)
+            .execute(&self.db)
+            .await?;
+        Ok(size)
+    }
+
+    pub(super) async fn get_referrers(&self, path: &StorePath) -> Result<BTreeSet<StorePath>> {
+        let rows = sqlx::query(
+            r#"
+            SELECT o.path
+            FROM ref r
+            JOIN store_obj o ON o.id = r.referrer
+            JOIN store_obj t ON t.id = r.reference
+            WHERE t.path = ?
+            "#,
+        )
+        .bind(Self::store_path(path))
+        .fetch_all(&self.db)
+        .await?;
+        Ok(rows
+            .into_iter()
+            .map(|row| Self::path_to_store(row.get(0)))
+            .collect())
+    }
+
     pub(super) async fn add_ref(
         tx: &mut sqlx::SqliteTransaction<'static>,
         referrer: ID,
@@ -78,7 +187,10 @@ impl LocalStore {
         Ok(())
     }
 
-    pub(super) async fn is_realisation(&self, realisation: &Realisation) -> Result<Option<ID>> {
+    pub(super) async fn is_realisation(
+        tx: &mut sqlx::SqliteTransaction<'static>,
+        realisation: &Realisation,
+    ) -> Result<Option<ID>> {
         let id: Option<(ID,)> = sqlx::query_as(
             r#"SELECT r.id
             FROM realisation r
@@ -88,25 +200,89 @@ impl LocalStore {
         .bind(Self::store_path(&realisation.eq_class))
         .bind(&realisation.out)
         .bind(Self::store_path(&realisation.path))
-        .fetch_optional(&self.db)
+        .fetch_optional(&mut **tx)
         .await?;
         Ok(id.map(|id| id.0))
     }
 
-    pub(super) async fn add_realisation(&self, realisation: &Realisation) -> Result<ID> {
+    pub(super) async fn add_realisation(
+        tx: &mut sqlx::SqliteTransaction<'static>,
+        realisation: &Realisation,
+        local: bool,
+    ) -> Result<ID> {
         let (id, ..): (ID,) = sqlx::query_as(
-            r#"INSERT INTO realisation (eq_class, out, obj) VALUES
-            (?, ?, (SELECT id FROM store_obj WHERE path = ?))
+            r#"INSERT INTO realisation (eq_class, out, obj, local) VALUES
+            (?, ?, (SELECT id FROM store_obj WHERE path = ?), ?)
             RETURNING id"#,
         )
         .bind(Self::store_path(&realisation.eq_class))
         .bind(&realisation.out)
         .bind(Self::store_path(&realisation.path))
-        .fetch_one(&self.db)
+        .bind(local)
+        .fetch_one(&mut **tx)
         .await?;
         Ok(id)
     }
 
+    pub(super) async fn set_realisation_local(
+        tx: &mut sqlx::SqliteTransaction<'static>,
+        id: ID,
+    ) -> Result<()> {
+        sqlx::query("UPDATE realisation SET local = 1 WHERE id = ?")
+            .bind(id)
+            .execute(&mut **tx)
+            .await?;
+        Ok(())
+    }
+
+    pub(super) async fn is_local_realisation(&self, realisation: &Realisation) -> Result<bool> {
+        let (local, ..): (bool,) = sqlx::query_as(
+            r#"
+            SELECT r.local
+            FROM realisation r
+            JOIN store_obj o ON r.obj = o.id
+            WHERE r.eq_class = ? AND r.out = ? AND o.path = ?
+            "#,
+        )
+        .bind(Self::store_path(&realisation.eq_class))
+        .bind(&realisation.out)
+        .bind(Self::store_path(&realisation.path))
+        .fetch_one(&self.db)
+        .await?;
+        Ok(local)
+    }
+
+    pub(super) async fn add_signature(
+        tx: &mut sqlx::SqliteTransaction<'static>,
+        realisation: ID,
+        sig: &str,
+    ) -> Result<()> {
+        sqlx::query("INSERT OR IGNORE INTO signatures (realisation, sig) VALUES (?, ?)")
+            .bind(realisation)
+            .bind(sig)
+            .execute(&mut **tx)
+            .await?;
+        Ok(())
+    }
+
+    pub(super) async fn get_signatures(&self, realisation: &Realisation) -> Result<Vec<String>> {
+        let rows = sqlx::query(
+            r#"
+            SELECT s.sig
+            FROM signatures s
+            JOIN realisation r ON r.id = s.realisation
+            JOIN store_obj o ON o.id = r.obj
+            WHERE r.eq_class = ? AND r.out = ? AND o.path = ?
+            "#,
+        )
+        .bind(Self::store_path(&realisation.eq_class))
+        .bind(&realisation.out)
+        .bind(Self::store_path(&realisation.path))
+        .fetch_all(&self.db)
+        .await?;
+        Ok(rows.into_iter().map(|row| row.get(0)).collect())
+    }
+
     pub(super) async fn get_realisation_id(&self, realisation: &Realisation) -> Result<ID> {
         let (id, ..): (ID,) = sqlx::query_as(
             r#"
@@ -124,15 +300,41 @@ impl LocalStore {
         Ok(id)
     }
 
-    pub(super) async fn add_realisation_ref(&self, referrer: ID, references: ID) -> Result<()> {
+    pub(super) async fn add_realisation_ref(
+        tx: &mut sqlx::SqliteTransaction<'static>,
+        referrer: ID,
+        references: ID,
+    ) -> Result<()> {
         sqlx::query("INSERT OR REPLACE INTO realisation_ref (referrer, reference) VALUES (?, ?)")
             .bind(referrer)
             .bind(references)
-            .execute(&self.db)
+            .execute(&mut **tx)
             .await?;
         Ok(())
     }
 
+    pub(super) async fn get_path_realisations(&self, path: &StorePath) -> Result<Vec<Realisation>> {
+        let rows = sqlx::query(
+            r#"
+            SELECT r.eq_class, r.out
+            FROM realisation r
+            JOIN store_obj o ON o.id = r.obj
+            WHERE o.path = ?
+            "#,
+        )
+        .bind(Self::store_path(path))
+        .fetch_all(&self.db)
+        .await?;
+        Ok(rows
+            .into_iter()
+            .map(|row| Realisation {
+                eq_class: Self::path_to_store(row.get(0)),
+                out: row.get(1),
+                path: path.clone(),
+            })
+            .collect())
+    }
+
     pub(super) async fn get_realisation_paths(
         &self,
         eq_class: &EqClass,
@@ -156,6 +358,48 @@ impl LocalStore {
             .collect())
     }
 
+    pub(super) async fn add_check(&self, result: &CheckResult) -> Result<()> {
+        sqlx::query(
+            r#"INSERT INTO checks (drv, out, path, rounds, mismatches, time)
+            VALUES (?, ?, ?, ?, ?, ?)"#,
+        )
+        .bind(Self::store_path(&result.drv))
+        .bind(&result.out)
+        .bind(Self::store_path(&result.path))
+        .bind(i64::try_from(result.rounds)?)
+        .bind(i64::try_from(result.mismatches)?)
+        .bind(i64::try_from(result.time)?)
+        .execute(&self.db)
+        .await?;
+        Ok(())
+    }
+
+    /// the last check of every output
+    pub(super) async fn get_checks(&self) -> Result<Vec<CheckResult>> {
+        let rows = sqlx::query(
+            r#"
+            SELECT drv, out, path, rounds, mismatches, time
+            FROM checks
+            WHERE id IN (SELECT MAX(id) FROM checks GROUP BY drv, out)
+            ORDER BY drv, out
+            "#,
+        )
+        .fetch_all(&self.db)
+        .await?;
+        rows.into_iter()
+            .map(|row| {
+                Ok(CheckResult {
+                    drv: Self::path_to_store(row.get(0)),
+                    out: row.get(1),
+                    path: Self::path_to_store(row.get(2)),
+                    rounds: u64::try_from(row.get::<i64, _>(3))?,
+                    mismatches: u64::try_from(row.get::<i64, _>(4))?,
+                    time: u64::try_from(row.get::<i64, _>(5))?,
+                })
+            })
+            .collect()
+    }
+
     pub(super) async fn get_realisation_refs(
         &self,
         realisation: &Realisation,
@@ -183,3 +427,64 @@ impl LocalStore {
             .collect())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hash::utils::{random_hash, random_path};
+    use crate::utils::{remove_path, tempfile::tempdir_in};
+
+    /// register `paths` with a size of 1 and `refs` between them
+    async fn register(
+        store: &LocalStore,
+        paths: &[&StorePath],
+        refs: &[(usize, usize)],
+    ) -> Result<()> {
+        let mut tx = store.write_tx().await?;
+        let mut ids = Vec::new();
+        for path in paths {
+            let obj = StoreObj {
+                path: (*path).clone(),
+                hash: random_hash(),
+                size: 1,
+                deriver: None,
+            };
+            ids.push(LocalStore::add_store_obj(&mut tx, &obj).await?);
+        }
+        for &(referrer, reference) in refs {
+            LocalStore::add_ref(&mut tx, ids[referrer], ids[reference]).await?;
+        }
+        tx.commit().await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn path_infos_referrers_and_closures() -> Result<()> {
+        let dir = tempdir_in(std::env::temp_dir()).await?;
+        let store = LocalStore::with_db_in(&dir).await?;
+        let [a, b, c, d] = ["a", "b", "c", "d"].map(random_path);
+        // a -> b -> c, d -> c
+        register(&store, &[&a, &b, &c, &d], &[(0, 1), (1, 2), (3, 2)]).await?;
+
+        let info = store.query_path_info(&a).await?.unwrap();
+        assert_eq!(info.refs, BTreeSet::from([b.clone()]));
+        assert_eq!(info.size, 1);
+        assert!(info.registration_time.is_some());
+        assert_eq!(store.query_path_info(&random_path("x")).await?, None);
+
+        assert_eq!(
+            store.query_referrers(&c).await?,
+            BTreeSet::from([b.clone(), d.clone()])
+        );
+        assert!(store.query_referrers(&a).await?.is_empty());
+
+        let closure = store.compute_closure(std::slice::from_ref(&a)).await?;
+        assert_eq!(closure, BTreeSet::from([a.clone(), b.clone(), c.clone()]));
+        let closure = store.compute_closure(&[b.clone(), d.clone()]).await?;
+        assert_eq!(closure, BTreeSet::from([b, c, d]));
+        assert!(store.compute_closure(&[random_path("x")]).await.is_err());
+
+        remove_path(&dir).await?;
+        Ok(())
+    }
+}
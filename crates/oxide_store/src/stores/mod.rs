@@ -1 +1,4 @@
+pub mod any;
+pub mod cache;
 pub mod local;
+pub mod remote;
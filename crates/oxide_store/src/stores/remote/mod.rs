@@ -0,0 +1,296 @@
+use crate::{
+    api::{CONFIG, Opt, Store},
+    archive::write_archive,
+    build::{BuildOpts, BuildPlan, Built},
+    export::write_objs,
+    protocol::{Connection, Request},
+    types::{CheckResult, ObjInfo, PathInfo, Realisation, RealisationInfo},
+    utils::{remove_path, tempfile::tempfile_in},
+};
+use anyhow::{Result, anyhow, bail};
+use futures_util::future::join;
+use oxide_core::{
+    store::StorePath,
+    types::{EqClass, Out},
+};
+use serde::de::DeserializeOwned;
+use std::{
+    collections::BTreeSet,
+    path::{Path, PathBuf},
+    process::Stdio,
+};
+use tokio::{
+    fs,
+    io::{self, AsyncBufRead, AsyncRead, AsyncWrite, AsyncWriteExt, BufReader, DuplexStream},
+    net::UnixStream,
+    process::{Child, Command},
+    sync::Mutex,
+};
+
+/// size of the pipe used to send paths that are in a temporary location
+const PIPE_SIZE: usize = 64 * 1024;
+
+/// The store served by the daemon of this machine
+pub type DaemonStore = RemoteStore;
+
+/// A store in another process, see [`crate::protocol`]
+pub struct RemoteStore {
+    url: String,
+    conn: Mutex<Connection>,
+    /// the process serving the store, killed when the store is dropped
+    _child: Option<Child>,
+}
+
+impl RemoteStore {
+    /// `ssh://<host>` runs `oxide serve-stdio` on `host`,
+    /// `pipe://<command>` runs `command` with `sh -c` and speaks to it through stdin and stdout,
+    /// `unix://<socket>` connects to a unix socket
+    pub async fn new(url: &str) -> Result<Self> {
+        let (conn, child) = if let Some(host) = url.strip_prefix("ssh://") {
+            let mut command = Command::new("ssh");
+            command.arg(host).arg("oxide serve-stdio");
+            Self::spawn(command).await?
+        } else if let Some(cmd) = url.strip_prefix("pipe://") {
+            let mut command = Command::new("sh");
+            command.arg("-c").arg(cmd);
+            Self::spawn(command).await?
+        } else if let Some(socket) = url.strip_prefix("unix://") {
+            let stream = UnixStream::connect(socket)
+                .await
+                .map_err(|e| anyhow!("cannot connect to {socket}: {e}"))?;
+            let (reader, writer) = stream.into_split();
+            (
+                Connection::new(Box::pin(reader), Box::pin(writer)).await?,
+                None,
+            )
+        } else {
+            bail!("unsupported remote store url {url}");
+        };
+        Ok(Self {
+            url: url.to_string(),
+            conn: Mutex::new(conn),
+            _child: child,
+        })
+    }
+
+    /// connect to the daemon listening on the socket from the config
+    pub async fn daemon() -> Result<DaemonStore> {
+        let socket = CONFIG.daemon_socket.clone();
+        Self::new(&format!("unix://{socket}")).await
+    }
+
+    async fn spawn(mut command: Command) -> Result<(Connection, Option<Child>)> {
+        let mut child = command
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .kill_on_drop(true)
+            .spawn()?;
+        let reader = child.stdout.take().unwrap();
+        let writer = child.stdin.take().unwrap();
+        let conn = Connection::new(Box::pin(reader), Box::pin(writer)).await?;
+        Ok((conn, Some(child)))
+    }
+
+    pub fn url(&self) -> &str {
+        &self.url
+    }
+
+    /// send the archive written by `archive` to `reader` to be added with `opt`
+    async fn add<F>(&self, opt: Opt, reader: DuplexStream, archive: F) -> Result<StorePath>
+    where
+        F: Future<Output = Result<()>>,
+    {
+        let mut conn = self.conn.lock().await;
+        conn.send(&Request::AddToStore { opt }).await?;
+        let (written, sent) = join(archive, conn.write_frames(reader)).await;
+        sent?;
+        // the response must be read even if the archive is incomplete
+        let path = conn.response().await;
+        written?;
+        path
+    }
+
+    async fn request<T>(&self, request: Request) -> Result<T>
+    where
+        T: DeserializeOwned,
+    {
+        let mut conn = self.conn.lock().await;
+        conn.send(&request).await?;
+        conn.response().await
+    }
+}
+
+impl Store for RemoteStore {
+    async fn add_to_store<P>(&self, path: P, opt: Opt) -> Result<StorePath>
+    where
+        P: AsRef<Path>,
+    {
+        let (reader, mut writer) = io::duplex(PIPE_SIZE);
+        let archive = async move {
+            write_archive(path, &mut writer).await?;
+            writer.shutdown().await?;
+            Ok(())
+        };
+        self.add(opt, reader, archive).await
+    }
+
+    /// the server may not be able to read a temporary file of the client,
+    /// the content goes through one of ours instead of being kept in memory
+    async fn add_to_store_buff<R>(&self, mut buff: BufReader<R>, opt: Opt) -> Result<StorePath>
+    where
+        R: AsyncBufRead + Unpin,
+    {
+        let (mut file, tmp_path) = tempfile_in(std::env::temp_dir()).await?;
+        let res = async {
+            io::copy(&mut buff, &mut file).await?;
+            file.flush().await?;
+            self.add_to_store(&tmp_path, opt).await
+        }
+        .await;
+        _ = fs::remove_file(&tmp_path).await;
+        res
+    }
+
+    async fn build_drvs(&self, drvs: &[StorePath], opts: &BuildOpts) -> Result<Built> {
+        self.request(Request::Build {
+            drvs: drvs.to_vec(),
+            opts: opts.clone(),
+        })
+        .await
+    }
+
+    async fn plan_build(&self, drvs: &[StorePath]) -> Result<BuildPlan> {
+        self.request(Request::PlanBuild {
+            drvs: drvs.to_vec(),
+        })
+        .await
+    }
+
+    async fn trusted_paths(&self, eq_class: &EqClass, out: &Out) -> Result<Vec<StorePath>> {
+        self.request(Request::TrustedPaths {
+            eq_class: eq_class.clone(),
+            out: out.clone(),
+        })
+        .await
+    }
+
+    async fn realisation_refs(&self, realisation: &Realisation) -> Result<Vec<Realisation>> {
+        self.request(Request::RealisationRefs {
+            realisation: realisation.clone(),
+        })
+        .await
+    }
+
+    async fn query_realisations(&self, path: &StorePath) -> Result<Vec<RealisationInfo>> {
+        self.request(Request::QueryRealisations { path: path.clone() })
+            .await
+    }
+
+    /// the paths are only read again to be sent to the server
+    fn import_dir(&self) -> PathBuf {
+        std::env::temp_dir()
+    }
+
+    /// the paths are sent to the server as an export stream
+    async fn import_paths(&self, objs: Vec<(ObjInfo, Option<PathBuf>)>) -> Result<()> {
+        let (reader, writer) = io::duplex(PIPE_SIZE);
+        let (written, imported) = join(write_objs(&objs, writer), self.import_stream(reader)).await;
+        for (_, tmp_path) in objs {
+            if let Some(tmp_path) = tmp_path {
+                remove_path(tmp_path).await?;
+            }
+        }
+        imported?;
+        written
+    }
+
+    async fn export_paths<W>(
+        &self,
+        paths: &[StorePath],
+        valid: &BTreeSet<StorePath>,
+        writer: W,
+    ) -> Result<()>
+    where
+        W: AsyncWrite + Unpin,
+    {
+        let mut conn = self.conn.lock().await;
+        conn.send(&Request::ExportPaths {
+            paths: paths.to_vec(),
+            valid: valid.clone(),
+        })
+        .await?;
+        conn.read_frames(writer).await?;
+        conn.response_unit().await
+    }
+
+    async fn import_stream<R>(&self, reader: R) -> Result<Vec<StorePath>>
+    where
+        R: AsyncRead + Unpin,
+    {
+        let mut conn = self.conn.lock().await;
+        conn.send(&Request::ImportPaths).await?;
+        conn.write_frames(reader).await?;
+        conn.response().await
+    }
+
+    async fn dump_path<W>(&self, path: &StorePath, _writer: &mut W) -> Result<()>
+    where
+        W: AsyncWrite + Unpin,
+    {
+        bail!(
+            "cannot read {path} from the remote store {}, export it instead",
+            self.url
+        );
+    }
+
+    async fn add_signatures(&self, realisation: &Realisation, sigs: Vec<String>) -> Result<()> {
+        let mut conn = self.conn.lock().await;
+        conn.send(&Request::AddSignatures {
+            realisation: realisation.clone(),
+            sigs,
+        })
+        .await?;
+        conn.response_unit().await
+    }
+
+    async fn record_check(&self, result: &CheckResult) -> Result<()> {
+        let mut conn = self.conn.lock().await;
+        conn.send(&Request::RecordCheck {
+            result: result.clone(),
+        })
+        .await?;
+        conn.response_unit().await
+    }
+
+    async fn query_checks(&self) -> Result<Vec<CheckResult>> {
+        self.request(Request::QueryChecks).await
+    }
+
+    async fn read_build_log<W>(&self, drv: &StorePath, writer: &mut W) -> Result<bool>
+    where
+        W: AsyncWrite + Unpin,
+    {
+        let mut conn = self.conn.lock().await;
+        conn.send(&Request::ReadBuildLog { drv: drv.clone() })
+            .await?;
+        conn.read_frames(writer).await?;
+        conn.response().await
+    }
+
+    async fn query_path_info(&self, path: &StorePath) -> Result<Option<PathInfo>> {
+        self.request(Request::QueryPathInfo { path: path.clone() })
+            .await
+    }
+
+    async fn query_referrers(&self, path: &StorePath) -> Result<BTreeSet<StorePath>> {
+        self.request(Request::QueryReferrers { path: path.clone() })
+            .await
+    }
+
+    async fn query_valid_paths(&self, paths: &[StorePath]) -> Result<BTreeSet<StorePath>> {
+        self.request(Request::QueryValidPaths {
+            paths: paths.to_vec(),
+        })
+        .await
+    }
+}
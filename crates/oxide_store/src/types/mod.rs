@@ -3,6 +3,9 @@ use oxide_core::{
     store::StorePath,
     types::{EqClass, Out},
 };
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeSet;
+use std::path::PathBuf;
 
 /// Database ID
 pub type ID = u32;
@@ -11,11 +14,73 @@ pub type ID = u32;
 pub struct StoreObj {
     pub path: StorePath,
     pub hash: Hash,
+    pub size: u64,
+    pub deriver: Option<StorePath>,
 }
 
-#[derive(Clone, Debug, Eq, PartialEq, Hash)]
+#[derive(Clone, Debug, Eq, PartialEq, Hash, Serialize, Deserialize)]
 pub struct Realisation {
     pub eq_class: EqClass,
     pub out: Out,
     pub path: StorePath,
 }
+
+/// Everything the store knows about a valid path
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PathInfo {
+    pub path: StorePath,
+    pub hash: Hash,
+    /// size in bytes of the regular files and symlinks
+    pub size: u64,
+    pub refs: BTreeSet<StorePath>,
+    pub deriver: Option<StorePath>,
+    /// seconds since the unix epoch, `None` if it is not known
+    pub registration_time: Option<u64>,
+}
+
+/// Where the hash part of a store path was found inside of another path
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct HashOccurrence {
+    /// the file or symlink containing the hash
+    pub file: PathBuf,
+    /// offset of the hash inside of the file or of the symlink target
+    pub offset: u64,
+    /// the bytes surrounding the hash, non printable bytes are replaced with '.'
+    pub context: String,
+}
+
+/// A realisation of an output with the realisations it references
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RealisationInfo {
+    pub eq_class: EqClass,
+    pub out: Out,
+    pub refs: Vec<Realisation>,
+    /// `<key name>:<base64 signature>`
+    #[serde(default)]
+    pub signatures: Vec<String>,
+}
+
+/// The outcome of building an output again to check that it is reproducible
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CheckResult {
+    pub drv: StorePath,
+    pub out: Out,
+    /// the trusted output the rebuilds were compared with
+    pub path: StorePath,
+    /// number of times the output was built again
+    pub rounds: u64,
+    /// number of rebuilds that were different from `path`
+    pub mismatches: u64,
+    /// seconds since the unix epoch
+    pub time: u64,
+}
+
+/// Everything needed to register a path into another store
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ObjInfo {
+    pub path: StorePath,
+    pub hash: Hash,
+    pub deriver: Option<StorePath>,
+    pub refs: BTreeSet<StorePath>,
+    pub realisations: Vec<RealisationInfo>,
+}
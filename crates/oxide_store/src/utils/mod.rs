@@ -1,5 +1,11 @@
+use anyhow::Result;
 use oxide_core::drv::DRV_EXT;
-use std::path::{Path, PathBuf};
+use std::{
+    fs::Permissions,
+    os::unix::fs::{PermissionsExt, lchown},
+    path::{Path, PathBuf},
+};
+use tokio::fs;
 
 pub mod tempfile;
 
@@ -17,11 +23,87 @@ pub fn is_valid_name(name: &str) -> bool {
     name.len() >= min_len && name.chars().all(is_valid_char)
 }
 
+const LOCK_EXT: &str = ".lock";
+
 pub fn add_lock_ext<P>(path: P) -> PathBuf
 where
     P: AsRef<Path>,
 {
     let mut os_str = path.as_ref().as_os_str().to_os_string();
-    os_str.push(".lock");
+    os_str.push(LOCK_EXT);
     os_str.into()
 }
+
+/// the name of what `name` locks if it is the name of a lock file
+pub fn strip_lock_ext(name: &str) -> Option<&str> {
+    name.strip_suffix(LOCK_EXT)
+}
+
+/// size in bytes of the regular files and symlinks inside of `path`
+pub async fn path_size<P>(path: P) -> Result<u64>
+where
+    P: AsRef<Path>,
+{
+    let metadata = fs::symlink_metadata(&path).await?;
+    if !metadata.is_dir() {
+        return Ok(metadata.len());
+    }
+    let mut size = 0;
+    let mut entries = fs::read_dir(&path).await?;
+    while let Some(entry) = entries.next_entry().await? {
+        size += Box::pin(path_size(entry.path())).await?;
+    }
+    Ok(size)
+}
+
+/// change the owner of `path` and of everything inside of it, symlinks are not followed
+pub async fn chown_path<P>(path: P, uid: u32, gid: u32) -> Result<()>
+where
+    P: AsRef<Path>,
+{
+    lchown(&path, Some(uid), Some(gid))?;
+    if fs::symlink_metadata(&path).await?.is_dir() {
+        let mut entries = fs::read_dir(&path).await?;
+        while let Some(entry) = entries.next_entry().await? {
+            Box::pin(chown_path(entry.path(), uid, gid)).await?;
+        }
+    }
+    Ok(())
+}
+
+/// remove a file, a symlink or a directory with all of its content
+pub async fn remove_path<P>(path: P) -> Result<()>
+where
+    P: AsRef<Path>,
+{
+    if fs::symlink_metadata(&path).await?.is_dir() {
+        if fs::remove_dir_all(&path).await.is_err() {
+            // builders can leave directories without the write permission
+            make_dirs_writable(&path).await?;
+            fs::remove_dir_all(&path).await?;
+        }
+    } else {
+        fs::remove_file(&path).await?;
+    }
+    Ok(())
+}
+
+/// give the owner full access to `path` and to every directory inside of it
+async fn make_dirs_writable<P>(path: P) -> Result<()>
+where
+    P: AsRef<Path>,
+{
+    let metadata = fs::symlink_metadata(&path).await?;
+    if !metadata.is_dir() {
+        return Ok(());
+    }
+    let mode = metadata.permissions().mode();
+    if mode & 0o700 != 0o700 {
+        fs::set_permissions(&path, Permissions::from_mode(mode | 0o700)).await?;
+    }
+    let mut entries = fs::read_dir(&path).await?;
+    while let Some(entry) = entries.next_entry().await? {
+        Box::pin(make_dirs_writable(entry.path())).await?;
+    }
+    Ok(())
+}
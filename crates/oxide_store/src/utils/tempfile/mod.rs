@@ -35,7 +35,14 @@ where
     Ok((File::create(&path).await?, path))
 }
 
-// TODO: tempdir does not get deleted
+/// a temporary path inside of `p` that is not created
+pub fn temppath_in<P>(p: P) -> PathBuf
+where
+    P: AsRef<Path>,
+{
+    p.as_ref().join(tmpname())
+}
+
 pub async fn tempdir_in<P>(p: P) -> io::Result<PathBuf>
 where
     P: AsRef<Path>,
@@ -0,0 +1,223 @@
+use crate::{api::Store, hash::scan_for_hash, types::HashOccurrence};
+use anyhow::{Result, bail};
+use oxide_core::store::StorePath;
+use std::collections::{BTreeSet, HashMap, HashSet, VecDeque};
+
+/// A reference from one path to another and where it was found
+#[derive(Clone, Debug)]
+pub struct Hop {
+    pub from: StorePath,
+    pub to: StorePath,
+    pub occurrences: Vec<HashOccurrence>,
+}
+
+/// chains returned with `all`, dense graphs can have exponentially many of them
+pub const MAX_CHAINS: usize = 100;
+
+/// The chains of references that bring `dep` into the closure of `path`
+/// Only the shortest one unless `all` is set, then at most [`MAX_CHAINS`]
+/// Empty if `path` does not depend on `dep`
+pub async fn why_depends<S>(
+    store: &S,
+    path: &StorePath,
+    dep: &StorePath,
+    all: bool,
+) -> Result<Vec<Vec<Hop>>>
+where
+    S: Store,
+{
+    let graph = reference_graph(store, path).await?;
+    let chains = if all {
+        all_chains(&graph, path, dep)
+    } else {
+        shortest_chain(&graph, path, dep).into_iter().collect()
+    };
+    let mut res = Vec::new();
+    for chain in chains {
+        let mut hops = Vec::new();
+        for pair in chain.windows(2) {
+            let (from, to) = (&pair[0], &pair[1]);
+            let occurrences = scan_for_hash(S::store_path(from), to).await?;
+            hops.push(Hop {
+                from: from.clone(),
+                to: to.clone(),
+                occurrences,
+            });
+        }
+        res.push(hops);
+    }
+    Ok(res)
+}
+
+/// references of every path in the closure of `path` without self references
+async fn reference_graph<S>(
+    store: &S,
+    path: &StorePath,
+) -> Result<HashMap<StorePath, BTreeSet<StorePath>>>
+where
+    S: Store,
+{
+    let mut graph = HashMap::new();
+    let mut queue = vec![path.clone()];
+    while let Some(p) = queue.pop() {
+        if graph.contains_key(&p) {
+            continue;
+        }
+        let Some(info) = store.query_path_info(&p).await? else {
+            bail!("path {} is not valid", S::store_path(&p));
+        };
+        let mut refs = info.refs;
+        refs.remove(&p);
+        queue.extend(refs.iter().filter(|r| !graph.contains_key(*r)).cloned());
+        graph.insert(p, refs);
+    }
+    Ok(graph)
+}
+
+fn shortest_chain(
+    graph: &HashMap<StorePath, BTreeSet<StorePath>>,
+    path: &StorePath,
+    dep: &StorePath,
+) -> Option<Vec<StorePath>> {
+    let mut parents: HashMap<&StorePath, &StorePath> = HashMap::new();
+    let mut queue = VecDeque::from([path]);
+    let mut visited = HashSet::from([path]);
+    while let Some(p) = queue.pop_front() {
+        if p == dep {
+            let mut chain = vec![p.clone()];
+            let mut cur = p;
+            while let Some(parent) = parents.get(cur) {
+                chain.push((*parent).clone());
+                cur = parent;
+            }
+            chain.reverse();
+            return Some(chain);
+        }
+        for r in &graph[p] {
+            if visited.insert(r) {
+                parents.insert(r, p);
+                queue.push_back(r);
+            }
+        }
+    }
+    None
+}
+
+fn all_chains(
+    graph: &HashMap<StorePath, BTreeSet<StorePath>>,
+    path: &StorePath,
+    dep: &StorePath,
+) -> Vec<Vec<StorePath>> {
+    // only walk through the paths that can reach dep
+    let mut reaches = HashSet::from([dep]);
+    let mut changed = true;
+    while changed {
+        changed = false;
+        for (p, refs) in graph {
+            if !reaches.contains(p) && refs.iter().any(|r| reaches.contains(r)) {
+                reaches.insert(p);
+                changed = true;
+            }
+        }
+    }
+    if !reaches.contains(path) {
+        return Vec::new();
+    }
+
+    let mut chains = Vec::new();
+    let mut stack = vec![vec![path]];
+    while let Some(chain) = stack.pop() {
+        let last = *chain.last().unwrap();
+        if last == dep {
+            chains.push(chain.into_iter().cloned().collect());
+            if chains.len() == MAX_CHAINS {
+                break;
+            }
+            continue;
+        }
+        for r in graph[last].iter().rev() {
+            // references can form cycles
+            if reaches.contains(r) && !chain.contains(&r) {
+                let mut next = chain.clone();
+                next.push(r);
+                stack.push(next);
+            }
+        }
+    }
+    chains
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hash::utils::random_path;
+
+    fn make_graph(
+        edges: &[(&StorePath, &[&StorePath])],
+    ) -> HashMap<StorePath, BTreeSet<StorePath>> {
+        edges
+            .iter()
+            .map(|(p, refs)| ((*p).clone(), refs.iter().map(|&r| r.clone()).collect()))
+            .collect()
+    }
+
+    #[test]
+    fn chains_between_paths() {
+        let [top, left, right, dep, back] =
+            ["top", "left", "right", "dep", "back"].map(random_path);
+        // left and back reference each other
+        let refs_of = make_graph(&[
+            (&top, &[&left, &right]),
+            (&left, &[&dep, &back]),
+            (&right, &[&left, &dep]),
+            (&dep, &[]),
+            (&back, &[&left]),
+        ]);
+        let shortest = shortest_chain(&refs_of, &top, &dep).unwrap();
+        assert_eq!(shortest.len(), 3);
+        assert_eq!((&shortest[0], &shortest[2]), (&top, &dep));
+        assert_eq!(
+            shortest_chain(&refs_of, &top, &top),
+            Some(vec![top.clone()])
+        );
+        assert_eq!(shortest_chain(&refs_of, &dep, &top), None);
+
+        let chains = all_chains(&refs_of, &top, &dep)
+            .into_iter()
+            .collect::<HashSet<_>>();
+        let expected = [
+            vec![&top, &left, &dep],
+            vec![&top, &right, &dep],
+            vec![&top, &right, &left, &dep],
+        ]
+        .map(|chain| chain.into_iter().cloned().collect::<Vec<_>>());
+        assert_eq!(chains, HashSet::from(expected));
+        assert!(all_chains(&refs_of, &dep, &top).is_empty());
+    }
+
+    #[test]
+    fn all_chains_are_capped() {
+        // every path of a layer references the three paths of the next one
+        let layers = (0..6)
+            .map(|i| {
+                (0..3)
+                    .map(|j| random_path(&format!("{i}-{j}")))
+                    .collect::<Vec<_>>()
+            })
+            .collect::<Vec<_>>();
+        let (top, dep) = (random_path("top"), random_path("dep"));
+        let mut refs_of = HashMap::from([(top.clone(), layers[0].iter().cloned().collect())]);
+        for (i, layer) in layers.iter().enumerate() {
+            let next = layers.get(i + 1).map_or_else(
+                || BTreeSet::from([dep.clone()]),
+                |l| l.iter().cloned().collect(),
+            );
+            for p in layer {
+                refs_of.insert(p.clone(), next.clone());
+            }
+        }
+        refs_of.insert(dep.clone(), BTreeSet::new());
+        // 3^6 chains
+        assert_eq!(all_chains(&refs_of, &top, &dep).len(), MAX_CHAINS);
+    }
+}
@@ -0,0 +1,3 @@
+ALTER TABLE store_obj DROP COLUMN registration_time;
+ALTER TABLE store_obj DROP COLUMN deriver;
+ALTER TABLE store_obj DROP COLUMN size;
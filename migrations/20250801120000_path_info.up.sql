@@ -0,0 +1,3 @@
+ALTER TABLE store_obj ADD COLUMN size INTEGER NOT NULL DEFAULT 0; -- size in bytes of the regular files and symlinks
+ALTER TABLE store_obj ADD COLUMN deriver TEXT; -- the derivation that produced the path if any
+ALTER TABLE store_obj ADD COLUMN registration_time INTEGER NOT NULL DEFAULT 0; -- seconds since the unix epoch
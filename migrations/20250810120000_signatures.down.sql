@@ -0,0 +1,2 @@
+DROP TABLE signatures;
+ALTER TABLE realisation DROP COLUMN local;
@@ -0,0 +1,12 @@
+-- every realisation registered so far was trusted
+ALTER TABLE realisation ADD COLUMN local INTEGER NOT NULL DEFAULT 0; -- 1 if the realisation was built on this machine
+UPDATE realisation SET local = 1;
+
+CREATE TABLE signatures (
+    realisation INTEGER NOT NULL,
+    sig         TEXT NOT NULL, -- <key name>:<base64 signature>
+    PRIMARY KEY (realisation, sig),
+    FOREIGN KEY (realisation) REFERENCES realisation(id) ON DELETE CASCADE
+);
+
+CREATE INDEX index_signatures_realisation ON signatures(realisation);
@@ -0,0 +1,11 @@
+CREATE TABLE checks (
+    id          INTEGER PRIMARY KEY,
+    drv         TEXT NOT NULL,
+    out         TEXT NOT NULL,
+    path        TEXT NOT NULL, -- the trusted output the rebuilds were compared with
+    rounds      INTEGER NOT NULL, -- number of times the output was built again
+    mismatches  INTEGER NOT NULL, -- number of rebuilds that were different
+    time        INTEGER NOT NULL -- seconds since the unix epoch
+);
+
+CREATE INDEX index_checks_drv ON checks(drv, out);
@@ -0,0 +1 @@
+UPDATE store_obj SET size = 0 WHERE size = -1;
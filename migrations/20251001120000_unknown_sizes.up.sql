@@ -0,0 +1,2 @@
+-- paths registered before sizes were recorded, their size is computed when they are first queried
+UPDATE store_obj SET size = -1 WHERE registration_time = 0;